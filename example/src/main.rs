@@ -7,9 +7,11 @@ mod log_impl;
 
 use log::LevelFilter;
 use log_impl::LogImpl;
+use polyhal2::boot::{BootInfo, PHInitStage};
 use polyhal2_debug::println;
 
-polyhal2::boot::ph_ctor!(INIT_LOG, || {
+// Install the logger right after the console is ready.
+polyhal2::boot::ph_ctor!(INIT_LOG, PHInitStage::Console as usize + 1, || {
     log::set_logger(&LogImpl).unwrap();
     log::set_max_level(match option_env!("LOG") {
         Some("error") => LevelFilter::Error,
@@ -21,9 +23,12 @@ polyhal2::boot::ph_ctor!(INIT_LOG, || {
     });
 });
 
-fn main(_hart_id: usize) {
+fn main(_hart_id: usize, boot_info: &BootInfo) {
     println!("Entering kernel ...");
     println!("Hello World!");
+    println!("Command line: {}", boot_info.cmdline);
+    let cpu_num = polyhal2::boot::smp::start_secondary_cpus();
+    println!("{} CPUs online", cpu_num);
     unsafe {
         let test_ptr = 0x1_0000_0000_0000 as *mut u64;
         test_ptr.write_volatile(0x12345678);
@@ -31,5 +36,9 @@ fn main(_hart_id: usize) {
     log::debug!("Test kernel Logging");
 }
 
-// Specific a boot function and the size of the boot_stack
-polyhal2::boot::entry_point!(main, 0x5000);
+fn secondary_main(hart_id: usize) {
+    println!("Secondary CPU {} is online", hart_id);
+}
+
+// Specific a boot function, a secondary boot function and the size of the boot_stack
+polyhal2::boot::entry_point!(main, secondary_main, 0x5000);
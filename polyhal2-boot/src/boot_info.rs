@@ -0,0 +1,242 @@
+//! Boot Information
+//!
+//! The information given by the bootloader and the firmware.
+//! It is filled from the boot protocol (multiboot, device tree and so on)
+//! and passed to the kernel entry.
+
+use core::ops::Range;
+
+use polyhal2_core::{
+    addr::{PhysAddr, VirtAddr},
+    lazy_init::LazyInit,
+};
+
+/// The maximum number of memory regions in the [BootInfo].
+pub const MAX_MEMORY_REGIONS: usize = 64;
+
+static BOOT_INFO: LazyInit<BootInfo> = LazyInit::new();
+
+/// The kind of the memory region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryRegionKind {
+    /// Free memory, can be used by the kernel.
+    Usable,
+    /// Reserved memory, used by the firmware or the bootloader.
+    Reserved,
+    /// The memory occupied by the kernel image.
+    KernelImage,
+    /// Memory mapped device registers.
+    Mmio,
+}
+
+/// Physical memory region.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryRegion {
+    /// The start physical address of the region.
+    pub start: PhysAddr,
+    /// The end physical address of the region (exclusive).
+    pub end: PhysAddr,
+    /// The kind of the region.
+    pub kind: MemoryRegionKind,
+}
+
+/// The firmware table passed by the bootloader.
+#[derive(Debug, Clone, Copy)]
+pub enum FirmwareTable {
+    /// No firmware table.
+    None,
+    /// The physical address of the device tree binary.
+    Dtb(PhysAddr),
+    /// The physical address of the ACPI RSDP.
+    Rsdp(PhysAddr),
+}
+
+/// The linear framebuffer set up by the bootloader.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameBuffer {
+    /// The physical address of the framebuffer.
+    pub addr: PhysAddr,
+    /// The number of bytes in a line.
+    pub pitch: usize,
+    /// The width in pixels.
+    pub width: usize,
+    /// The height in pixels.
+    pub height: usize,
+    /// The bits per pixel.
+    pub bpp: u8,
+}
+
+/// The boot information passed to the kernel entry.
+#[derive(Debug, Clone)]
+pub struct BootInfo {
+    /// The hart id of the boot cpu.
+    pub boot_hart_id: usize,
+    /// The kernel command line.
+    pub cmdline: &'static str,
+    /// The physical range of the initrd.
+    pub initrd: Option<Range<PhysAddr>>,
+    /// The firmware table.
+    pub firmware_table: FirmwareTable,
+    /// The framebuffer set up by the bootloader.
+    pub framebuffer: Option<FrameBuffer>,
+    regions: [MemoryRegion; MAX_MEMORY_REGIONS],
+    region_num: usize,
+}
+
+impl BootInfo {
+    /// Create an empty boot information.
+    pub(crate) const fn new(boot_hart_id: usize) -> Self {
+        Self {
+            boot_hart_id,
+            cmdline: "",
+            initrd: None,
+            firmware_table: FirmwareTable::None,
+            framebuffer: None,
+            regions: [MemoryRegion {
+                start: PhysAddr::new(0),
+                end: PhysAddr::new(0),
+                kind: MemoryRegionKind::Reserved,
+            }; MAX_MEMORY_REGIONS],
+            region_num: 0,
+        }
+    }
+
+    /// Get the memory regions, sorted by the start address.
+    ///
+    /// The usable regions don't overlap with the other regions.
+    pub fn memory_regions(&self) -> &[MemoryRegion] {
+        &self.regions[..self.region_num]
+    }
+
+    /// Add a memory region.
+    ///
+    /// Add all usable regions first, the other regions will be carved out of
+    /// the usable regions. MMIO regions inside the usable memory are ignored.
+    pub(crate) fn add_memory_region(
+        &mut self,
+        start: PhysAddr,
+        end: PhysAddr,
+        kind: MemoryRegionKind,
+    ) {
+        if start >= end {
+            return;
+        }
+        let overlap_usable =
+            |r: &MemoryRegion| r.kind == MemoryRegionKind::Usable && r.start < end && r.end > start;
+        if kind == MemoryRegionKind::Mmio && self.memory_regions().iter().any(overlap_usable) {
+            return;
+        }
+        if kind != MemoryRegionKind::Usable {
+            while let Some(i) = self.memory_regions().iter().position(overlap_usable) {
+                let usable = self.regions[i];
+                self.region_num -= 1;
+                self.regions[i] = self.regions[self.region_num];
+                self.push(usable.start, start, MemoryRegionKind::Usable);
+                self.push(end, usable.end, MemoryRegionKind::Usable);
+            }
+        }
+        self.push(start, end, kind);
+    }
+
+    /// Push a region, merge it with the adjacent region of the same kind.
+    fn push(&mut self, start: PhysAddr, end: PhysAddr, kind: MemoryRegionKind) {
+        if start >= end {
+            return;
+        }
+        if let Some(region) = self.regions[..self.region_num]
+            .iter_mut()
+            .find(|r| r.kind == kind && r.start <= end && r.end >= start)
+        {
+            region.start = region.start.min(start);
+            region.end = region.end.max(end);
+        } else if self.region_num < MAX_MEMORY_REGIONS {
+            self.regions[self.region_num] = MemoryRegion { start, end, kind };
+            self.region_num += 1;
+        } else {
+            log::warn!(
+                "Too many memory regions, drop {:?}: {}-{}",
+                kind,
+                start,
+                end
+            );
+        }
+        self.regions[..self.region_num].sort_unstable_by_key(|r| r.start);
+    }
+
+    /// Set the framebuffer and add its memory as MMIO region.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn set_framebuffer(&mut self, fb: FrameBuffer) {
+        let end = PhysAddr::new(fb.addr.raw() + fb.pitch * fb.height);
+        self.add_memory_region(fb.addr, end, MemoryRegionKind::Mmio);
+        self.framebuffer = Some(fb);
+    }
+
+    /// Add the memory occupied by the kernel image.
+    ///
+    /// The linker script should provide `_skernel` and `end` symbols.
+    pub(crate) fn add_kernel_image(&mut self) {
+        unsafe extern "C" {
+            fn _skernel();
+            fn end();
+        }
+        self.add_memory_region(
+            VirtAddr::new(_skernel as usize).mapped_paddr(),
+            VirtAddr::new(end as usize).mapped_paddr(),
+            MemoryRegionKind::KernelImage,
+        );
+    }
+
+    /// Fill the boot information from the device tree.
+    #[cfg(not(target_arch = "x86_64"))]
+    pub(crate) fn from_fdt(boot_hart_id: usize, dtb: PhysAddr) -> Self {
+        let mut info = Self::new(boot_hart_id);
+        if dtb.raw() == 0 {
+            return info;
+        }
+        info.firmware_table = FirmwareTable::Dtb(dtb);
+        info.cmdline = polyhal2_device::bootargs().unwrap_or("");
+        info.initrd = polyhal2_device::initrd();
+
+        polyhal2_device::for_each_memory(|start, size| {
+            info.add_memory_region(
+                start,
+                PhysAddr::new(start.raw() + size),
+                MemoryRegionKind::Usable,
+            )
+        });
+        polyhal2_device::for_each_reserved_memory(|start, size| {
+            info.add_memory_region(
+                start,
+                PhysAddr::new(start.raw() + size),
+                MemoryRegionKind::Reserved,
+            )
+        });
+        let dtb_end = PhysAddr::new(dtb.raw() + polyhal2_device::dtb_size());
+        info.add_memory_region(dtb, dtb_end, MemoryRegionKind::Reserved);
+        if let Some(initrd) = info.initrd.clone() {
+            info.add_memory_region(initrd.start, initrd.end, MemoryRegionKind::Reserved);
+        }
+        info.add_kernel_image();
+        polyhal2_device::for_each_mmio(|start, size| {
+            info.add_memory_region(
+                start,
+                PhysAddr::new(start.raw() + size),
+                MemoryRegionKind::Mmio,
+            )
+        });
+        info
+    }
+}
+
+/// Save the boot information, it can be only called once.
+pub(crate) fn init_boot_info(info: BootInfo) -> &'static BootInfo {
+    BOOT_INFO.init_by(info);
+    &BOOT_INFO
+}
+
+/// Get the boot information.
+///
+/// Panic if it is called before entering the kernel.
+pub fn boot_info() -> &'static BootInfo {
+    &BOOT_INFO
+}
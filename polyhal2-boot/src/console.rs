@@ -1,6 +1,6 @@
 use polyhal2_core::consts::KERNEL_OFFSET;
 
-use crate::display_info;
+use crate::{boot_info::BootInfo, display_info};
 use core::fmt::{Arguments, Write};
 
 /// A Struct implement the Writer
@@ -46,10 +46,23 @@ pub(crate) fn display_basic() {
 }
 
 /// Display the information before entering kernel
-pub(crate) fn display_end() {
+pub(crate) fn display_end(boot_info: &BootInfo) {
     unsafe extern "C" {
         fn bstack_top();
     }
+    display_info!("Boot Command Line", "{}", boot_info.cmdline);
+    if let Some(initrd) = &boot_info.initrd {
+        display_info!("Boot Initrd", "{} - {}", initrd.start, initrd.end);
+    }
+    boot_info.memory_regions().iter().for_each(|rg| {
+        display_info!(
+            "Platform Memory Region",
+            "{:#018x} - {:#018x} {:?}",
+            rg.start.raw(),
+            rg.end.raw(),
+            rg.kind
+        );
+    });
     display_info!("Boot Stack Top", "{:#p}", bstack_top as *const u8);
     display_info!();
     display_info!("Kernel Offset", "{:#p}", KERNEL_OFFSET as *const u8);
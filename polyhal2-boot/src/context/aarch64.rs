@@ -0,0 +1,79 @@
+use polyhal2_core::addr::VirtAddr;
+
+use super::{KContextEntry, STACK_ALIGN};
+
+/// The context of a kernel thread.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct KContext {
+    /// Callee-saved registers `x19` - `x29`, and the link register `x30`
+    /// which is the pc after switching to this context.
+    pub x: [usize; 12],
+    /// Stack pointer.
+    pub sp: usize,
+    /// Thread pointer, `TPIDR_EL1`.
+    pub tp: usize,
+}
+
+impl KContext {
+    /// Create a context which calls `entry(arg)` on the stack `stack_top`.
+    pub fn new(entry: KContextEntry, arg: usize, stack_top: VirtAddr) -> Self {
+        let mut ctx = Self::default();
+        ctx.x[0] = entry as usize;
+        ctx.x[1] = arg;
+        ctx.x[11] = kcontext_entry as usize;
+        ctx.sp = stack_top.raw() & !(STACK_ALIGN - 1);
+        ctx
+    }
+}
+
+/// The first code of a new context, call `x19` with the argument `x20`.
+#[naked]
+unsafe extern "C" fn kcontext_entry() -> ! {
+    unsafe {
+        core::arch::naked_asm!(
+            "
+            mov     x0, x20
+            br      x19
+        "
+        )
+    }
+}
+
+/// Save the current registers to `from` and switch to `to`.
+///
+/// # Safety
+///
+/// `to` must be a valid context saved by [context_switch] or created by [KContext::new].
+#[naked]
+pub unsafe extern "C" fn context_switch(from: &mut KContext, to: &KContext) {
+    unsafe {
+        core::arch::naked_asm!(
+            // Save the registers to `from`.
+            "
+            stp     x19, x20, [x0, 0 * 8]
+            stp     x21, x22, [x0, 2 * 8]
+            stp     x23, x24, [x0, 4 * 8]
+            stp     x25, x26, [x0, 6 * 8]
+            stp     x27, x28, [x0, 8 * 8]
+            stp     x29, x30, [x0, 10 * 8]
+            mov     x9, sp
+            mrs     x10, tpidr_el1
+            stp     x9, x10, [x0, 12 * 8]
+        ",
+            // Restore the registers from `to`.
+            "
+            ldp     x19, x20, [x1, 0 * 8]
+            ldp     x21, x22, [x1, 2 * 8]
+            ldp     x23, x24, [x1, 4 * 8]
+            ldp     x25, x26, [x1, 6 * 8]
+            ldp     x27, x28, [x1, 8 * 8]
+            ldp     x29, x30, [x1, 10 * 8]
+            ldp     x9, x10, [x1, 12 * 8]
+            mov     sp, x9
+            msr     tpidr_el1, x10
+            ret
+        "
+        )
+    }
+}
@@ -0,0 +1,93 @@
+use polyhal2_core::addr::VirtAddr;
+
+use super::{KContextEntry, STACK_ALIGN};
+
+/// The context of a kernel thread.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct KContext {
+    /// Return address, the pc after switching to this context.
+    pub ra: usize,
+    /// Stack pointer.
+    pub sp: usize,
+    /// Frame pointer.
+    pub fp: usize,
+    /// Callee-saved registers `s0` - `s8`.
+    pub s: [usize; 9],
+    /// Thread pointer.
+    pub tp: usize,
+}
+
+impl KContext {
+    /// Create a context which calls `entry(arg)` on the stack `stack_top`.
+    pub fn new(entry: KContextEntry, arg: usize, stack_top: VirtAddr) -> Self {
+        let mut s = [0; 9];
+        s[0] = entry as usize;
+        s[1] = arg;
+        Self {
+            ra: kcontext_entry as usize,
+            sp: stack_top.raw() & !(STACK_ALIGN - 1),
+            s,
+            ..Default::default()
+        }
+    }
+}
+
+/// The first code of a new context, call `s0` with the argument `s1`.
+#[naked]
+unsafe extern "C" fn kcontext_entry() -> ! {
+    unsafe {
+        core::arch::naked_asm!(
+            "
+            move    $a0, $s1
+            jirl    $zero, $s0, 0
+        "
+        )
+    }
+}
+
+/// Save the current registers to `from` and switch to `to`.
+///
+/// # Safety
+///
+/// `to` must be a valid context saved by [context_switch] or created by [KContext::new].
+#[naked]
+pub unsafe extern "C" fn context_switch(from: &mut KContext, to: &KContext) {
+    unsafe {
+        core::arch::naked_asm!(
+            // Save the registers to `from`.
+            "
+            st.d    $ra, $a0, 0 * 8
+            st.d    $sp, $a0, 1 * 8
+            st.d    $fp, $a0, 2 * 8
+            st.d    $s0, $a0, 3 * 8
+            st.d    $s1, $a0, 4 * 8
+            st.d    $s2, $a0, 5 * 8
+            st.d    $s3, $a0, 6 * 8
+            st.d    $s4, $a0, 7 * 8
+            st.d    $s5, $a0, 8 * 8
+            st.d    $s6, $a0, 9 * 8
+            st.d    $s7, $a0, 10 * 8
+            st.d    $s8, $a0, 11 * 8
+            st.d    $tp, $a0, 12 * 8
+        ",
+            // Restore the registers from `to`.
+            "
+            ld.d    $ra, $a1, 0 * 8
+            ld.d    $sp, $a1, 1 * 8
+            ld.d    $fp, $a1, 2 * 8
+            ld.d    $s0, $a1, 3 * 8
+            ld.d    $s1, $a1, 4 * 8
+            ld.d    $s2, $a1, 5 * 8
+            ld.d    $s3, $a1, 6 * 8
+            ld.d    $s4, $a1, 7 * 8
+            ld.d    $s5, $a1, 8 * 8
+            ld.d    $s6, $a1, 9 * 8
+            ld.d    $s7, $a1, 10 * 8
+            ld.d    $s8, $a1, 11 * 8
+            ld.d    $tp, $a1, 12 * 8
+            ret
+        "
+        )
+    }
+}
@@ -0,0 +1,31 @@
+//! Kernel Thread Context
+//!
+//! [KContext] saves the callee-saved registers, the stack pointer, the
+//! return address and the thread pointer of a kernel thread.
+//! [context_switch] saves the current registers into a context and
+//! restores the registers from another one.
+//!
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
+#[cfg(target_arch = "loongarch64")]
+mod loongarch64;
+#[cfg(target_arch = "riscv64")]
+mod riscv64;
+#[cfg(target_arch = "x86_64")]
+mod x86_64;
+
+#[cfg(target_arch = "aarch64")]
+pub use aarch64::{KContext, context_switch};
+#[cfg(target_arch = "loongarch64")]
+pub use loongarch64::{KContext, context_switch};
+#[cfg(target_arch = "riscv64")]
+pub use riscv64::{KContext, context_switch};
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::{KContext, context_switch};
+
+/// The entry of a new kernel thread, called with the argument given by [KContext::new].
+pub type KContextEntry = extern "C" fn(usize) -> !;
+
+/// The alignment of the stack pointer of a new kernel thread.
+const STACK_ALIGN: usize = 16;
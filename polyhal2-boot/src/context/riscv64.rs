@@ -0,0 +1,95 @@
+use polyhal2_core::addr::VirtAddr;
+
+use super::{KContextEntry, STACK_ALIGN};
+
+/// The context of a kernel thread.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct KContext {
+    /// Return address, the pc after switching to this context.
+    pub ra: usize,
+    /// Stack pointer.
+    pub sp: usize,
+    /// Callee-saved registers `s0` - `s11`.
+    pub s: [usize; 12],
+    /// Thread pointer.
+    pub tp: usize,
+}
+
+impl KContext {
+    /// Create a context which calls `entry(arg)` on the stack `stack_top`.
+    pub fn new(entry: KContextEntry, arg: usize, stack_top: VirtAddr) -> Self {
+        let mut s = [0; 12];
+        s[0] = entry as usize;
+        s[1] = arg;
+        Self {
+            ra: kcontext_entry as usize,
+            sp: stack_top.raw() & !(STACK_ALIGN - 1),
+            s,
+            ..Default::default()
+        }
+    }
+}
+
+/// The first code of a new context, call `s0` with the argument `s1`.
+#[naked]
+unsafe extern "C" fn kcontext_entry() -> ! {
+    unsafe {
+        core::arch::naked_asm!(
+            "
+            mv      a0, s1
+            jr      s0
+        "
+        )
+    }
+}
+
+/// Save the current registers to `from` and switch to `to`.
+///
+/// # Safety
+///
+/// `to` must be a valid context saved by [context_switch] or created by [KContext::new].
+#[naked]
+pub unsafe extern "C" fn context_switch(from: &mut KContext, to: &KContext) {
+    unsafe {
+        core::arch::naked_asm!(
+            // Save the registers to `from`.
+            "
+            sd      ra, 0*8(a0)
+            sd      sp, 1*8(a0)
+            sd      s0, 2*8(a0)
+            sd      s1, 3*8(a0)
+            sd      s2, 4*8(a0)
+            sd      s3, 5*8(a0)
+            sd      s4, 6*8(a0)
+            sd      s5, 7*8(a0)
+            sd      s6, 8*8(a0)
+            sd      s7, 9*8(a0)
+            sd      s8, 10*8(a0)
+            sd      s9, 11*8(a0)
+            sd      s10, 12*8(a0)
+            sd      s11, 13*8(a0)
+            sd      tp, 14*8(a0)
+        ",
+            // Restore the registers from `to`.
+            "
+            ld      ra, 0*8(a1)
+            ld      sp, 1*8(a1)
+            ld      s0, 2*8(a1)
+            ld      s1, 3*8(a1)
+            ld      s2, 4*8(a1)
+            ld      s3, 5*8(a1)
+            ld      s4, 6*8(a1)
+            ld      s5, 7*8(a1)
+            ld      s6, 8*8(a1)
+            ld      s7, 9*8(a1)
+            ld      s8, 10*8(a1)
+            ld      s9, 11*8(a1)
+            ld      s10, 12*8(a1)
+            ld      s11, 13*8(a1)
+            ld      tp, 14*8(a1)
+            ret
+        "
+        )
+    }
+}
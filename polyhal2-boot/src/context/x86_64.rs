@@ -0,0 +1,113 @@
+use core::mem::offset_of;
+
+use polyhal2_core::addr::VirtAddr;
+
+use super::{KContextEntry, STACK_ALIGN};
+
+/// The model specific register of the `fs` base.
+const IA32_FS_BASE: u32 = 0xC000_0100;
+
+/// The context of a kernel thread.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct KContext {
+    /// Return address, the pc after switching to this context.
+    pub rip: usize,
+    /// Stack pointer.
+    pub rsp: usize,
+    /// Callee-saved register `rbx`.
+    pub rbx: usize,
+    /// Callee-saved register `rbp`.
+    pub rbp: usize,
+    /// Callee-saved register `r12`.
+    pub r12: usize,
+    /// Callee-saved register `r13`.
+    pub r13: usize,
+    /// Callee-saved register `r14`.
+    pub r14: usize,
+    /// Callee-saved register `r15`.
+    pub r15: usize,
+    /// Thread pointer, the base of `fs`.
+    pub fs_base: usize,
+}
+
+impl KContext {
+    /// Create a context which calls `entry(arg)` on the stack `stack_top`.
+    pub fn new(entry: KContextEntry, arg: usize, stack_top: VirtAddr) -> Self {
+        Self {
+            rip: kcontext_entry as usize,
+            rsp: stack_top.raw() & !(STACK_ALIGN - 1),
+            r12: entry as usize,
+            r13: arg,
+            ..Default::default()
+        }
+    }
+}
+
+/// The first code of a new context, call `r12` with the argument `r13`.
+#[naked]
+unsafe extern "C" fn kcontext_entry() -> ! {
+    unsafe {
+        core::arch::naked_asm!(
+            "
+            mov     rdi, r13
+            call    r12
+            ud2
+        "
+        )
+    }
+}
+
+/// Save the current registers to `from` and switch to `to`.
+///
+/// # Safety
+///
+/// `to` must be a valid context saved by [context_switch] or created by [KContext::new].
+#[naked]
+pub unsafe extern "C" fn context_switch(from: &mut KContext, to: &KContext) {
+    unsafe {
+        core::arch::naked_asm!(
+            // Save the registers to `from`, the return address is on the stack.
+            "
+            mov     rax, [rsp]
+            mov     [rdi + {rip}], rax
+            lea     rax, [rsp + 8]
+            mov     [rdi + {rsp}], rax
+            mov     [rdi + {rbx}], rbx
+            mov     [rdi + {rbp}], rbp
+            mov     [rdi + {r12}], r12
+            mov     [rdi + {r13}], r13
+            mov     [rdi + {r14}], r14
+            mov     [rdi + {r15}], r15
+            mov     ecx, {fs_base_msr}
+            rdmsr
+            mov     [rdi + {fs_base}], eax
+            mov     [rdi + {fs_base} + 4], edx
+        ",
+            // Restore the registers from `to`.
+            "
+            mov     eax, [rsi + {fs_base}]
+            mov     edx, [rsi + {fs_base} + 4]
+            wrmsr
+            mov     rbx, [rsi + {rbx}]
+            mov     rbp, [rsi + {rbp}]
+            mov     r12, [rsi + {r12}]
+            mov     r13, [rsi + {r13}]
+            mov     r14, [rsi + {r14}]
+            mov     r15, [rsi + {r15}]
+            mov     rsp, [rsi + {rsp}]
+            jmp     [rsi + {rip}]
+        ",
+            rip = const offset_of!(KContext, rip),
+            rsp = const offset_of!(KContext, rsp),
+            rbx = const offset_of!(KContext, rbx),
+            rbp = const offset_of!(KContext, rbp),
+            r12 = const offset_of!(KContext, r12),
+            r13 = const offset_of!(KContext, r13),
+            r14 = const offset_of!(KContext, r14),
+            r15 = const offset_of!(KContext, r15),
+            fs_base = const offset_of!(KContext, fs_base),
+            fs_base_msr = const IA32_FS_BASE,
+        )
+    }
+}
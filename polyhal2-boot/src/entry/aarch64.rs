@@ -1,12 +1,13 @@
 use aarch64_cpu::asm::barrier;
 use aarch64_cpu::registers::{
-    CurrentEL, MAIR_EL1, ReadWriteable, Readable, SCTLR_EL1, TCR_EL1, TTBR0_EL1, TTBR1_EL1,
-    Writeable,
+    CurrentEL, MAIR_EL1, MPIDR_EL1, ReadWriteable, Readable, SCTLR_EL1, TCR_EL1, TTBR0_EL1,
+    TTBR1_EL1, Writeable,
 };
 use polyhal2_core::addr::{PhysAddr, VirtAddr};
-use polyhal2_core::consts::KERNEL_OFFSET;
-use polyhal2_pagetable::{MappingFlags, MappingSize, TLB, VSpace};
+use polyhal2_core::consts::{KERNEL_OFFSET, PAGE_SIZE};
+use polyhal2_pagetable::{MappingFlags, TLB, VSpace};
 
+use crate::boot_info::BootInfo;
 use crate::console::{display_basic, display_end};
 use crate::display_info;
 
@@ -18,8 +19,10 @@ unsafe extern "C" fn _start() -> ! {
     // X0 = dtb
     unsafe {
         core::arch::naked_asm!("
-            mrs     x19, mpidr_el1
-            and     x19, x19, #0xffffff     // get current CPU id
+            mrs     x19, mpidr_el1          // get the affinity of current CPU,
+            mov     x9, #0xffffff           // the same as current_hart_id
+            movk    x9, #0xff, lsl #32
+            and     x19, x19, x9
             mov     x20, x0                 // save DTB pointer
             cbz     x19, 1f
             b       .
@@ -37,8 +40,7 @@ unsafe extern "C" fn _start() -> ! {
         ",
         // Init boot Stack and call main function
         "
-            mov     x0, x19                 // call rust_entry(cpu_id, dtb)
-            mov     x1, x20
+            mov     x0, x20                 // call rust_tmp_main(dtb)
             ldr     x8, ={entry}
             blr     x8
         ",
@@ -49,6 +51,34 @@ unsafe extern "C" fn _start() -> ! {
     }
 }
 
+/// The entry point for the secondary CPUs started by PSCI CPU_ON.
+///
+/// X0 is the physical stack top given by [start_cpu].
+#[naked]
+unsafe extern "C" fn _secondary_start() -> ! {
+    unsafe {
+        core::arch::naked_asm!("
+            mov     sp, x0
+        ",
+        // Enable Paging Mode, the boot page table has been initialized
+        "
+            adrp    x0, boot_page
+            bl      {enable_mmu}
+            mov     x8, {KERNEL_OFFSET}     // set SP to the high address
+            add     sp, sp, x8
+        ",
+        // Call secondary main function
+        "
+            ldr     x8, ={entry}            // call rust_secondary_main()
+            blr     x8
+        ",
+            enable_mmu = sym enable_mmu,
+            KERNEL_OFFSET = const polyhal2_core::consts::KERNEL_OFFSET,
+            entry = sym rust_secondary_main,
+        )
+    }
+}
+
 /// enter low cost area, loop until shutdown.
 pub fn hlt_forever() -> ! {
     loop {
@@ -56,40 +86,62 @@ pub fn hlt_forever() -> ! {
     }
 }
 
-unsafe fn init_mmu(mut root_paddr: u64) {
-    MAIR_EL1.set(0x44_ff_04);
+// TTBR1 translates the top `VA_BITS` of the address space.
+const _: () = assert!(
+    KERNEL_OFFSET == 0 || KERNEL_OFFSET == usize::MAX << VSpace::VA_BITS,
+    "KERNEL_OFFSET must be the start of the TTBR1 range"
+);
 
-    // Enable TTBR0 and TTBR1 walks, page size = 4K, vaddr size = 39 bits, paddr size = 40 bits.
+unsafe fn init_mmu(root_paddr: u64) {
+    // Mapping the first 256GB physical addresses as normal memory from the
+    // block holding the kernel image. The blocks below it hold the MMIO
+    // regions used before the device tree is parsed, such as the UART and
+    // the GIC on qemu virt, they are non-executable device memory.
+    // The other devices are mapped by ioremap in the upper half.
+    let root = VirtAddr::new(root_paddr as _).mapped_paddr();
+    super::init_boot_page(root, |paddr| {
+        match paddr < root.floor(super::BOOT_BLOCK.size()) {
+            true => MappingFlags::R | MappingFlags::W | MappingFlags::Device,
+            false => MappingFlags::RWX,
+        }
+    });
+    unsafe { enable_mmu(root_paddr) };
+}
+
+/// Set the translation registers and enable the MMU on the current CPU.
+unsafe fn enable_mmu(mut root_paddr: u64) {
+    // The memory attributes used by the page table:
+    // 0: Device-nGnRnE, 1: Normal write-back, 2: Normal non-cacheable.
+    MAIR_EL1.set(0x44_ff_00);
+
+    // Enable TTBR0 and TTBR1 walks, page size = the granule,
+    // vaddr size = VSpace::VA_BITS, paddr size = 48 bits.
+    let tsz = 64 - VSpace::VA_BITS as u64;
+    let (tg0, tg1) = match PAGE_SIZE {
+        0x4000 => (TCR_EL1::TG0::KiB_16, TCR_EL1::TG1::KiB_16),
+        0x10000 => (TCR_EL1::TG0::KiB_64, TCR_EL1::TG1::KiB_64),
+        _ => (TCR_EL1::TG0::KiB_4, TCR_EL1::TG1::KiB_4),
+    };
     let tcr_flags0 = TCR_EL1::EPD0::EnableTTBR0Walks
-        + TCR_EL1::TG0::KiB_4
+        + tg0
         + TCR_EL1::SH0::Inner
         + TCR_EL1::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
         + TCR_EL1::IRGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
-        + TCR_EL1::T0SZ.val(25);
+        + TCR_EL1::T0SZ.val(tsz);
     let tcr_flags1 = TCR_EL1::EPD1::EnableTTBR1Walks
-        + TCR_EL1::TG1::KiB_4
+        + tg1
         + TCR_EL1::SH1::Inner
         + TCR_EL1::ORGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
         + TCR_EL1::IRGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
-        + TCR_EL1::T1SZ.val(25);
+        + TCR_EL1::T1SZ.val(tsz);
     TCR_EL1.write(TCR_EL1::IPS::Bits_48 + tcr_flags0 + tcr_flags1);
     barrier::isb(barrier::SY);
 
-    // Set both TTBR0 and TTBR1
+    // TTBR1 holds the kernel, TTBR0 holds the identity mapping used while
+    // enabling the MMU until it is replaced by a user space.
     if root_paddr > KERNEL_OFFSET as _ {
         root_paddr -= KERNEL_OFFSET as u64;
     }
-    // Mapping all physical addresses.
-    let vspace = VSpace::from_paddr(PhysAddr::new(root_paddr as _));
-    for i in 0..512 {
-        vspace.map_page(
-            VirtAddr::new(0x4000_0000 * i),
-            PhysAddr::new(0x4000_0000 * i),
-            MappingFlags::RWX,
-            MappingSize::Page1GB,
-        );
-    }
-
     TTBR0_EL1.set(root_paddr);
     TTBR1_EL1.set(root_paddr);
     // Flush the entire TLB
@@ -101,17 +153,73 @@ unsafe fn init_mmu(mut root_paddr: u64) {
 }
 
 /// Rust Temporary Entry
-unsafe fn rust_tmp_main(hart_id: usize, dtb: usize) {
+unsafe fn rust_tmp_main(dtb: usize) {
+    let hart_id = current_hart_id();
     crate::trap::aarch64::init();
     // Initialize all constructor functions.
-    crate::ph_init_iter().for_each(|phw| (phw.func)());
+    crate::ph_init_call();
     polyhal2_device::init_dtb(PhysAddr::new(dtb));
+    let boot_info = BootInfo::from_fdt(hart_id, PhysAddr::new(dtb));
+    crate::mmio::init();
+    crate::irq::init();
+    crate::timer::init();
+    crate::ipi::init();
     display_basic();
     display_info!("Platform CurrentEL", "{}", CurrentEL.read(CurrentEL::EL));
     display_info!();
     display_info!("Boot HART ID", "{}", hart_id);
-    display_end();
+    display_end(&boot_info);
 
     // Call rust main function.
-    super::call_rust_main(hart_id);
+    super::call_rust_main(boot_info);
+}
+
+/// Rust secondary entry
+unsafe fn rust_secondary_main() {
+    crate::trap::aarch64::init();
+    crate::irq::init_cpu();
+    crate::ipi::init_cpu();
+
+    super::call_secondary_main(current_hart_id());
+}
+
+/// Call `f` with the MPIDR affinity of every cpu in the device tree.
+pub(crate) fn for_each_cpu(f: impl FnMut(usize)) {
+    polyhal2_device::for_each_cpu(f);
+}
+
+/// Get the MPIDR affinity of the current cpu, the same as the id in the device tree.
+pub(crate) fn current_hart_id() -> usize {
+    MPIDR_EL1.get() as usize & 0xff_00ff_ffff
+}
+
+/// PSCI CPU_ON function id (SMC64)
+const PSCI_CPU_ON: usize = 0xC400_0003;
+
+/// Start the cpu through PSCI CPU_ON.
+///
+/// The conduit (hvc or smc) is read from the psci node of the device tree.
+pub(crate) fn start_cpu(hart_id: usize, stack_top: VirtAddr) -> bool {
+    let entry = VirtAddr::new(_secondary_start as usize).mapped_paddr();
+    let stack_top = stack_top.mapped_paddr();
+    let ret: isize;
+    unsafe {
+        match polyhal2_device::psci_method() {
+            Some("smc") => core::arch::asm!(
+                "smc #0",
+                inlateout("x0") PSCI_CPU_ON => ret,
+                in("x1") hart_id,
+                in("x2") entry.raw(),
+                in("x3") stack_top.raw(),
+            ),
+            _ => core::arch::asm!(
+                "hvc #0",
+                inlateout("x0") PSCI_CPU_ON => ret,
+                in("x1") hart_id,
+                in("x2") entry.raw(),
+                in("x3") stack_top.raw(),
+            ),
+        }
+    }
+    ret == 0
 }
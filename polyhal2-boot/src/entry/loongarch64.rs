@@ -1,7 +1,8 @@
-use loongArch64::register::euen;
-use polyhal2_core::addr::PhysAddr;
+use loongArch64::register::{cpuid, euen};
+use polyhal2_core::addr::{PhysAddr, VirtAddr};
 
 use crate::{
+    boot_info::BootInfo,
     console::{display_basic, display_end},
     display_info,
 };
@@ -20,6 +21,9 @@ unsafe extern "C" fn _start() -> ! {
             ori         $t0, $zero, 0x11    # CSR_DMW1_MAT | CSR_DMW1_PLV0
             lu52i.d     $t0, $t0, -1792     # CA, PLV0, 0x9000 xxxx xxxx xxxx
             csrwr       $t0, 0x181          # LOONGARCH_CSR_DMWIN1
+            ori         $t0, $zero, 0x21    # CSR_DMW2_MAT | CSR_DMW2_PLV0
+            lu52i.d     $t0, $t0, -1536     # WUC, PLV0, 0xa000 xxxx xxxx xxxx
+            csrwr       $t0, 0x182          # LOONGARCH_CSR_DMWIN2
         ",
         // Enable Paging Mode
         // TODO: Enable if need to enable paging mode
@@ -46,6 +50,41 @@ unsafe extern "C" fn _start() -> ! {
     }
 }
 
+/// IOCSR address of the mailbox 1, it holds the stack top of the secondary CPU.
+const LOONGARCH_IOCSR_MBUF1: usize = 0x1028;
+
+/// The entry point for the secondary CPUs.
+///
+/// The firmware jumps here after reading the address from mailbox 0.
+#[naked]
+unsafe extern "C" fn _secondary_start() -> ! {
+    unsafe {
+        core::arch::naked_asm!("
+            ori         $t0, $zero, 0x1     # CSR_DMW1_PLV0
+            lu52i.d     $t0, $t0, -2048     # UC, PLV0, 0x8000 xxxx xxxx xxxx
+            csrwr       $t0, 0x180          # LOONGARCH_CSR_DMWIN0
+            ori         $t0, $zero, 0x11    # CSR_DMW1_MAT | CSR_DMW1_PLV0
+            lu52i.d     $t0, $t0, -1792     # CA, PLV0, 0x9000 xxxx xxxx xxxx
+            csrwr       $t0, 0x181          # LOONGARCH_CSR_DMWIN1
+            ori         $t0, $zero, 0x21    # CSR_DMW2_MAT | CSR_DMW2_PLV0
+            lu52i.d     $t0, $t0, -1536     # WUC, PLV0, 0xa000 xxxx xxxx xxxx
+            csrwr       $t0, 0x182          # LOONGARCH_CSR_DMWIN2
+        ",
+        // Read the stack top from mailbox 1 and jump to secondary main function
+        "
+            li.d        $t0, {mbuf1}
+            iocsrrd.d   $sp, $t0
+
+            csrrd       $a0, 0x20           # cpuid
+            la.global   $t0, {entry}
+            jirl        $zero,$t0,0
+        ",
+            mbuf1 = const LOONGARCH_IOCSR_MBUF1,
+            entry = sym rust_secondary_main,
+        )
+    }
+}
+
 /// enter low cost area, loop until shutdown.
 pub fn hlt_forever() -> ! {
     loop {
@@ -61,17 +100,64 @@ pub fn rust_tmp_main(hart_id: usize) {
     init_cpu();
     crate::trap::loongarch64::init();
 
-    crate::ph_init_iter().for_each(|phw| (phw.func)());
+    crate::ph_init_call();
     // FIXME: Make this statement more efficient
     polyhal2_device::init_dtb(PhysAddr::new(0x100000));
+    let boot_info = BootInfo::from_fdt(hart_id, PhysAddr::new(0x100000));
+    crate::mmio::init();
+    crate::irq::init();
+    crate::timer::init();
+    crate::ipi::init();
 
     // Display Information.
     display_basic();
     display_info!();
     display_info!("Boot HART ID", "{}", hart_id);
-    display_end();
+    display_end(&boot_info);
+
+    super::call_rust_main(boot_info);
+}
+
+/// Rust secondary entry point
+pub fn rust_secondary_main(hart_id: usize) {
+    // Initialize CPU Configuration.
+    init_cpu();
+    crate::trap::loongarch64::init();
+    crate::irq::init_cpu();
+    crate::ipi::init_cpu();
 
-    super::call_rust_main(hart_id);
+    super::call_secondary_main(hart_id);
+}
+
+/// Call `f` with the cpuid of every cpu in the device tree.
+pub(crate) fn for_each_cpu(f: impl FnMut(usize)) {
+    polyhal2_device::for_each_cpu(f);
+}
+
+/// Get the cpuid of the current cpu.
+pub(crate) fn current_hart_id() -> usize {
+    cpuid::read().core_id()
+}
+
+/// IPI action to wake up the secondary CPU in the firmware.
+const ACTION_BOOT_CPU: u32 = 1 << 0;
+/// The core number is 10 bits in the IOCSR IPI and mailbox send registers.
+const MAX_CORE_ID: usize = 1 << 10;
+
+/// Start the cpu through the IOCSR mailbox.
+///
+/// Write the entry address to mailbox 0 and the stack top to mailbox 1,
+/// then send an IPI to wake up the cpu. The sends block until they are
+/// delivered, return false if the cpuid can't be addressed by them.
+pub(crate) fn start_cpu(hart_id: usize, stack_top: VirtAddr) -> bool {
+    if hart_id >= MAX_CORE_ID {
+        return false;
+    }
+    let entry = VirtAddr::new(_secondary_start as usize).mapped_paddr();
+    loongArch64::ipi::csr_mail_send(stack_top.raw() as _, hart_id, 1);
+    loongArch64::ipi::csr_mail_send(entry.raw() as _, hart_id, 0);
+    loongArch64::ipi::send_ipi_single(hart_id, ACTION_BOOT_CPU);
+    true
 }
 
 /// Initialize CPU Configuration.
@@ -2,49 +2,173 @@
 mod aarch64;
 #[cfg(target_arch = "aarch64")]
 pub use aarch64::hlt_forever;
+#[cfg(target_arch = "aarch64")]
+pub(crate) use aarch64::{current_hart_id, for_each_cpu, start_cpu};
 #[cfg(target_arch = "loongarch64")]
 mod loongarch64;
 #[cfg(target_arch = "loongarch64")]
 pub use loongarch64::hlt_forever;
+#[cfg(target_arch = "loongarch64")]
+pub(crate) use loongarch64::{current_hart_id, for_each_cpu, start_cpu};
 #[cfg(target_arch = "x86_64")]
 mod x86_64;
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::hlt_forever;
+#[cfg(target_arch = "x86_64")]
+pub(crate) use x86_64::{current_hart_id, for_each_cpu, start_cpu};
 #[cfg(target_arch = "riscv64")]
 mod riscv64;
 #[cfg(target_arch = "riscv64")]
 pub use riscv64::hlt_forever;
+#[cfg(target_arch = "riscv64")]
+pub(crate) use riscv64::{current_hart_id, for_each_cpu, start_cpu};
 
 use core::arch::global_asm;
-use polyhal2_core::consts::PAGE_SIZE;
 
-fn call_rust_main(hart_id: usize) -> ! {
+use crate::boot_info::BootInfo;
+#[cfg(not(target_arch = "loongarch64"))]
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(not(target_arch = "loongarch64"))]
+use polyhal2_core::addr::{PhysAddr, VirtAddr};
+#[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
+use polyhal2_core::consts::KERNEL_OFFSET;
+use polyhal2_core::consts::{PAGE_SHIFT, PAGE_SIZE};
+#[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
+use polyhal2_pagetable::{MappingFlags, MappingSize};
+#[cfg(not(target_arch = "loongarch64"))]
+use polyhal2_pagetable::{VSpace, VSpaceAO};
+
+fn call_rust_main(boot_info: BootInfo) -> ! {
+    let hart_id = boot_info.boot_hart_id;
+    crate::smp::set_boot_hart_id(hart_id);
+    let boot_info = crate::boot_info::init_boot_info(boot_info);
     // Call rust main function.
-    unsafe { crate::__polyhal_real_entry(hart_id) };
+    unsafe { crate::__polyhal_real_entry(hart_id, boot_info) };
+    hlt_forever()
+}
+
+fn call_secondary_main(hart_id: usize) -> ! {
+    crate::smp::mark_cpu_online(hart_id);
+    // Call rust secondary main function.
+    unsafe { crate::__polyhal_secondary_entry(hart_id) };
     hlt_forever()
 }
 
-// Map all memory to the page using 1GB Huge Page.
+// Map all memory to the page using the huge pages.
 global_asm!(
     "
     .section .data
-    .p2align 12
+    .p2align {PAGE_SHIFT}
     .global boot_page
     boot_page:
         .fill {PAGE_SIZE} * {BOOT_PAGES}
-", PAGE_SIZE = const PAGE_SIZE, BOOT_PAGES = const (get_boot_pages()) );
+", PAGE_SHIFT = const PAGE_SHIFT, PAGE_SIZE = const PAGE_SIZE, BOOT_PAGES = const (get_boot_pages()) );
+
+/// The size of the physical memory mapped by the boot page table.
+#[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
+const BOOT_MAP_SIZE: usize = 0x40_0000_0000;
+
+/// The huge pages mapping the boot memory, aarch64 only supports the blocks
+/// in the 1GB level with 4K pages.
+#[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
+const BOOT_BLOCK: MappingSize = match PAGE_SIZE {
+    0x1000 => MappingSize::Page1GB,
+    _ => MappingSize::Page2MB,
+};
+
+#[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
+const _: () = assert!(
+    KERNEL_OFFSET % BOOT_BLOCK.size() == 0,
+    "KERNEL_OFFSET must be aligned to the huge pages of the boot page table"
+);
+
+/// The pages in `boot_page` built by the boot code, the others are
+/// allocated by [BootPageAlloc].
+#[cfg(not(target_arch = "loongarch64"))]
+const BOOT_PAGE_RESERVED: usize = match cfg!(target_arch = "x86_64") {
+    // x86_64 use 514 physical pages (for not 1G huge page compatiable)
+    // and the PML5 after them for the five-level paging.
+    true if VSpace::PAGE_LEVEL == 5 => 515,
+    true => 514,
+    // riscv64 and aarch64 build the root.
+    false => 1,
+};
 
 /// Get the boot pages number
 const fn get_boot_pages() -> usize {
-    #[cfg(not(target_arch = "x86_64"))]
-    match polyhal2_pagetable::VSpace::PAGE_LEVEL {
-        // use 2 physical pages.
-        4 => 2,
-        // riscv64 and aarch64 will use 1 boot pages
-        3 => 1,
-        _ => panic!("Unsupported page level"),
-    }
-    // x86_64 use 514 physical pages (for not 1G huge page compatiable)
+    // The tables between the root and the huge pages of the boot memory
+    // and the table of the ioremap window.
+    #[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
+    return BOOT_PAGE_RESERVED + boot_tables() + 1;
     #[cfg(target_arch = "x86_64")]
-    514
+    return BOOT_PAGE_RESERVED + 1;
+    // loongarch64 uses the direct mapping windows.
+    #[cfg(target_arch = "loongarch64")]
+    return 1;
+}
+
+/// Get the number of the tables between the root and the huge pages
+/// mapping [BOOT_MAP_SIZE].
+#[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
+const fn boot_tables() -> usize {
+    let entries = PAGE_SIZE / size_of::<usize>();
+    // The memory covered by a table and the level of the entry linking it.
+    let mut covered = BOOT_BLOCK.size() * entries;
+    let mut level = match BOOT_BLOCK {
+        MappingSize::Page1GB => 3,
+        _ => 2,
+    };
+    let mut tables = 0;
+    while level < VSpace::PAGE_LEVEL {
+        tables += BOOT_MAP_SIZE.div_ceil(covered);
+        covered *= entries;
+        level += 1;
+    }
+    tables
+}
+
+/// Map the first [BOOT_MAP_SIZE] physical memory to the same virtual
+/// addresses in the boot page table at `root`, `flags` gives the flags
+/// of every block.
+#[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
+pub(crate) fn init_boot_page(root: PhysAddr, flags: impl Fn(PhysAddr) -> MappingFlags) {
+    let vspace = VSpace::from_paddr(root).with_allocator(&BootPageAlloc);
+    for addr in (0..BOOT_MAP_SIZE).step_by(BOOT_BLOCK.size()) {
+        let paddr = PhysAddr::new(addr);
+        vspace
+            .map_page(VirtAddr::new(addr), paddr, flags(paddr), BOOT_BLOCK)
+            .expect("Failed to map the boot page");
+    }
+}
+
+/// Allocate the pages from `boot_page` after the pages built by the boot
+/// code, they are the tables of the boot page table and the ioremap window.
+///
+/// It is used by the boot cpu before paging is enabled, the atomic
+/// read-modify-write instructions may not work on the memory then.
+#[cfg(not(target_arch = "loongarch64"))]
+pub(crate) struct BootPageAlloc;
+
+/// The number of the used pages in `boot_page`.
+#[cfg(not(target_arch = "loongarch64"))]
+static BOOT_PAGE_USED: AtomicUsize = AtomicUsize::new(BOOT_PAGE_RESERVED);
+
+#[cfg(not(target_arch = "loongarch64"))]
+impl VSpaceAO for BootPageAlloc {
+    fn alloc_page(&self) -> Option<PhysAddr> {
+        unsafe extern "C" {
+            fn boot_page();
+        }
+        let used = BOOT_PAGE_USED.load(Ordering::Relaxed);
+        if used >= get_boot_pages() {
+            return None;
+        }
+        BOOT_PAGE_USED.store(used + 1, Ordering::Relaxed);
+        let vaddr = VirtAddr::new(boot_page as usize + used * PAGE_SIZE);
+        Some(vaddr.mapped_paddr())
+    }
+
+    fn free_page(&self, _paddr: PhysAddr) {
+        unreachable!("The boot page tables are never freed")
+    }
 }
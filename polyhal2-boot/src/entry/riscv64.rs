@@ -2,13 +2,14 @@ use polyhal2_core::{
     addr::{PhysAddr, VirtAddr},
     consts::KERNEL_OFFSET,
 };
-use polyhal2_pagetable::{MappingFlags, MappingSize, VSpace};
+use polyhal2_pagetable::{MappingFlags, VSpace};
 use riscv::{
     asm::wfi,
     register::{sie, sstatus},
 };
 
 use crate::{
+    boot_info::BootInfo,
     console::{display_basic, display_end},
     display_info,
 };
@@ -33,11 +34,11 @@ unsafe extern "C" fn _start() -> ! {
                 or      sp, sp, s0
             ",
             // 2. Open Paging Mode
-            // satp = (8 << 60) | PPN(page_table)
+            // satp = (mode << 60) | PPN(page_table)
             "
                 la      t0, boot_page
                 srli    t0, t0, 12
-                li      t1, 8 << 60
+                li      t1, {mode} << 60
                 or      t0, t0, t1
                 csrw    satp, t0
                 sfence.vma
@@ -53,6 +54,7 @@ unsafe extern "C" fn _start() -> ! {
             entry = sym rust_main,
             init_vspace = sym init_vspace,
             offset = const KERNEL_OFFSET,
+            mode = const VSpace::SATP_MODE,
         )
     }
 }
@@ -60,6 +62,7 @@ unsafe extern "C" fn _start() -> ! {
 /// Assembly Entry Function
 ///
 /// Initialize Page Information. Call rust_secondary_main entry function.
+/// a0 is the hart id and a1 is the stack top given by [start_cpu].
 #[naked]
 #[unsafe(no_mangle)]
 pub(crate) unsafe extern "C" fn secondary_start() -> ! {
@@ -75,11 +78,11 @@ pub(crate) unsafe extern "C" fn secondary_start() -> ! {
                 or      sp, sp, s0
             ",
             // 2. Call Paging Mode
-            // satp = (8 << 60) | PPN(page_table)
+            // satp = (mode << 60) | PPN(page_table)
             "
                 la      t0, boot_page
                 srli    t0, t0, 12
-                li      t1, 8 << 60
+                li      t1, {mode} << 60
                 or      t0, t0, t1
                 csrw    satp, t0
                 sfence.vma
@@ -93,6 +96,7 @@ pub(crate) unsafe extern "C" fn secondary_start() -> ! {
             ",
             entry = sym rust_secondary_main,
             offset = const KERNEL_OFFSET,
+            mode = const VSpace::SATP_MODE,
         );
     }
 }
@@ -100,42 +104,63 @@ pub(crate) unsafe extern "C" fn secondary_start() -> ! {
 pub(crate) fn rust_main(hartid: usize, dtb: usize) {
     // Initialize CPU Configuration.
     init_cpu();
-    crate::trap::riscv64::init();
+    crate::trap::riscv64::init(hartid);
 
-    crate::ph_init_iter().for_each(|phw| (phw.func)());
+    crate::ph_init_call();
     display_info!("DTB PTR", "{:#X}", dtb);
     polyhal2_device::init_dtb(PhysAddr::new(dtb));
+    if polyhal2_device::has_isa_extension("svpbmt") {
+        polyhal2_pagetable::enable_svpbmt();
+    }
+    let boot_info = BootInfo::from_fdt(hartid, PhysAddr::new(dtb));
+    crate::mmio::init();
+    crate::irq::init();
+    crate::timer::init();
+    crate::ipi::init();
     // Display Information.
     display_basic();
     display_info!();
     display_info!("Boot HART ID", "{}", hartid);
-    display_end();
+    display_end(&boot_info);
 
-    super::call_rust_main(hartid);
+    super::call_rust_main(boot_info);
 }
 
 /// Secondary Main function Entry.
 ///
 /// Supports MultiCore, Boot in this function.
 pub(crate) extern "C" fn rust_secondary_main(hartid: usize) {
-    // TODO: Get the hart_id and device_tree for the specified device.
-    // let (hartid, _device_tree) = boards::init_device(hartid, 0);
-
     // Initialize CPU Configuration.
     init_cpu();
+    crate::trap::riscv64::init(hartid);
+    crate::irq::init_cpu();
+    crate::ipi::init_cpu();
 
-    super::call_rust_main(hartid);
+    super::call_secondary_main(hartid);
 }
 
-fn init_vspace(vspace: VSpace) {
-    for i in 0..0x100 {
-        vspace.map_page(
-            VirtAddr::new(i * 0x4000_0000),
-            PhysAddr::new(i * 0x4000_0000),
-            MappingFlags::RWX,
-            MappingSize::Page1GB,
-        );
-    }
+/// Call `f` with the hart id of every hart in the device tree.
+pub(crate) fn for_each_cpu(f: impl FnMut(usize)) {
+    polyhal2_device::for_each_cpu(f);
+}
+
+/// Start the hart through SBI HSM extension.
+///
+/// The hart starts at [secondary_start] with the physical address.
+pub(crate) fn start_cpu(hart_id: usize, stack_top: VirtAddr) -> bool {
+    let entry = VirtAddr::new(secondary_start as usize).mapped_paddr();
+    sbi_rt::hart_start(hart_id, entry.raw(), stack_top.mapped_paddr().raw()).is_ok()
+}
+
+/// Map the boot page table, `root` is the physical address of `boot_page`.
+fn init_vspace(root: usize) {
+    super::init_boot_page(PhysAddr::new(root), |_| MappingFlags::RWX);
+}
+
+/// Get the hart id of the current hart, which is kept in the per-cpu area
+/// since it can't be read in supervisor mode.
+pub(crate) fn current_hart_id() -> usize {
+    crate::trap::riscv64::current_hart_id()
 }
 
 #[inline]
@@ -1,14 +1,23 @@
+mod mb2_entry;
 mod mb_entry;
 
 use core::{arch::global_asm, slice};
 
-use mb_entry::use_multiboot;
-use polyhal2_core::consts::KERNEL_OFFSET;
-use x86_64::registers::control::{Cr0Flags, Cr4Flags, EferFlags};
+use polyhal2_core::{
+    addr::{PhysAddr, VirtAddr},
+    consts::KERNEL_OFFSET,
+};
+use polyhal2_device::acpi::{self, MadtEntry};
+use polyhal2_pagetable::VSpace;
+use x86_64::registers::{
+    control::{Cr0Flags, Cr4, Cr4Flags, EferFlags},
+    model_specific::Msr,
+};
 
 use crate::{
+    boot_info::{BootInfo, FirmwareTable, MemoryRegionKind},
     console::{display_basic, display_end},
-    display_info,
+    display_info, timer,
 };
 
 /// CR0 Registers introduction: https://wiki.osdev.org/CPU_Registers_x86-64#CR0
@@ -31,13 +40,26 @@ const CR4: u64 = Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits()
     // This bit should open if the processor was supported.
     // | Cr4Flags::OSXSAVE.bits()
     // OS Support for unmasked simd floating point exceptions
-    | Cr4Flags::OSXMMEXCPT_ENABLE.bits();
+    | Cr4Flags::OSXMMEXCPT_ENABLE.bits()
+    // 57-bit virtual addresses with the five-level paging.
+    | match VSpace::PAGE_LEVEL {
+        5 => Cr4Flags::L5_PAGING.bits(),
+        _ => 0,
+    };
+
+/// The offset of the root in `boot_page`, the PML5 is after the 514 pages.
+const BOOT_ROOT_OFFSET: usize = match VSpace::PAGE_LEVEL {
+    5 => 514 * 0x1000,
+    _ => 0,
+};
 
 const IA32_EFER_NUM: u32 = 0xC0000080;
-const EFER: u64 = EferFlags::LONG_MODE_ENABLE.bits();
+/// Long mode and the XD bit of the page table entries.
+const EFER: u64 = EferFlags::LONG_MODE_ENABLE.bits() | EferFlags::NO_EXECUTE_ENABLE.bits();
 global_asm!(
     include_str!("x86_64/entry.S"),
     entry = sym rust_tmp_main,
+    secondary_entry = sym rust_secondary_main,
 
     offset = const KERNEL_OFFSET,
 
@@ -45,49 +67,203 @@ global_asm!(
     cr4 = const CR4,
     efer_msr = const IA32_EFER_NUM,
     efer = const EFER,
+    root = const BOOT_ROOT_OFFSET,
+    la57 = const (VSpace::PAGE_LEVEL == 5) as usize,
 );
 
+/// The physical page which the AP start code will be copied to.
+/// The page number is used as the vector of the STARTUP IPI.
+const AP_START_PAGE: usize = 0x6000;
+
 global_asm!(
-    include_str!("x86_64/multiboot.S"),
-    mb_hdr_magic = const (mb_entry::MULTIBOOT_HEADER_MAGIC),
-    mb_hdr_flags = const (mb_entry::MULTIBOOT_HEADER_FLAGS),
-    graphic_mode = const 0,
-    offset = const (polyhal2_core::consts::KERNEL_OFFSET),
+    include_str!("x86_64/ap_start.S"),
+    start_page_paddr = const AP_START_PAGE,
 );
 
 fn rust_tmp_main(magic: usize, mboot_ptr: u64) {
     // Initialize CPU Configuration.
     init_page_table();
+    enable_pcid();
+    init_pat();
     crate::trap::x86_64::init();
 
-    crate::ph_init_iter().for_each(|phw| (phw.func)());
-    let hart_id = match raw_cpuid::CpuId::new().get_feature_info() {
-        Some(finfo) => finfo.initial_local_apic_id() as _,
-        None => 0,
-    };
+    crate::ph_init_call();
+    let hart_id = current_hart_id();
     // Display Information.
     display_basic();
     display_info!("Platform Multiboot Magic", "{:#x?}", magic);
     display_info!("Platform Multiboot", "{:#018x}", mboot_ptr);
-
-    if let Some(mboot) = use_multiboot(mboot_ptr) {
-        if let Some(regions) = mboot.memory_regions() {
-            regions.for_each(|rg| {
-                display_info!(
-                    "Platform Memory Region",
-                    "{:#018x} - {:#018x} {:?}",
-                    rg.base_address(),
-                    rg.base_address() + rg.length(),
-                    rg.memory_type()
-                );
-            });
+    let mut boot_info = match magic {
+        mb_entry::BOOTLOADER_MAGIC => mb_entry::boot_info_from_multiboot(hart_id, mboot_ptr),
+        mb2_entry::BOOTLOADER_MAGIC => {
+            mb2_entry::boot_info_from_multiboot2(hart_id, mboot_ptr as _)
         }
-    }
+        _ => {
+            log::warn!("Unknown multiboot magic: {:#x}", magic);
+            BootInfo::new(hart_id)
+        }
+    };
+    add_platform_regions(&mut boot_info);
+    crate::mmio::init();
+    crate::irq::init();
+    crate::timer::init();
+    crate::ipi::init();
     display_info!();
     display_info!("Boot HART ID", "{}", hart_id);
-    display_end();
+    display_end(&boot_info);
+
+    super::call_rust_main(boot_info);
+}
 
-    super::call_rust_main(hart_id);
+/// The size of the local APIC and I/O APIC registers.
+const APIC_MMIO_SIZE: usize = 0x1000;
+
+/// Add the regions which aren't described by the bootloader.
+///
+/// Includes the initrd, the kernel image and the APIC registers in the MADT.
+fn add_platform_regions(info: &mut BootInfo) {
+    if let Some(initrd) = info.initrd.clone() {
+        info.add_memory_region(initrd.start, initrd.end, MemoryRegionKind::Reserved);
+    }
+    info.add_kernel_image();
+
+    if let Some(rsdp) = acpi::get_rsdp_ptr() {
+        info.firmware_table = FirmwareTable::Rsdp(rsdp);
+    }
+    let mut add_mmio = |paddr: PhysAddr| {
+        let end = PhysAddr::new(paddr.raw() + APIC_MMIO_SIZE);
+        info.add_memory_region(paddr, end, MemoryRegionKind::Mmio);
+    };
+    if let Some(lapic) = acpi::local_apic_addr() {
+        add_mmio(lapic);
+    }
+    acpi::for_each_madt_entry(|entry| {
+        if let MadtEntry::IoApic { address, .. } = entry {
+            add_mmio(address);
+        }
+    });
+}
+
+/// Rust secondary entry, called from `ap_entry64` in entry.S
+fn rust_secondary_main() {
+    enable_pcid();
+    init_pat();
+    crate::trap::x86_64::init();
+    crate::irq::init_cpu();
+    crate::ipi::init_cpu();
+
+    super::call_secondary_main(current_hart_id());
+}
+
+/// Get the local APIC id of the current cpu.
+pub(crate) fn current_hart_id() -> usize {
+    match raw_cpuid::CpuId::new().get_feature_info() {
+        Some(finfo) => finfo.initial_local_apic_id() as _,
+        None => 0,
+    }
+}
+
+/// Call `f` with the local APIC id of every enabled cpu in the ACPI MADT.
+pub(crate) fn for_each_cpu(mut f: impl FnMut(usize)) {
+    polyhal2_device::acpi::for_each_madt_entry(|entry| {
+        if let MadtEntry::LocalApic {
+            apic_id,
+            enabled: true,
+            ..
+        } = entry
+        {
+            f(apic_id as _)
+        }
+    });
+}
+
+/// IA32_APIC_BASE MSR, contains the physical address of the local APIC.
+const IA32_APIC_BASE: u32 = 0x1b;
+/// Local APIC Interrupt Command Register, low 32 bits.
+const APIC_ICR_LOW: usize = 0x300;
+/// Local APIC Interrupt Command Register, high 32 bits.
+const APIC_ICR_HIGH: usize = 0x310;
+/// ICR delivery status, the IPI is still pending.
+const APIC_ICR_PENDING: u32 = 1 << 12;
+/// Local APIC Error Status Register.
+const APIC_ESR: usize = 0x280;
+/// The time to wait for the delivery of the IPI, in microseconds.
+const APIC_IPI_TIMEOUT_US: u64 = 1000;
+/// ICR value of the INIT IPI, level assert.
+const APIC_ICR_INIT: u32 = 0x4500;
+/// ICR value of the STARTUP IPI, vector is the page number of the start code.
+const APIC_ICR_STARTUP: u32 = 0x4600 | (AP_START_PAGE >> 12) as u32;
+
+/// Send an IPI through the local APIC of the current cpu.
+///
+/// Return false if the IPI isn't delivered in time or the local APIC
+/// reports an error.
+fn send_apic_ipi(apic_id: usize, icr: u32) -> bool {
+    let base = unsafe { Msr::new(IA32_APIC_BASE).read() } as usize & 0xffff_f000;
+    let base = PhysAddr::new(base).mapped_vaddr().raw();
+    let esr = (base + APIC_ESR) as *mut u32;
+    let timeout = timer::nanos_to_ticks(APIC_IPI_TIMEOUT_US * 1000);
+    unsafe {
+        // The ESR is updated by a write before it is read.
+        esr.write_volatile(0);
+        ((base + APIC_ICR_HIGH) as *mut u32).write_volatile((apic_id as u32) << 24);
+        ((base + APIC_ICR_LOW) as *mut u32).write_volatile(icr);
+        let start = timer::current_ticks();
+        while ((base + APIC_ICR_LOW) as *const u32).read_volatile() & APIC_ICR_PENDING != 0 {
+            if timer::current_ticks() - start >= timeout {
+                return false;
+            }
+            core::hint::spin_loop();
+        }
+        esr.write_volatile(0);
+        esr.read_volatile() == 0
+    }
+}
+
+/// Wait for `micros` microseconds with the TSC calibrated by the timer.
+fn delay_micros(micros: u64) {
+    let ticks = timer::nanos_to_ticks(micros * 1000);
+    let start = timer::current_ticks();
+    while timer::current_ticks() - start < ticks {
+        core::hint::spin_loop();
+    }
+}
+
+/// Start the application processor through INIT-SIPI-SIPI.
+///
+/// The AP start code is copied to [AP_START_PAGE], the physical stack top and
+/// the entry `ap_entry32` are placed at the end of the page.
+/// Return false if any of the IPIs fails.
+pub(crate) fn start_cpu(hart_id: usize, stack_top: VirtAddr) -> bool {
+    unsafe extern "C" {
+        fn ap_start();
+        fn ap_end();
+        fn ap_entry32();
+    }
+    let start_page = PhysAddr::new(AP_START_PAGE).mapped_vaddr();
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            ap_start as *const u8,
+            start_page.get_mut_ptr(),
+            ap_end as usize - ap_start as usize,
+        );
+        let args = start_page.slice_mut_with_len::<u64>(0x200);
+        args[0x1fe] = stack_top.mapped_paddr().raw() as _;
+        args[0x1ff] = VirtAddr::new(ap_entry32 as usize).mapped_paddr().raw() as _;
+    }
+
+    // Wait 10 ms after the INIT IPI and 200 us after each STARTUP IPI.
+    if !send_apic_ipi(hart_id, APIC_ICR_INIT) {
+        return false;
+    }
+    delay_micros(10_000);
+    for _ in 0..2 {
+        if !send_apic_ipi(hart_id, APIC_ICR_STARTUP) {
+            return false;
+        }
+        delay_micros(200);
+    }
+    true
 }
 
 /// enter low cost area, loop until shutdown.
@@ -97,6 +273,29 @@ pub fn hlt_forever() -> ! {
     }
 }
 
+/// Enable the PCIDs if the cpu supports them, the page table uses them as the ASIDs.
+///
+/// The PCID of the current `CR3` is 0 when it is enabled.
+fn enable_pcid() {
+    let has_pcid = raw_cpuid::CpuId::new()
+        .get_feature_info()
+        .is_some_and(|info| info.has_pcid());
+    if has_pcid {
+        unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::PCID)) };
+    }
+}
+
+/// IA32_PAT MSR, the memory types selected by the PAT, PCD and PWT bits.
+const IA32_PAT: u32 = 0x277;
+/// The default layout with the entry 1 changed from write-through to
+/// write-combining: WB, WC, UC-, UC, WB, WC, UC-, UC.
+const PAT_LAYOUT: u64 = 0x0007_0106_0007_0106;
+
+/// Set the PAT layout used by the page table, PWT selects write-combining.
+fn init_pat() {
+    unsafe { Msr::new(IA32_PAT).write(PAT_LAYOUT) };
+}
+
 /// Initialize Boot Page Table
 fn init_page_table() {
     unsafe extern "C" {
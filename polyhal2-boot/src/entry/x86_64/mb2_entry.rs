@@ -1,17 +1,275 @@
-//! Multiboot Header And Defination
+//! Multiboot2 Header And Defination
 //!
 //! <https://www.gnu.org/software/grub/manual/multiboot2/multiboot.html>
 //!
 //! The layout of the Multiboot2 header must be as follows:
-//! Offset	Type	Field Name	Note
-//! 0	u32	magic	required
-//! 4	u32	architecture	required
-//! 8	u32	header_length	required
-//! 12	u32	checksum	required
-//! 16-XX		tags	required
-pub const MAGIC: u32 = 0xE85250D6;
+//! | Offset | Type | Field Name    | Note     |
+//! |--------|------|---------------|----------|
+//! | 0      | u32  | magic         | required |
+//! | 4      | u32  | architecture  | required |
+//! | 8      | u32  | header_length | required |
+//! | 12     | u32  | checksum      | required |
+//! | 16-XX  |      | tags          | required |
+
+use core::{arch::global_asm, ops::Range};
+
+use polyhal2_core::{addr::PhysAddr, consts::KERNEL_OFFSET};
+
+use crate::boot_info::{BootInfo, FrameBuffer, MemoryRegionKind};
+
+/// The magic field of the multiboot2 header.
+pub(super) const MAGIC: u32 = 0xE85250D6;
 /// Spec: "means 32-bit (protected) mode of i386".
 /// Caution: This is confusing. If you use the EFI64-tag
 /// on an UEFI system, the machine will boot into `64-bit long mode`.
 /// Therefore this tag should be understood as "arch=x86|x86_64".
-// pub const ARCH: u32 = 
\ No newline at end of file
+pub(super) const ARCH: u32 = 0;
+/// The magic value passed in `eax` by the multiboot2 bootloader.
+pub(super) const BOOTLOADER_MAGIC: usize = 0x36D76289;
+
+global_asm!(
+    include_str!("multiboot2.S"),
+    mb2_hdr_magic = const MAGIC,
+    mb2_arch = const ARCH,
+    offset = const KERNEL_OFFSET,
+);
+
+/// The types of the tags in the boot information.
+mod tag_type {
+    pub const END: u32 = 0;
+    pub const CMDLINE: u32 = 1;
+    pub const MODULE: u32 = 3;
+    pub const MEMORY_MAP: u32 = 6;
+    pub const FRAMEBUFFER: u32 = 8;
+    pub const ACPI_OLD: u32 = 14;
+    pub const ACPI_NEW: u32 = 15;
+    pub const EFI_MEMORY_MAP: u32 = 17;
+}
+
+/// Read a value from the given physical address.
+#[inline]
+fn read<T: Copy>(paddr: usize) -> T {
+    unsafe {
+        PhysAddr::new(paddr)
+            .mapped_vaddr()
+            .get_ptr::<T>()
+            .read_unaligned()
+    }
+}
+
+/// Read a null-terminated string in the given physical range.
+fn read_str(paddr: usize, max_len: usize) -> &'static str {
+    let bytes = PhysAddr::new(paddr)
+        .mapped_vaddr()
+        .slice_with_len::<u8>(max_len);
+    let len = bytes.iter().position(|c| *c == 0).unwrap_or(max_len);
+    core::str::from_utf8(&bytes[..len]).unwrap_or("")
+}
+
+/// A tag in the boot information.
+#[derive(Debug, Clone, Copy)]
+pub struct Tag {
+    /// The type of the tag.
+    pub typ: u32,
+    /// The physical address of the tag.
+    pub addr: usize,
+    /// The size of the tag, includes the header.
+    pub size: usize,
+}
+
+impl Tag {
+    /// The physical address of the tag body.
+    const fn body(&self) -> usize {
+        self.addr + 8
+    }
+}
+
+/// The entry of the memory map provided by the bootloader.
+#[derive(Debug, Clone)]
+pub struct MemoryMapEntry {
+    /// The physical range of the region.
+    pub range: Range<usize>,
+    /// The type of the region, 1 means available RAM.
+    pub typ: u32,
+}
+
+/// The descriptor of the EFI memory map.
+#[derive(Debug, Clone)]
+pub struct EfiMemoryDescriptor {
+    /// The physical range of the region.
+    pub range: Range<usize>,
+    /// The EFI memory type.
+    pub typ: u32,
+}
+
+/// The boot information passed by the multiboot2 bootloader.
+pub struct Multiboot2Info {
+    addr: usize,
+    total_size: usize,
+}
+
+impl Multiboot2Info {
+    /// Parse the boot information at the given physical address.
+    pub fn new(addr: usize) -> Self {
+        Self {
+            addr,
+            total_size: read::<u32>(addr) as usize,
+        }
+    }
+
+    /// The physical range occupied by the boot information.
+    pub fn range(&self) -> Range<usize> {
+        self.addr..self.addr + self.total_size
+    }
+
+    /// Iterate all tags, the end tag isn't included.
+    pub fn tags(&self) -> impl Iterator<Item = Tag> {
+        let end = self.addr + self.total_size;
+        let mut addr = self.addr + 8;
+        core::iter::from_fn(move || {
+            if addr + 8 > end {
+                return None;
+            }
+            let tag = Tag {
+                typ: read(addr),
+                addr,
+                size: read::<u32>(addr + 4) as usize,
+            };
+            if tag.typ == tag_type::END || tag.size < 8 {
+                return None;
+            }
+            addr += tag.size.next_multiple_of(8);
+            Some(tag)
+        })
+    }
+
+    /// Find the first tag with the given type.
+    fn find_tag(&self, typ: u32) -> Option<Tag> {
+        self.tags().find(|tag| tag.typ == typ)
+    }
+
+    /// Get the kernel command line.
+    pub fn command_line(&self) -> Option<&'static str> {
+        self.find_tag(tag_type::CMDLINE)
+            .map(|tag| read_str(tag.body(), tag.size - 8))
+    }
+
+    /// Iterate the modules, yields the physical range and the command line.
+    pub fn modules(&self) -> impl Iterator<Item = (Range<usize>, &'static str)> {
+        self.tags()
+            .filter(|tag| tag.typ == tag_type::MODULE)
+            .map(|tag| {
+                let start = read::<u32>(tag.body()) as usize;
+                let end = read::<u32>(tag.body() + 4) as usize;
+                (start..end, read_str(tag.body() + 8, tag.size - 16))
+            })
+    }
+
+    /// Iterate the entries in the memory map.
+    pub fn memory_map(&self) -> impl Iterator<Item = MemoryMapEntry> {
+        self.find_tag(tag_type::MEMORY_MAP)
+            .into_iter()
+            .flat_map(|tag| {
+                let entry_size = read::<u32>(tag.body()) as usize;
+                (tag.body() + 8..tag.addr + tag.size).step_by(entry_size.max(24))
+            })
+            .map(|entry| {
+                let start = read::<u64>(entry) as usize;
+                let len = read::<u64>(entry + 8) as usize;
+                MemoryMapEntry {
+                    range: start..start + len,
+                    typ: read(entry + 16),
+                }
+            })
+    }
+
+    /// Get the framebuffer information.
+    pub fn framebuffer(&self) -> Option<FrameBuffer> {
+        let body = self.find_tag(tag_type::FRAMEBUFFER)?.body();
+        Some(FrameBuffer {
+            addr: PhysAddr::new(read::<u64>(body) as usize),
+            pitch: read::<u32>(body + 8) as usize,
+            width: read::<u32>(body + 12) as usize,
+            height: read::<u32>(body + 16) as usize,
+            bpp: read(body + 20),
+        })
+    }
+
+    /// Get the physical address of the RSDP copied by the bootloader.
+    ///
+    /// The new RSDP (ACPI 2.0+) is preferred.
+    pub fn rsdp(&self) -> Option<PhysAddr> {
+        self.find_tag(tag_type::ACPI_NEW)
+            .or_else(|| self.find_tag(tag_type::ACPI_OLD))
+            .map(|tag| PhysAddr::new(tag.body()))
+    }
+
+    /// Iterate the descriptors in the EFI memory map.
+    pub fn efi_memory_map(&self) -> impl Iterator<Item = EfiMemoryDescriptor> {
+        self.find_tag(tag_type::EFI_MEMORY_MAP)
+            .into_iter()
+            .flat_map(|tag| {
+                let desc_size = read::<u32>(tag.body()) as usize;
+                (tag.body() + 8..tag.addr + tag.size).step_by(desc_size.max(40))
+            })
+            .map(|desc| {
+                let start = read::<u64>(desc + 8) as usize;
+                let pages = read::<u64>(desc + 24) as usize;
+                EfiMemoryDescriptor {
+                    range: start..start + pages * 0x1000,
+                    typ: read(desc),
+                }
+            })
+    }
+}
+
+/// Get the kind of the region in the EFI memory map.
+///
+/// The loader and boot services memory can be used after exiting boot services.
+const fn efi_memory_kind(typ: u32) -> MemoryRegionKind {
+    match typ {
+        // EfiLoaderCode, EfiLoaderData, EfiBootServicesCode,
+        // EfiBootServicesData, EfiConventionalMemory
+        1..=4 | 7 => MemoryRegionKind::Usable,
+        _ => MemoryRegionKind::Reserved,
+    }
+}
+
+/// Fill the boot information from the multiboot2 information.
+pub fn boot_info_from_multiboot2(boot_hart_id: usize, mboot_ptr: usize) -> BootInfo {
+    let mut info = BootInfo::new(boot_hart_id);
+    let mb2 = Multiboot2Info::new(mboot_ptr);
+    let mut add_region = |range: Range<usize>, kind| {
+        info.add_memory_region(PhysAddr::new(range.start), PhysAddr::new(range.end), kind)
+    };
+
+    // Prefer the memory map, the EFI memory map is used if it doesn't exist.
+    if mb2.find_tag(tag_type::MEMORY_MAP).is_some() {
+        mb2.memory_map().for_each(|entry| {
+            let kind = match entry.typ {
+                1 => MemoryRegionKind::Usable,
+                _ => MemoryRegionKind::Reserved,
+            };
+            add_region(entry.range, kind);
+        });
+    } else {
+        mb2.efi_memory_map()
+            .for_each(|desc| add_region(desc.range, efi_memory_kind(desc.typ)));
+    }
+    add_region(mb2.range(), MemoryRegionKind::Reserved);
+    mb2.modules()
+        .for_each(|(range, _)| add_region(range, MemoryRegionKind::Reserved));
+
+    info.cmdline = mb2.command_line().unwrap_or("");
+    info.initrd = mb2
+        .modules()
+        .next()
+        .map(|(range, _)| PhysAddr::new(range.start)..PhysAddr::new(range.end));
+    if let Some(fb) = mb2.framebuffer() {
+        info.set_framebuffer(fb);
+    }
+    if let Some(rsdp) = mb2.rsdp() {
+        polyhal2_device::acpi::init_rsdp(rsdp);
+    }
+    info
+}
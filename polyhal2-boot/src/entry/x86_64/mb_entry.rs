@@ -1,7 +1,9 @@
 use core::{arch::global_asm, slice};
 
-use multiboot::information::{MemoryManagement, Multiboot, PAddr};
-use polyhal2_core::{bit, consts::KERNEL_OFFSET};
+use multiboot::information::{MemoryManagement, MemoryType, Multiboot, PAddr};
+use polyhal2_core::{addr::PhysAddr, bit, consts::KERNEL_OFFSET};
+
+use crate::boot_info::{BootInfo, FrameBuffer, MemoryRegionKind};
 
 /// Flags set in the 'flags' member of the multiboot header.
 ///
@@ -12,6 +14,12 @@ pub(super) const MULTIBOOT_HEADER_FLAGS: u32 = bit!(1) | bit!(16) | bit!(2);
 /// The magic field should contain this.
 pub(super) const MULTIBOOT_HEADER_MAGIC: u32 = 0x1BADB002;
 
+/// The magic value passed in `eax` by the multiboot bootloader.
+pub(super) const BOOTLOADER_MAGIC: usize = 0x2BADB002;
+
+/// The size of the multiboot information structure, up to the framebuffer fields.
+const MULTIBOOT_INFO_SIZE: usize = 116;
+
 global_asm!(
     include_str!("multiboot.S"),
     mb_hdr_magic = const MULTIBOOT_HEADER_MAGIC,
@@ -48,3 +56,56 @@ impl MemoryManagement for Mem {
 pub fn use_multiboot(mboot_ptr: PAddr) -> Option<Multiboot<'static, 'static>> {
     unsafe { Multiboot::from_ptr(mboot_ptr, &mut MEM) }
 }
+
+/// Fill the boot information from the multiboot information.
+pub fn boot_info_from_multiboot(boot_hart_id: usize, mboot_ptr: PAddr) -> BootInfo {
+    let mut info = BootInfo::new(boot_hart_id);
+    let Some(mboot) = use_multiboot(mboot_ptr) else {
+        return info;
+    };
+    if let Some(regions) = mboot.memory_regions() {
+        regions.for_each(|rg| {
+            let kind = match rg.memory_type() {
+                MemoryType::Available => MemoryRegionKind::Usable,
+                _ => MemoryRegionKind::Reserved,
+            };
+            let start = rg.base_address() as usize;
+            let end = start + rg.length() as usize;
+            info.add_memory_region(PhysAddr::new(start), PhysAddr::new(end), kind);
+        });
+    }
+    // The information, the command line and the modules are used after boot.
+    let mut reserve = |start: usize, end: usize| {
+        info.add_memory_region(
+            PhysAddr::new(start),
+            PhysAddr::new(end),
+            MemoryRegionKind::Reserved,
+        )
+    };
+    let mboot_ptr = mboot_ptr as usize;
+    reserve(mboot_ptr, mboot_ptr + MULTIBOOT_INFO_SIZE);
+    if let Some(modules) = mboot.modules() {
+        modules.for_each(|module| reserve(module.start as _, module.end as _));
+    }
+    let cmdline = mboot.command_line().unwrap_or("");
+    if !cmdline.is_empty() {
+        // The command line is mapped at the physical address with KERNEL_OFFSET.
+        let start = cmdline.as_ptr() as usize & !KERNEL_OFFSET;
+        reserve(start, start + cmdline.len() + 1);
+    }
+    info.cmdline = cmdline;
+    info.initrd = mboot
+        .modules()
+        .and_then(|mut modules| modules.next())
+        .map(|module| PhysAddr::new(module.start as _)..PhysAddr::new(module.end as _));
+    if let Some(fb) = mboot.framebuffer_table() {
+        info.set_framebuffer(FrameBuffer {
+            addr: PhysAddr::new(fb.addr as _),
+            pitch: fb.pitch as _,
+            width: fb.width as _,
+            height: fb.height as _,
+            bpp: fb.bpp,
+        });
+    }
+    info
+}
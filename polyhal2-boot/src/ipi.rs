@@ -0,0 +1,90 @@
+//! Inter-processor interrupt
+//!
+//! [send_ipi] records the [IpiKind] in the pending kinds of every target cpu
+//! and raises [IPI_IRQ] on them:
+//! - riscv64: SBI IPI extension, `sip.SSIP`
+//! - aarch64: GIC SGI 1
+//! - x86_64: Local APIC ICR, vector `0xf1`
+//! - loongarch64: IOCSR IPI
+//!
+//! The target cpu takes the pending kinds in the [IPI_IRQ] handler and
+//! calls the handler given by [crate::ipi_handler] with every kind.
+//! [IpiKind::TlbShootdown] is handled by polyhal2 to flush the TLB entries
+//! requested by `polyhal2_pagetable::TlbBatch`, it isn't passed to the handler.
+//! The kinds sent several times before the interrupt is handled are
+//! delivered once.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::{
+    irq::{IPI_IRQ, enable_percpu_irq, register_irq},
+    smp::{CpuMask, MAX_CPUS, current_cpu_id, hart_id_of},
+};
+
+/// The kind of the inter-processor interrupt.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiKind {
+    /// Reschedule the current cpu.
+    Reschedule = 0,
+    /// Call the functions queued by the sender.
+    CallFunction = 1,
+    /// Flush the TLB entries changed by the sender.
+    TlbShootdown = 2,
+}
+
+impl IpiKind {
+    /// All the kinds in the order they are handled.
+    const ALL: [IpiKind; 3] = [
+        IpiKind::Reschedule,
+        IpiKind::CallFunction,
+        IpiKind::TlbShootdown,
+    ];
+
+    /// The bit of the kind in the pending kinds.
+    const fn bit(self) -> u32 {
+        1 << self as u8
+    }
+}
+
+/// The pending kinds of every cpu, indexed by the cpu id.
+static PENDING: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+
+/// Send the inter-processor interrupt `kind` to the cpus in `target`.
+///
+/// The current cpu is also interrupted if it is in `target`.
+pub fn send_ipi(target: CpuMask, kind: IpiKind) {
+    for cpu_id in target.iter() {
+        let Some(hart_id) = hart_id_of(cpu_id) else {
+            continue;
+        };
+        PENDING[cpu_id].fetch_or(kind.bit(), Ordering::Release);
+        crate::irq::send_ipi(hart_id);
+    }
+}
+
+/// Take the pending kinds of the current cpu and handle them.
+fn handle_ipi(_irq: usize) {
+    let pending = PENDING[current_cpu_id()].swap(0, Ordering::Acquire);
+    for kind in IpiKind::ALL
+        .into_iter()
+        .filter(|kind| pending & kind.bit() != 0)
+    {
+        match kind {
+            IpiKind::TlbShootdown => crate::tlb::handle_shootdown(),
+            kind => unsafe { crate::__polyhal_ipi_handler(kind) },
+        }
+    }
+}
+
+/// Register the handler of [IPI_IRQ] on the boot cpu.
+pub(crate) fn init() {
+    if !register_irq(IPI_IRQ, handle_ipi) {
+        log::warn!("Failed to register the IPI handler");
+    }
+}
+
+/// Enable [IPI_IRQ] on the secondary cpu.
+pub(crate) fn init_cpu() {
+    enable_percpu_irq(IPI_IRQ);
+}
@@ -0,0 +1,348 @@
+use polyhal2_core::{
+    addr::{PhysAddr, VirtAddr},
+    lazy_init::LazyInit,
+};
+use polyhal2_device::acpi::{self, MadtEntry};
+use raw_cpuid::CpuId;
+use spin::Mutex;
+use x86_64::{instructions::port::Port, registers::model_specific::Msr};
+
+use super::IrqController;
+use crate::{smp::current_hart_id, trap::x86_64::IRQ_VECTOR_START};
+
+/// IA32_APIC_BASE MSR, contains the physical address of the local APIC.
+const IA32_APIC_BASE: u32 = 0x1b;
+/// IA32_APIC_BASE: the local APIC is enabled.
+const IA32_APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// Local APIC Task Priority Register.
+const LAPIC_TPR: usize = 0x80;
+/// Local APIC End Of Interrupt Register.
+const LAPIC_EOI: usize = 0xb0;
+/// Local APIC Spurious Interrupt Vector Register.
+const LAPIC_SVR: usize = 0xf0;
+/// Local APIC In-Service Register, 8 registers with 32 bits.
+const LAPIC_ISR: usize = 0x100;
+/// Local APIC Interrupt Command Register, low 32 bits.
+const LAPIC_ICR_LOW: usize = 0x300;
+/// Local APIC Interrupt Command Register, high 32 bits.
+const LAPIC_ICR_HIGH: usize = 0x310;
+/// ICR delivery status, the IPI is still pending.
+const LAPIC_ICR_PENDING: u32 = 1 << 12;
+/// Local APIC LVT Timer Register.
+const LAPIC_LVT_TIMER: usize = 0x320;
+/// LVT: the interrupt is masked.
+const LAPIC_LVT_MASKED: u32 = 1 << 16;
+/// LVT Timer: TSC-deadline mode.
+const LAPIC_LVT_TSC_DEADLINE: u32 = 0b10 << 17;
+/// Local APIC Timer Initial Count Register.
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+/// Local APIC Timer Current Count Register.
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+/// Local APIC Timer Divide Configuration Register.
+const LAPIC_TIMER_DIVIDE: usize = 0x3e0;
+/// Timer divide configuration: divide by 1.
+const LAPIC_TIMER_DIVIDE_1: u32 = 0b1011;
+/// LAPIC_SVR: the local APIC is software enabled.
+const LAPIC_SVR_ENABLE: u32 = 1 << 8;
+/// The vector of the spurious interrupt.
+const SPURIOUS_VECTOR: u32 = 0xff;
+
+/// I/O APIC Register Select.
+const IOAPIC_REGSEL: usize = 0x0;
+/// I/O APIC Register Window.
+const IOAPIC_WIN: usize = 0x10;
+/// I/O APIC Version Register, bits 16-23 are the maximum redirection entry.
+const IOAPIC_VER: u32 = 0x1;
+/// The first I/O APIC Redirection Table register.
+const IOAPIC_REDTBL: u32 = 0x10;
+/// Redirection entry: the interrupt is masked.
+const IOAPIC_MASKED: u32 = 1 << 16;
+/// Redirection entry: level triggered.
+const IOAPIC_LEVEL: u32 = 1 << 15;
+/// Redirection entry: active low.
+const IOAPIC_ACTIVE_LOW: u32 = 1 << 13;
+
+/// The maximum number of the I/O APICs.
+const MAX_IOAPICS: usize = 4;
+/// The maximum global system interrupt, the vectors from `0xf0` are
+/// reserved for the local interrupts.
+const MAX_GSI: usize = 0xf0 - IRQ_VECTOR_START;
+
+/// The local APIC timer interrupt, vector `0xf0`.
+pub const TIMER_IRQ: usize = MAX_GSI;
+/// The interrupt sent by other cpus, vector `0xf1`.
+pub const IPI_IRQ: usize = MAX_GSI + 1;
+/// The number of the irqs, the vector minus [IRQ_VECTOR_START].
+pub(super) const IRQ_NUM: usize = 0x100 - IRQ_VECTOR_START;
+
+/// I/O APIC, handles the global system interrupts from `gsi_base`.
+#[derive(Clone, Copy)]
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: usize,
+    count: usize,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            ((self.base.raw() + IOAPIC_REGSEL) as *mut u32).write_volatile(reg);
+            ((self.base.raw() + IOAPIC_WIN) as *mut u32).read_volatile()
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            ((self.base.raw() + IOAPIC_REGSEL) as *mut u32).write_volatile(reg);
+            ((self.base.raw() + IOAPIC_WIN) as *mut u32).write_volatile(value);
+        }
+    }
+
+    /// Get the low register of the redirection entry of `gsi`.
+    fn redirection(&self, gsi: usize) -> u32 {
+        IOAPIC_REDTBL + 2 * (gsi - self.gsi_base) as u32
+    }
+}
+
+/// Local APIC and I/O APICs.
+pub(super) struct Apic {
+    lapic: VirtAddr,
+    ioapics: [Option<IoApic>; MAX_IOAPICS],
+    /// Protect the register select of the I/O APICs.
+    lock: Mutex<()>,
+}
+
+static APIC: LazyInit<Apic> = LazyInit::new();
+
+/// Find the APICs in the ACPI MADT.
+pub(super) fn probe() -> Option<&'static dyn IrqController> {
+    let lapic = acpi::local_apic_addr().unwrap_or_else(|| {
+        let base = unsafe { Msr::new(IA32_APIC_BASE).read() } as usize & 0xffff_f000;
+        PhysAddr::new(base)
+    });
+    let mut apic = Apic {
+        lapic: lapic.mapped_vaddr(),
+        ioapics: [None; MAX_IOAPICS],
+        lock: Mutex::new(()),
+    };
+    let mut index = 0;
+    acpi::for_each_madt_entry(|entry| {
+        if let MadtEntry::IoApic {
+            address, gsi_base, ..
+        } = entry
+        {
+            if index < MAX_IOAPICS {
+                let mut ioapic = IoApic {
+                    base: address.mapped_vaddr(),
+                    gsi_base: gsi_base as _,
+                    count: 0,
+                };
+                ioapic.count = ((ioapic.read(IOAPIC_VER) >> 16) & 0xff) as usize + 1;
+                apic.ioapics[index] = Some(ioapic);
+                index += 1;
+            }
+        }
+    });
+    if index == 0 {
+        return None;
+    }
+    apic.init_ioapics();
+    APIC.init_by(apic);
+    Some(APIC.try_get()?)
+}
+
+/// Mask all interrupts of the legacy 8259 PICs.
+fn disable_pic() {
+    unsafe {
+        Port::<u8>::new(0x21).write(0xff);
+        Port::<u8>::new(0xa1).write(0xff);
+    }
+}
+
+impl Apic {
+    /// Get the pointer to the local APIC register at `offset`.
+    fn lapic_reg(&self, offset: usize) -> *mut u32 {
+        (self.lapic.raw() + offset) as *mut u32
+    }
+
+    /// Find the I/O APIC which handles `gsi`.
+    ///
+    /// The GSIs from [MAX_GSI] have no vector, they are never handled.
+    fn ioapic(&self, gsi: usize) -> Option<&IoApic> {
+        if gsi >= MAX_GSI {
+            return None;
+        }
+        self.ioapics
+            .iter()
+            .flatten()
+            .find(|ioapic| (ioapic.gsi_base..ioapic.gsi_base + ioapic.count).contains(&gsi))
+    }
+
+    /// Update the low register of the redirection entry of `gsi`.
+    fn update_redirection(&self, gsi: usize, f: impl FnOnce(u32) -> u32) {
+        let Some(ioapic) = self.ioapic(gsi) else {
+            log::warn!("No I/O APIC handles the interrupt {} with a vector", gsi);
+            return;
+        };
+        let reg = ioapic.redirection(gsi);
+        let _guard = self.lock.lock();
+        ioapic.write(reg, f(ioapic.read(reg)));
+    }
+
+    /// Mask all the interrupts, set the vector and route them to the boot cpu.
+    ///
+    /// The GSIs from [MAX_GSI] are left masked without a vector.
+    /// The polarity and trigger mode of the ISA interrupts are given by
+    /// the interrupt source overrides in the MADT.
+    fn init_ioapics(&self) {
+        disable_pic();
+        let dest = (current_hart_id() as u32) << 24;
+        for ioapic in self.ioapics.iter().flatten() {
+            for gsi in ioapic.gsi_base..ioapic.gsi_base + ioapic.count {
+                let reg = ioapic.redirection(gsi);
+                let vector = match gsi < MAX_GSI {
+                    true => (IRQ_VECTOR_START + gsi) as u32,
+                    false => 0,
+                };
+                ioapic.write(reg + 1, dest);
+                ioapic.write(reg, IOAPIC_MASKED | vector);
+            }
+        }
+        acpi::for_each_madt_entry(|entry| {
+            if let MadtEntry::InterruptOverride { gsi, flags, .. } = entry {
+                self.update_redirection(gsi as _, |value| {
+                    let mut value = value & !(IOAPIC_ACTIVE_LOW | IOAPIC_LEVEL);
+                    if flags & 0b11 == 0b11 {
+                        value |= IOAPIC_ACTIVE_LOW;
+                    }
+                    if (flags >> 2) & 0b11 == 0b11 {
+                        value |= IOAPIC_LEVEL;
+                    }
+                    value
+                });
+            }
+        });
+    }
+}
+
+impl IrqController for Apic {
+    fn name(&self) -> &'static str {
+        "APIC"
+    }
+
+    fn init_cpu(&self) {
+        unsafe {
+            let mut base = Msr::new(IA32_APIC_BASE);
+            base.write(base.read() | IA32_APIC_BASE_ENABLE);
+            self.lapic_reg(LAPIC_SVR)
+                .write_volatile(LAPIC_SVR_ENABLE | SPURIOUS_VECTOR);
+            self.lapic_reg(LAPIC_TPR).write_volatile(0);
+            // The timer is unmasked by enabling the per-cpu interrupt, it
+            // falls back to the one-shot mode without the TSC-deadline mode.
+            let vector = (IRQ_VECTOR_START + TIMER_IRQ) as u32;
+            let mode = match has_tsc_deadline() {
+                true => LAPIC_LVT_TSC_DEADLINE,
+                false => 0,
+            };
+            self.lapic_reg(LAPIC_TIMER_DIVIDE)
+                .write_volatile(LAPIC_TIMER_DIVIDE_1);
+            self.lapic_reg(LAPIC_LVT_TIMER)
+                .write_volatile(LAPIC_LVT_MASKED | mode | vector);
+        }
+    }
+
+    fn enable(&self, irq: usize) {
+        self.update_redirection(irq, |value| value & !IOAPIC_MASKED);
+    }
+
+    fn disable(&self, irq: usize) {
+        self.update_redirection(irq, |value| value | IOAPIC_MASKED);
+    }
+
+    /// The priority is decided by the vector on x86_64, it can't be changed.
+    fn set_priority(&self, _irq: usize, _priority: u8) {}
+
+    fn set_affinity(&self, irq: usize, hart_id: usize) {
+        let Some(ioapic) = self.ioapic(irq) else {
+            return;
+        };
+        let _guard = self.lock.lock();
+        ioapic.write(ioapic.redirection(irq) + 1, (hart_id as u32) << 24);
+    }
+
+    /// Find the highest vector in service, the vector is delivered to the cpu directly.
+    fn claim(&self) -> Option<usize> {
+        let vector = (0..8).rev().find_map(|index| {
+            let isr = unsafe { self.lapic_reg(LAPIC_ISR + index * 0x10).read_volatile() };
+            match isr {
+                0 => None,
+                isr => Some(index * 32 + 31 - isr.leading_zeros() as usize),
+            }
+        })?;
+        vector.checked_sub(IRQ_VECTOR_START)
+    }
+
+    fn complete(&self, _irq: usize) {
+        unsafe { self.lapic_reg(LAPIC_EOI).write_volatile(0) };
+    }
+}
+
+/// Mask or unmask the local APIC timer, the IPI can't be masked.
+pub(super) fn set_percpu_enable(irq: usize, enable: bool) {
+    let Some(apic) = APIC.try_get() else {
+        return;
+    };
+    if irq == TIMER_IRQ {
+        let lvt = apic.lapic_reg(LAPIC_LVT_TIMER);
+        let vector = (IRQ_VECTOR_START + TIMER_IRQ) as u32;
+        unsafe {
+            let value = (lvt.read_volatile() & !0xff) | vector;
+            match enable {
+                true => lvt.write_volatile(value & !LAPIC_LVT_MASKED),
+                false => lvt.write_volatile(value | LAPIC_LVT_MASKED),
+            }
+        }
+    }
+}
+
+/// Return true if the local APIC timer supports the TSC-deadline mode.
+pub(crate) fn has_tsc_deadline() -> bool {
+    CpuId::new()
+        .get_feature_info()
+        .is_some_and(|info| info.has_tsc_deadline())
+}
+
+/// Start the local APIC timer in one-shot mode with `count`, zero stops it.
+pub(crate) fn set_timer_count(count: u32) {
+    if let Some(apic) = APIC.try_get() {
+        unsafe { apic.lapic_reg(LAPIC_TIMER_INITIAL).write_volatile(count) };
+    }
+}
+
+/// Get the current count of the local APIC timer in one-shot mode.
+pub(crate) fn timer_count() -> u32 {
+    APIC.try_get()
+        .map(|apic| unsafe { apic.lapic_reg(LAPIC_TIMER_CURRENT).read_volatile() })
+        .unwrap_or(0)
+}
+
+/// Send the fixed interrupt [IPI_IRQ] to the cpu `hart_id` through the ICR.
+pub(crate) fn send_ipi(hart_id: usize) {
+    let Some(apic) = APIC.try_get() else {
+        return;
+    };
+    let vector = (IRQ_VECTOR_START + IPI_IRQ) as u32;
+    // The ICR is shared by the local interrupts and the secondary cpu bring-up.
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        apic.lapic_reg(LAPIC_ICR_HIGH)
+            .write_volatile((hart_id as u32) << 24);
+        apic.lapic_reg(LAPIC_ICR_LOW).write_volatile(vector);
+        while apic.lapic_reg(LAPIC_ICR_LOW).read_volatile() & LAPIC_ICR_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+/// Acknowledge the per-cpu interrupt, they are claimed from the local APIC.
+pub(super) fn ack_percpu(_irq: usize) {}
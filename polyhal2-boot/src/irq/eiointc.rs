@@ -0,0 +1,247 @@
+use loongArch64::{
+    iocsr::{iocsr_read_d, iocsr_read_w, iocsr_write_b, iocsr_write_d, iocsr_write_w},
+    register::ecfg::{self, LineBasedInterrupt},
+};
+use polyhal2_core::{
+    addr::{PhysAddr, VirtAddr},
+    lazy_init::LazyInit,
+};
+use spin::Mutex;
+
+use super::IrqController;
+use crate::smp::current_hart_id;
+
+/// IOCSR miscellaneous function register.
+const IOCSR_MISC_FUNC: usize = 0x420;
+/// IOCSR_MISC_FUNC: enable the extended I/O interrupts.
+const IOCSR_MISC_FUNC_EXT_IOI_EN: u64 = 1 << 48;
+
+/// EIOINTC interrupt pin map, 8 bytes for the groups of 32 interrupts.
+const EIOINTC_IPMAP: usize = 0x14c0;
+/// EIOINTC enable bits.
+const EIOINTC_ENABLE: usize = 0x1600;
+/// EIOINTC bounce bits.
+const EIOINTC_BOUNCE: usize = 0x1680;
+/// EIOINTC status bits of the current cpu, write 1 to clear.
+const EIOINTC_ISR: usize = 0x1800;
+/// EIOINTC route map, one byte for every interrupt.
+const EIOINTC_ROUTE: usize = 0x1c00;
+/// The number of the EIOINTC interrupts.
+const EIOINTC_IRQ_NUM: usize = 256;
+/// The number of the cores in an EIOINTC node.
+const EIOINTC_NODE_CORES: usize = 4;
+/// The number of the nodes in the route map.
+const EIOINTC_NODES: usize = 16;
+
+/// PCH-PIC interrupt mask register.
+const PCH_PIC_MASK: usize = 0x20;
+/// PCH-PIC HyperTransport message enable register.
+const PCH_PIC_HTMSI_EN: usize = 0x40;
+/// PCH-PIC edge trigger register.
+const PCH_PIC_EDGE: usize = 0x60;
+/// PCH-PIC clear register of the edge triggered interrupts.
+const PCH_PIC_CLEAR: usize = 0x80;
+/// PCH-PIC route entries, one byte for every interrupt.
+const PCH_PIC_ROUTE: usize = 0x100;
+/// PCH-PIC HyperTransport message vectors, one byte for every interrupt.
+const PCH_PIC_HTMSI_VEC: usize = 0x200;
+/// PCH-PIC polarity register.
+const PCH_PIC_POL: usize = 0x3e0;
+/// The number of the PCH-PIC interrupts.
+const PCH_PIC_IRQ_NUM: usize = 64;
+
+/// IOCSR IPI status register of the current cpu.
+const IOCSR_IPI_STATUS: usize = 0x1000;
+/// IOCSR IPI clear register of the current cpu.
+const IOCSR_IPI_CLEAR: usize = 0x100c;
+/// The IPI action bit used by [send_ipi], the bit 0 wakes up the cpu in the firmware.
+const IPI_ACTION: u32 = 1 << 1;
+
+/// The timer interrupt, it isn't from the EIOINTC.
+pub const TIMER_IRQ: usize = EIOINTC_IRQ_NUM;
+/// The inter-processor interrupt, it isn't from the EIOINTC.
+pub const IPI_IRQ: usize = EIOINTC_IRQ_NUM + 1;
+/// The number of the irqs, including the per-cpu interrupts.
+pub(super) const IRQ_NUM: usize = EIOINTC_IRQ_NUM + 2;
+
+/// The default address of PCH-PIC on the loongarch virt machine.
+const PCH_PIC_DEFAULT_BASE: usize = 0x1000_0000;
+
+/// EIOINTC with the cascaded PCH-PIC.
+///
+/// The PCH-PIC interrupt `n` is sent to the EIOINTC interrupt `n`, all
+/// the EIOINTC interrupts are delivered to the cpu through HWI0.
+pub(super) struct Eiointc {
+    pch_pic: VirtAddr,
+    /// Protect the read-modify-write of the registers.
+    lock: Mutex<()>,
+}
+
+static EIOINTC: LazyInit<Eiointc> = LazyInit::new();
+
+/// Find the PCH-PIC in the device tree, the EIOINTC is accessed through IOCSR.
+pub(super) fn probe() -> Option<&'static dyn IrqController> {
+    let mut base = None;
+    polyhal2_device::for_each_compatible_reg(&["loongson,pch-pic-1.0"], |paddr, _| {
+        base.get_or_insert(paddr);
+    });
+    let base = base.unwrap_or(PhysAddr::new(PCH_PIC_DEFAULT_BASE));
+    let eiointc = Eiointc {
+        pch_pic: base.mapped_vaddr(),
+        lock: Mutex::new(()),
+    };
+    eiointc.init();
+    EIOINTC.init_by(eiointc);
+    Some(EIOINTC.try_get()?)
+}
+
+impl Eiointc {
+    /// Get the pointer to the PCH-PIC register at `offset`.
+    fn pch_pic_reg<T>(&self, offset: usize) -> *mut T {
+        (self.pch_pic.raw() + offset) as *mut T
+    }
+
+    /// Update the 64-bit IOCSR register which contains the bit of `irq`.
+    fn update_iocsr_bit(&self, base: usize, irq: usize, set: bool) {
+        let reg = base + irq / 64 * 8;
+        let bit = 1 << (irq % 64);
+        let _guard = self.lock.lock();
+        let value = iocsr_read_d(reg);
+        iocsr_write_d(reg, if set { value | bit } else { value & !bit });
+    }
+
+    /// Mask or unmask the PCH-PIC interrupt `irq`.
+    fn mask_pch_pic(&self, irq: usize, mask: bool) {
+        if irq >= PCH_PIC_IRQ_NUM {
+            return;
+        }
+        let reg = self.pch_pic_reg::<u64>(PCH_PIC_MASK);
+        let _guard = self.lock.lock();
+        unsafe {
+            let value = reg.read_volatile();
+            let bit = 1 << irq;
+            reg.write_volatile(if mask { value | bit } else { value & !bit });
+        }
+    }
+
+    /// Route all interrupts to the boot cpu with everything masked.
+    fn init(&self) {
+        iocsr_write_d(
+            IOCSR_MISC_FUNC,
+            iocsr_read_d(IOCSR_MISC_FUNC) | IOCSR_MISC_FUNC_EXT_IOI_EN,
+        );
+        for index in 0..EIOINTC_IRQ_NUM / 64 {
+            iocsr_write_d(EIOINTC_ENABLE + index * 8, 0);
+            iocsr_write_d(EIOINTC_BOUNCE + index * 8, 0);
+        }
+        // All groups of interrupts are sent to HWI0.
+        iocsr_write_d(EIOINTC_IPMAP, 0x0101_0101_0101_0101);
+        let route = route(current_hart_id()).expect("The boot cpu is out of the EIOINTC route");
+        for irq in 0..EIOINTC_IRQ_NUM {
+            iocsr_write_b(EIOINTC_ROUTE + irq, route);
+        }
+        unsafe {
+            self.pch_pic_reg::<u64>(PCH_PIC_MASK)
+                .write_volatile(u64::MAX);
+            self.pch_pic_reg::<u64>(PCH_PIC_EDGE).write_volatile(0);
+            self.pch_pic_reg::<u64>(PCH_PIC_POL).write_volatile(0);
+            self.pch_pic_reg::<u64>(PCH_PIC_HTMSI_EN)
+                .write_volatile(u64::MAX);
+            for irq in 0..PCH_PIC_IRQ_NUM {
+                self.pch_pic_reg::<u8>(PCH_PIC_ROUTE + irq)
+                    .write_volatile(1);
+                self.pch_pic_reg::<u8>(PCH_PIC_HTMSI_VEC + irq)
+                    .write_volatile(irq as _);
+            }
+        }
+    }
+}
+
+/// Get the route map entry of the cpu `hart_id`, the core bitmap in the node
+/// is in the low 4 bits and the node number is in the high 4 bits.
+///
+/// Return None if the node is out of the route map.
+fn route(hart_id: usize) -> Option<u8> {
+    let node = hart_id / EIOINTC_NODE_CORES;
+    let core = hart_id % EIOINTC_NODE_CORES;
+    (node < EIOINTC_NODES).then_some(((node << 4) | (1 << core)) as u8)
+}
+
+impl IrqController for Eiointc {
+    fn name(&self) -> &'static str {
+        "EIOINTC+PCH-PIC"
+    }
+
+    fn init_cpu(&self) {
+        ecfg::set_lie(ecfg::read().lie() | LineBasedInterrupt::HWI0);
+    }
+
+    fn enable(&self, irq: usize) {
+        self.update_iocsr_bit(EIOINTC_ENABLE, irq, true);
+        self.mask_pch_pic(irq, false);
+    }
+
+    fn disable(&self, irq: usize) {
+        self.mask_pch_pic(irq, true);
+        self.update_iocsr_bit(EIOINTC_ENABLE, irq, false);
+    }
+
+    /// The priority is fixed by the interrupt number.
+    fn set_priority(&self, _irq: usize, _priority: u8) {}
+
+    fn set_affinity(&self, irq: usize, hart_id: usize) {
+        match route(hart_id) {
+            Some(route) => iocsr_write_b(EIOINTC_ROUTE + irq, route),
+            None => log::warn!("Can't route the irq {} to cpu {}", irq, hart_id),
+        }
+    }
+
+    fn claim(&self) -> Option<usize> {
+        (0..EIOINTC_IRQ_NUM / 64).find_map(|index| {
+            let reg = EIOINTC_ISR + index * 8;
+            match iocsr_read_d(reg) {
+                0 => None,
+                isr => {
+                    let irq = isr.trailing_zeros() as usize;
+                    iocsr_write_d(reg, 1 << irq);
+                    Some(index * 64 + irq)
+                }
+            }
+        })
+    }
+
+    fn complete(&self, irq: usize) {
+        // Clear the edge triggered interrupt in PCH-PIC.
+        if irq < PCH_PIC_IRQ_NUM {
+            unsafe {
+                self.pch_pic_reg::<u64>(PCH_PIC_CLEAR)
+                    .write_volatile(1 << irq)
+            };
+        }
+    }
+}
+
+/// Enable or disable the per-cpu interrupt in `ECFG`.
+pub(super) fn set_percpu_enable(irq: usize, enable: bool) {
+    let line = match irq {
+        TIMER_IRQ => LineBasedInterrupt::TIMER,
+        IPI_IRQ => LineBasedInterrupt::IPI,
+        _ => return,
+    };
+    let lie = ecfg::read().lie();
+    ecfg::set_lie(if enable { lie | line } else { lie - line });
+}
+
+/// Raise the IPI of the cpu `hart_id` through IOCSR.
+pub(crate) fn send_ipi(hart_id: usize) {
+    loongArch64::ipi::send_ipi_single(hart_id, IPI_ACTION);
+}
+
+/// Acknowledge the per-cpu interrupt.
+///
+/// The timer interrupt is cleared by [crate::timer] before the handlers.
+pub(super) fn ack_percpu(irq: usize) {
+    if irq == IPI_IRQ {
+        iocsr_write_w(IOCSR_IPI_CLEAR, iocsr_read_w(IOCSR_IPI_STATUS));
+    }
+}
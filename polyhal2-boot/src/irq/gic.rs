@@ -0,0 +1,374 @@
+use core::arch::asm;
+
+use polyhal2_core::{
+    addr::{PhysAddr, VirtAddr},
+    lazy_init::LazyInit,
+};
+use spin::Mutex;
+
+use super::IrqController;
+use crate::smp::current_hart_id;
+
+/// Distributor Control Register.
+const GICD_CTLR: usize = 0x0;
+/// Interrupt Controller Type Register.
+const GICD_TYPER: usize = 0x4;
+/// Interrupt Group Registers.
+const GICD_IGROUPR: usize = 0x80;
+/// Interrupt Set-Enable Registers.
+const GICD_ISENABLER: usize = 0x100;
+/// Interrupt Clear-Enable Registers.
+const GICD_ICENABLER: usize = 0x180;
+/// Interrupt Priority Registers, one byte for every interrupt.
+const GICD_IPRIORITYR: usize = 0x400;
+/// Interrupt Processor Targets Registers, one byte for every interrupt (GICv2).
+const GICD_ITARGETSR: usize = 0x800;
+/// Software Generated Interrupt Register (GICv2).
+const GICD_SGIR: usize = 0xf00;
+/// Interrupt Routing Registers, 8 bytes for every interrupt (GICv3).
+const GICD_IROUTER: usize = 0x6000;
+
+/// GICD_CTLR: enable group 0 and group 1 interrupts.
+const GICD_CTLR_ENABLE: u32 = 0b11;
+/// GICD_CTLR: affinity routing enable (GICv3).
+const GICD_CTLR_ARE: u32 = 1 << 4;
+
+/// CPU Interface Control Register (GICv2).
+const GICC_CTLR: usize = 0x0;
+/// Interrupt Priority Mask Register (GICv2).
+const GICC_PMR: usize = 0x4;
+/// Interrupt Acknowledge Register (GICv2).
+const GICC_IAR: usize = 0xc;
+/// End of Interrupt Register (GICv2).
+const GICC_EOIR: usize = 0x10;
+
+/// The size of the registers of every redistributor, RD_base and SGI_base.
+const GICR_STRIDE: usize = 0x20000;
+/// Redistributor Wake Register.
+const GICR_WAKER: usize = 0x14;
+/// Redistributor Type Register.
+const GICR_TYPER: usize = 0x8;
+/// The offset of the SGI and PPI registers in the redistributor.
+const GICR_SGI_BASE: usize = 0x10000;
+/// GICR_WAKER: the cpu is asleep.
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+/// GICR_WAKER: the interface to the cpu is quiescent.
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+/// GICR_TYPER: this is the last redistributor.
+const GICR_TYPER_LAST: u64 = 1 << 4;
+
+/// The first shared peripheral interrupt, the lower are private to the cpu.
+const SPI_START: usize = 32;
+/// The interrupt ids from this are special, 1023 means no pending interrupt.
+const SPECIAL_INTID_START: usize = 1020;
+/// The default priority of the interrupts.
+const DEFAULT_PRIORITY: u8 = 0xa0;
+
+/// The non-secure EL1 physical timer interrupt, PPI 14.
+pub const TIMER_IRQ: usize = 30;
+/// The software generated interrupt used as IPI.
+pub const IPI_IRQ: usize = 1;
+/// The number of the irqs, the interrupt ids from 1020 are special.
+pub(super) const IRQ_NUM: usize = SPECIAL_INTID_START;
+
+/// The version specific part of the GIC.
+enum GicVersion {
+    /// GICv2, the cpu interface is memory mapped.
+    V2 { gicc: VirtAddr },
+    /// GICv3, the cpu interface is accessed through system registers.
+    V3 { gicr: VirtAddr },
+}
+
+/// Generic Interrupt Controller.
+pub(super) struct Gic {
+    gicd: VirtAddr,
+    version: GicVersion,
+    /// Protect the read-modify-write of the distributor registers.
+    lock: Mutex<()>,
+}
+
+static GIC: LazyInit<Gic> = LazyInit::new();
+
+/// Get the first two register regions of the compatible device.
+fn find_regs(compatible: &[&str]) -> Option<(PhysAddr, PhysAddr)> {
+    let mut regs = [None; 2];
+    let mut index = 0;
+    polyhal2_device::for_each_compatible_reg(compatible, |paddr, _| {
+        if let Some(reg) = regs.get_mut(index) {
+            *reg = Some(paddr);
+        }
+        index += 1;
+    });
+    Some((regs[0]?, regs[1]?))
+}
+
+/// Find the GICv3 or GICv2 in the device tree.
+pub(super) fn probe() -> Option<&'static dyn IrqController> {
+    let gic = if let Some((gicd, gicr)) = find_regs(&["arm,gic-v3"]) {
+        Gic::new(gicd, GicVersion::V3 {
+            gicr: gicr.mapped_vaddr(),
+        })
+    } else {
+        let compatible = ["arm,cortex-a15-gic", "arm,gic-400", "arm,cortex-a9-gic"];
+        let (gicd, gicc) = find_regs(&compatible)?;
+        Gic::new(gicd, GicVersion::V2 {
+            gicc: gicc.mapped_vaddr(),
+        })
+    };
+    gic.init_distributor();
+    GIC.init_by(gic);
+    Some(GIC.try_get()?)
+}
+
+/// Convert the MPIDR affinity to the affinity value used in GICR_TYPER.
+const fn typer_affinity(mpidr: usize) -> u64 {
+    (((mpidr >> 8) & 0xff00_0000) | (mpidr & 0xff_ffff)) as u64
+}
+
+impl Gic {
+    fn new(gicd: PhysAddr, version: GicVersion) -> Self {
+        Self {
+            gicd: gicd.mapped_vaddr(),
+            version,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Get the pointer to the register at `offset` from `base`.
+    fn reg<T>(base: VirtAddr, offset: usize) -> *mut T {
+        (base.raw() + offset) as *mut T
+    }
+
+    /// Get the number of the interrupts supported by the distributor.
+    fn irq_num(&self) -> usize {
+        let typer = unsafe { Self::reg::<u32>(self.gicd, GICD_TYPER).read_volatile() };
+        ((typer as usize & 0x1f) + 1) * 32
+    }
+
+    /// Get the base of the registers of the interrupt `irq`.
+    ///
+    /// The private interrupts are in the redistributor of the current cpu on GICv3.
+    fn irq_base(&self, irq: usize) -> VirtAddr {
+        match self.version {
+            GicVersion::V3 { .. } if irq < SPI_START => {
+                VirtAddr::new(self.redistributor().raw() + GICR_SGI_BASE)
+            }
+            _ => self.gicd,
+        }
+    }
+
+    /// Find the redistributor of the current cpu.
+    fn redistributor(&self) -> VirtAddr {
+        let GicVersion::V3 { gicr } = self.version else {
+            panic!("There is no redistributor in GICv2");
+        };
+        let affinity = typer_affinity(current_hart_id());
+        let mut rd = gicr;
+        loop {
+            let typer = unsafe { Self::reg::<u64>(rd, GICR_TYPER).read_volatile() };
+            if typer >> 32 == affinity {
+                return rd;
+            }
+            if typer & GICR_TYPER_LAST != 0 {
+                panic!(
+                    "Can't find the redistributor of cpu {:#x}",
+                    current_hart_id()
+                );
+            }
+            rd = VirtAddr::new(rd.raw() + GICR_STRIDE);
+        }
+    }
+
+    /// Initialize the shared interrupts in the distributor.
+    fn init_distributor(&self) {
+        let gicd = self.gicd;
+        unsafe {
+            Self::reg::<u32>(gicd, GICD_CTLR).write_volatile(0);
+            for irq in (SPI_START..self.irq_num()).step_by(32) {
+                Self::reg::<u32>(gicd, GICD_ICENABLER + irq / 8).write_volatile(u32::MAX);
+                // GICv3 signals the non-secure group 1 through ICC_IGRPEN1_EL1,
+                // GICv2 only enables group 0 in GICD_CTLR and GICC_CTLR.
+                if let GicVersion::V3 { .. } = self.version {
+                    Self::reg::<u32>(gicd, GICD_IGROUPR + irq / 8).write_volatile(u32::MAX);
+                }
+            }
+            for irq in SPI_START..self.irq_num() {
+                Self::reg::<u8>(gicd, GICD_IPRIORITYR + irq).write_volatile(DEFAULT_PRIORITY);
+            }
+        }
+        // Route all shared interrupts to the boot cpu.
+        for irq in SPI_START..self.irq_num() {
+            self.set_affinity(irq, current_hart_id());
+        }
+        let ctlr = match self.version {
+            GicVersion::V2 { .. } => GICD_CTLR_ENABLE,
+            GicVersion::V3 { .. } => GICD_CTLR_ENABLE | GICD_CTLR_ARE,
+        };
+        unsafe { Self::reg::<u32>(gicd, GICD_CTLR).write_volatile(ctlr) };
+    }
+}
+
+impl IrqController for Gic {
+    fn name(&self) -> &'static str {
+        match self.version {
+            GicVersion::V2 { .. } => "GICv2",
+            GicVersion::V3 { .. } => "GICv3",
+        }
+    }
+
+    fn init_cpu(&self) {
+        match self.version {
+            GicVersion::V2 { gicc } => unsafe {
+                Self::reg::<u32>(gicc, GICC_PMR).write_volatile(0xff);
+                Self::reg::<u32>(gicc, GICC_CTLR).write_volatile(1);
+            },
+            GicVersion::V3 { .. } => unsafe {
+                let rd = self.redistributor();
+                let waker = Self::reg::<u32>(rd, GICR_WAKER);
+                waker.write_volatile(waker.read_volatile() & !GICR_WAKER_PROCESSOR_SLEEP);
+                while waker.read_volatile() & GICR_WAKER_CHILDREN_ASLEEP != 0 {
+                    core::hint::spin_loop();
+                }
+                let sgi = VirtAddr::new(rd.raw() + GICR_SGI_BASE);
+                Self::reg::<u32>(sgi, GICD_IGROUPR).write_volatile(u32::MAX);
+                // ICC_SRE_EL1.SRE, ICC_PMR_EL1 and ICC_IGRPEN1_EL1
+                asm!(
+                    "
+                    mrs     {tmp}, S3_0_C12_C12_5
+                    orr     {tmp}, {tmp}, 1
+                    msr     S3_0_C12_C12_5, {tmp}
+                    isb
+                    mov     {tmp}, 0xff
+                    msr     S3_0_C4_C6_0, {tmp}
+                    mov     {tmp}, 1
+                    msr     S3_0_C12_C12_7, {tmp}
+                    isb
+                ",
+                    tmp = out(reg) _,
+                );
+            },
+        }
+        // The private interrupts of the current cpu.
+        let base = self.irq_base(0);
+        for irq in 0..SPI_START {
+            unsafe {
+                Self::reg::<u8>(base, GICD_IPRIORITYR + irq).write_volatile(DEFAULT_PRIORITY)
+            };
+        }
+    }
+
+    fn enable(&self, irq: usize) {
+        let reg = Self::reg::<u32>(self.irq_base(irq), GICD_ISENABLER + irq / 32 * 4);
+        unsafe { reg.write_volatile(1 << (irq % 32)) };
+    }
+
+    fn disable(&self, irq: usize) {
+        let reg = Self::reg::<u32>(self.irq_base(irq), GICD_ICENABLER + irq / 32 * 4);
+        unsafe { reg.write_volatile(1 << (irq % 32)) };
+    }
+
+    fn set_priority(&self, irq: usize, priority: u8) {
+        // The lower value has the higher priority in GIC.
+        let reg = Self::reg::<u8>(self.irq_base(irq), GICD_IPRIORITYR + irq);
+        unsafe { reg.write_volatile(!priority) };
+    }
+
+    fn set_affinity(&self, irq: usize, hart_id: usize) {
+        // The private interrupts can't be routed.
+        if irq < SPI_START {
+            return;
+        }
+        let _guard = self.lock.lock();
+        match self.version {
+            // The cpu interface number is the Aff0 of MPIDR on a single cluster.
+            GicVersion::V2 { .. } => unsafe {
+                Self::reg::<u8>(self.gicd, GICD_ITARGETSR + irq)
+                    .write_volatile(1 << (hart_id & 0x7))
+            },
+            GicVersion::V3 { .. } => unsafe {
+                Self::reg::<u64>(self.gicd, GICD_IROUTER + irq * 8).write_volatile(hart_id as _)
+            },
+        }
+    }
+
+    /// Read the IAR, the source cpu of the SGI is kept in the id on GICv2.
+    fn claim(&self) -> Option<usize> {
+        let id = match self.version {
+            GicVersion::V2 { gicc } => unsafe {
+                Self::reg::<u32>(gicc, GICC_IAR).read_volatile() as usize & 0x1fff
+            },
+            GicVersion::V3 { .. } => {
+                let iar: usize;
+                // ICC_IAR1_EL1
+                unsafe { asm!("mrs {}, S3_0_C12_C12_0", out(reg) iar) };
+                iar & 0xff_ffff
+            }
+        };
+        match self.irq_of(id) {
+            SPECIAL_INTID_START.. => None,
+            _ => Some(id),
+        }
+    }
+
+    /// The INTID is the low 10 bits of the GICv2 IAR, the bits 10-12 are the
+    /// source cpu of the SGI.
+    fn irq_of(&self, id: usize) -> usize {
+        match self.version {
+            GicVersion::V2 { .. } => id & 0x3ff,
+            GicVersion::V3 { .. } => id,
+        }
+    }
+
+    /// Write the id to the EOIR, it must be the same value read from the IAR.
+    fn complete(&self, id: usize) {
+        match self.version {
+            GicVersion::V2 { gicc } => unsafe {
+                Self::reg::<u32>(gicc, GICC_EOIR).write_volatile(id as _)
+            },
+            // ICC_EOIR1_EL1
+            GicVersion::V3 { .. } => unsafe { asm!("msr S3_0_C12_C12_1, {}", in(reg) id) },
+        }
+    }
+}
+
+/// Enable or disable the per-cpu interrupt in the GIC of the current cpu.
+pub(super) fn set_percpu_enable(irq: usize, enable: bool) {
+    let Some(gic) = GIC.try_get() else {
+        return;
+    };
+    match enable {
+        true => gic.enable(irq),
+        false => gic.disable(irq),
+    }
+}
+
+/// Send the SGI [IPI_IRQ] to the cpu `hart_id`.
+pub(crate) fn send_ipi(hart_id: usize) {
+    let Some(gic) = GIC.try_get() else {
+        return;
+    };
+    // Make the memory writes visible before the interrupt.
+    aarch64_cpu::asm::barrier::dsb(aarch64_cpu::asm::barrier::ISHST);
+    match gic.version {
+        // The cpu interface number is the Aff0 of MPIDR on a single cluster.
+        GicVersion::V2 { .. } => unsafe {
+            let target = 1 << (16 + (hart_id & 0x7));
+            Gic::reg::<u32>(gic.gicd, GICD_SGIR).write_volatile(target | IPI_IRQ as u32)
+        },
+        // ICC_SGI1R_EL1, the target list is the bit of Aff0 in the cluster.
+        GicVersion::V3 { .. } => {
+            let aff3 = (hart_id >> 32) & 0xff;
+            let aff2 = (hart_id >> 16) & 0xff;
+            let aff1 = (hart_id >> 8) & 0xff;
+            let sgi1r = (aff3 << 48)
+                | (aff2 << 32)
+                | (IPI_IRQ << 24)
+                | (aff1 << 16)
+                | (1 << (hart_id & 0xf));
+            unsafe { asm!("msr S3_0_C12_C11_5, {}", "isb", in(reg) sgi1r) };
+        }
+    }
+}
+
+/// Acknowledge the per-cpu interrupt, they are claimed from the GIC.
+pub(super) fn ack_percpu(_irq: usize) {}
@@ -0,0 +1,251 @@
+//! Interrupt Controller
+//!
+//! The interrupt controller is probed on the boot cpu, the base address
+//! comes from the device tree or the ACPI MADT:
+//! - riscv64: PLIC
+//! - aarch64: GICv2 or GICv3
+//! - x86_64: Local APIC and I/O APIC
+//! - loongarch64: EIOINTC with PCH-PIC
+//!
+//! The irq number is the interrupt source number of the controller, which
+//! is the `interrupts` property in the device tree or the global system
+//! interrupt in ACPI. The interrupts are routed by the hart id given to the
+//! entry, see [crate::smp] for the logical cpu id.
+//!
+//! The handlers registered by [register_irq] are called in the trap path,
+//! the interrupt is claimed and completed around the handlers.
+//! [TIMER_IRQ] and [IPI_IRQ] are per-cpu interrupts, they are enabled
+//! on every cpu separately by [enable_percpu_irq].
+//!
+
+#[cfg(target_arch = "x86_64")]
+mod apic;
+#[cfg(target_arch = "loongarch64")]
+mod eiointc;
+#[cfg(target_arch = "aarch64")]
+mod gic;
+#[cfg(target_arch = "riscv64")]
+mod plic;
+
+#[cfg(target_arch = "x86_64")]
+use apic::{IRQ_NUM, ack_percpu, probe, set_percpu_enable};
+#[cfg(target_arch = "loongarch64")]
+use eiointc::{IRQ_NUM, ack_percpu, probe, set_percpu_enable};
+#[cfg(target_arch = "aarch64")]
+use gic::{IRQ_NUM, ack_percpu, probe, set_percpu_enable};
+#[cfg(target_arch = "riscv64")]
+use plic::{IRQ_NUM, ack_percpu, probe, set_percpu_enable};
+
+#[cfg(target_arch = "x86_64")]
+pub use apic::{IPI_IRQ, TIMER_IRQ};
+#[cfg(target_arch = "x86_64")]
+pub(crate) use apic::{has_tsc_deadline, send_ipi, set_timer_count, timer_count};
+#[cfg(target_arch = "loongarch64")]
+pub(crate) use eiointc::send_ipi;
+#[cfg(target_arch = "loongarch64")]
+pub use eiointc::{IPI_IRQ, TIMER_IRQ};
+#[cfg(target_arch = "aarch64")]
+pub(crate) use gic::send_ipi;
+#[cfg(target_arch = "aarch64")]
+pub use gic::{IPI_IRQ, TIMER_IRQ};
+#[cfg(target_arch = "riscv64")]
+pub(crate) use plic::send_ipi;
+#[cfg(target_arch = "riscv64")]
+pub use plic::{IPI_IRQ, TIMER_IRQ};
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use polyhal2_core::lazy_init::LazyInit;
+
+/// The irq of the interrupt which has gone before it is claimed.
+pub const SPURIOUS_IRQ: usize = usize::MAX;
+
+/// The interrupt controller.
+pub trait IrqController: Sync {
+    /// The name of the interrupt controller.
+    fn name(&self) -> &'static str;
+    /// Initialize the part of the controller for the current cpu.
+    fn init_cpu(&self);
+    /// Enable the interrupt `irq`.
+    fn enable(&self, irq: usize);
+    /// Disable the interrupt `irq`.
+    fn disable(&self, irq: usize);
+    /// Set the priority of the interrupt `irq`, the higher the more urgent.
+    ///
+    /// It is ignored if the controller doesn't support priority.
+    fn set_priority(&self, irq: usize, priority: u8);
+    /// Route the interrupt `irq` to the cpu `hart_id`.
+    ///
+    /// [crate::smp::hart_id_of] gets the hart id of the logical cpu id.
+    fn set_affinity(&self, irq: usize, hart_id: usize);
+    /// Claim the pending interrupt of the current cpu.
+    ///
+    /// Return the interrupt id read from the controller, or None if there
+    /// is no pending interrupt.
+    fn claim(&self) -> Option<usize>;
+    /// Get the irq of the interrupt id returned by [IrqController::claim].
+    ///
+    /// The id is the irq unless the controller encodes more in it.
+    fn irq_of(&self, id: usize) -> usize {
+        id
+    }
+    /// Complete the interrupt id returned by [IrqController::claim].
+    fn complete(&self, id: usize);
+}
+
+/// The interrupt controller probed on the boot cpu.
+static IRQ_CONTROLLER: LazyInit<&'static dyn IrqController> = LazyInit::new();
+
+/// Get the interrupt controller.
+///
+/// Return None if no interrupt controller is found.
+pub fn irq_controller() -> Option<&'static dyn IrqController> {
+    IRQ_CONTROLLER.try_get().copied()
+}
+
+/// Probe the interrupt controller and initialize it on the boot cpu.
+pub(crate) fn init() {
+    match probe() {
+        Some(controller) => {
+            log::debug!("interrupt controller: {}", controller.name());
+            IRQ_CONTROLLER.init_by(controller);
+            controller.init_cpu();
+        }
+        None => log::warn!("No interrupt controller found"),
+    }
+}
+
+/// Initialize the interrupt controller on the secondary cpu.
+pub(crate) fn init_cpu() {
+    if let Some(controller) = irq_controller() {
+        controller.init_cpu();
+    }
+}
+
+/// The interrupt handler, called with the irq number.
+pub type IrqHandler = fn(usize);
+
+/// The maximum number of the handlers sharing an irq.
+const MAX_SHARED_HANDLERS: usize = 4;
+
+/// The registered handlers of every irq, zero means the slot is free.
+///
+/// The handlers are stored as the function address, so that the trap path
+/// can read them without taking a lock.
+static IRQ_HANDLERS: [[AtomicUsize; MAX_SHARED_HANDLERS]; IRQ_NUM] =
+    [const { [const { AtomicUsize::new(0) }; MAX_SHARED_HANDLERS] }; IRQ_NUM];
+
+/// Return true if the irq is private to every cpu.
+const fn is_percpu(irq: usize) -> bool {
+    irq == TIMER_IRQ || irq == IPI_IRQ
+}
+
+/// Enable the per-cpu interrupt [TIMER_IRQ] or [IPI_IRQ] on the current cpu.
+///
+/// [register_irq] only enables it on the cpu which registers the handler.
+pub fn enable_percpu_irq(irq: usize) {
+    set_percpu_enable(irq, true);
+}
+
+/// Disable the per-cpu interrupt [TIMER_IRQ] or [IPI_IRQ] on the current cpu.
+pub fn disable_percpu_irq(irq: usize) {
+    set_percpu_enable(irq, false);
+}
+
+/// Enable or disable the irq, the per-cpu irq only on the current cpu.
+fn set_irq_enable(irq: usize, enable: bool) {
+    match (is_percpu(irq), irq_controller()) {
+        (true, _) => set_percpu_enable(irq, enable),
+        (false, Some(controller)) if enable => controller.enable(irq),
+        (false, Some(controller)) => controller.disable(irq),
+        (false, None) => {}
+    }
+}
+
+/// Register the handler of the irq and enable it.
+///
+/// An irq can be shared by at most 4 handlers, all of them are called
+/// when the interrupt happens.
+/// Return false if the irq is out of range or there is no free slot.
+pub fn register_irq(irq: usize, handler: IrqHandler) -> bool {
+    let Some(handlers) = IRQ_HANDLERS.get(irq) else {
+        return false;
+    };
+    let registered = handlers.iter().any(|slot| {
+        slot.compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    });
+    if registered {
+        set_irq_enable(irq, true);
+    }
+    registered
+}
+
+/// Unregister the handler of the irq.
+///
+/// The irq is disabled after the last handler is removed.
+/// Return false if the handler isn't registered.
+pub fn unregister_irq(irq: usize, handler: IrqHandler) -> bool {
+    let Some(handlers) = IRQ_HANDLERS.get(irq) else {
+        return false;
+    };
+    let removed = handlers.iter().any(|slot| {
+        slot.compare_exchange(handler as usize, 0, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    });
+    if removed
+        && handlers
+            .iter()
+            .all(|slot| slot.load(Ordering::Acquire) == 0)
+    {
+        set_irq_enable(irq, false);
+    }
+    removed
+}
+
+/// Call the handlers of the irq, return false if there is no handler.
+fn call_handlers(irq: usize) -> bool {
+    let Some(handlers) = IRQ_HANDLERS.get(irq) else {
+        return false;
+    };
+    let mut handled = false;
+    for slot in handlers {
+        let handler = slot.load(Ordering::Acquire);
+        if handler != 0 {
+            let handler: IrqHandler = unsafe { core::mem::transmute(handler) };
+            handler(irq);
+            handled = true;
+        }
+    }
+    handled
+}
+
+/// Handle the interrupt in the trap path.
+///
+/// `local` is the per-cpu interrupt decoded from the trap cause, the
+/// others are claimed from the interrupt controller.
+/// Return the last handled irq, or the first irq without handler in Err.
+/// It is [SPURIOUS_IRQ] if no interrupt is claimed.
+pub(crate) fn handle_irq(local: Option<usize>) -> Result<usize, usize> {
+    if let Some(irq) = local {
+        ack_percpu(irq);
+        return match call_handlers(irq) {
+            true => Ok(irq),
+            false => Err(irq),
+        };
+    }
+    let Some(controller) = irq_controller() else {
+        return Ok(SPURIOUS_IRQ);
+    };
+    let mut ret = Ok(SPURIOUS_IRQ);
+    while let Some(id) = controller.claim() {
+        let irq = controller.irq_of(id);
+        match (call_handlers(irq), ret) {
+            (true, Ok(_)) => ret = Ok(irq),
+            (false, Ok(_)) => ret = Err(irq),
+            _ => {}
+        }
+        controller.complete(id);
+    }
+    ret
+}
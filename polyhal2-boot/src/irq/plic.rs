@@ -0,0 +1,200 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use polyhal2_core::{
+    addr::{PhysAddr, VirtAddr},
+    lazy_init::LazyInit,
+};
+use riscv::register::{sie, sip};
+use spin::Mutex;
+
+use super::IrqController;
+use crate::smp::{boot_hart_id, current_hart_id};
+
+/// The maximum number of the interrupt sources.
+const PLIC_MAX_IRQ: usize = 1024;
+
+/// Interrupt source priority registers.
+const PLIC_PRIORITY: usize = 0x0;
+/// Interrupt enable bits of the first context.
+const PLIC_ENABLE: usize = 0x2000;
+/// The size of the enable bits of every context.
+const PLIC_ENABLE_STRIDE: usize = 0x80;
+/// Priority threshold register of the first context.
+const PLIC_THRESHOLD: usize = 0x20_0000;
+/// Claim and complete register of the first context.
+const PLIC_CLAIM: usize = 0x20_0004;
+/// The size of the registers of every context.
+const PLIC_CONTEXT_STRIDE: usize = 0x1000;
+
+/// The supervisor timer interrupt, it isn't from the PLIC.
+pub const TIMER_IRQ: usize = PLIC_MAX_IRQ;
+/// The supervisor software interrupt, it isn't from the PLIC.
+pub const IPI_IRQ: usize = PLIC_MAX_IRQ + 1;
+/// The number of the irqs, including the per-cpu interrupts.
+pub(super) const IRQ_NUM: usize = PLIC_MAX_IRQ + 2;
+
+/// No affinity has been set, the interrupt is routed to the boot hart.
+const NO_AFFINITY: usize = usize::MAX;
+
+/// Platform-Level Interrupt Controller.
+pub(super) struct Plic {
+    base: VirtAddr,
+    /// The hart which every interrupt is routed to.
+    affinity: [AtomicUsize; PLIC_MAX_IRQ],
+    /// Protect the read-modify-write of the enable bits.
+    lock: Mutex<()>,
+}
+
+static PLIC: LazyInit<Plic> = LazyInit::new();
+
+/// Find the PLIC in the device tree.
+pub(super) fn probe() -> Option<&'static dyn IrqController> {
+    let mut base = None;
+    polyhal2_device::for_each_compatible_reg(&["riscv,plic0", "sifive,plic-1.0.0"], |paddr, _| {
+        base.get_or_insert(paddr);
+    });
+    PLIC.init_by(Plic::new(base?));
+    Some(PLIC.try_get()?)
+}
+
+impl Plic {
+    fn new(base: PhysAddr) -> Self {
+        Self {
+            base: base.mapped_vaddr(),
+            affinity: [const { AtomicUsize::new(NO_AFFINITY) }; PLIC_MAX_IRQ],
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Get the pointer to the register at `offset`.
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base.raw() + offset) as *mut u32
+    }
+
+    /// Get the supervisor context of the hart, the machine context is `2 * hart_id`.
+    const fn context(hart_id: usize) -> usize {
+        2 * hart_id + 1
+    }
+
+    /// Get the hart which the interrupt is routed to.
+    fn target(&self, irq: usize) -> usize {
+        match self.affinity[irq].load(Ordering::Acquire) {
+            NO_AFFINITY => boot_hart_id(),
+            hart_id => hart_id,
+        }
+    }
+
+    /// Get the enable register and the bit of `irq` of the hart.
+    fn enable_bit(&self, hart_id: usize, irq: usize) -> (*mut u32, u32) {
+        let offset = PLIC_ENABLE + Self::context(hart_id) * PLIC_ENABLE_STRIDE + irq / 32 * 4;
+        (self.reg(offset), 1 << (irq % 32))
+    }
+
+    /// Set or clear the enable bit of `irq` of the hart, return the old state.
+    fn set_enable(&self, hart_id: usize, irq: usize, enable: bool) -> bool {
+        let (reg, bit) = self.enable_bit(hart_id, irq);
+        let _guard = self.lock.lock();
+        unsafe {
+            let old = reg.read_volatile();
+            match enable {
+                true => reg.write_volatile(old | bit),
+                false => reg.write_volatile(old & !bit),
+            }
+            old & bit != 0
+        }
+    }
+}
+
+impl IrqController for Plic {
+    fn name(&self) -> &'static str {
+        "PLIC"
+    }
+
+    fn init_cpu(&self) {
+        let context = Self::context(current_hart_id());
+        unsafe {
+            self.reg(PLIC_THRESHOLD + context * PLIC_CONTEXT_STRIDE)
+                .write_volatile(0)
+        };
+    }
+
+    fn enable(&self, irq: usize) {
+        // The interrupt with priority 0 is never delivered.
+        let priority = self.reg(PLIC_PRIORITY + irq * 4);
+        unsafe {
+            if priority.read_volatile() == 0 {
+                priority.write_volatile(1);
+            }
+        }
+        self.set_enable(self.target(irq), irq, true);
+    }
+
+    fn disable(&self, irq: usize) {
+        self.set_enable(self.target(irq), irq, false);
+    }
+
+    fn set_priority(&self, irq: usize, priority: u8) {
+        unsafe {
+            self.reg(PLIC_PRIORITY + irq * 4)
+                .write_volatile(priority as _)
+        };
+    }
+
+    fn set_affinity(&self, irq: usize, hart_id: usize) {
+        let old = self.target(irq);
+        self.affinity[irq].store(hart_id, Ordering::Release);
+        if old != hart_id && self.set_enable(old, irq, false) {
+            self.set_enable(hart_id, irq, true);
+        }
+    }
+
+    fn claim(&self) -> Option<usize> {
+        let context = Self::context(current_hart_id());
+        let irq = unsafe {
+            self.reg(PLIC_CLAIM + context * PLIC_CONTEXT_STRIDE)
+                .read_volatile()
+        };
+        match irq {
+            0 => None,
+            irq => Some(irq as _),
+        }
+    }
+
+    fn complete(&self, irq: usize) {
+        let context = Self::context(current_hart_id());
+        unsafe {
+            self.reg(PLIC_CLAIM + context * PLIC_CONTEXT_STRIDE)
+                .write_volatile(irq as _)
+        };
+    }
+}
+
+/// Enable or disable the per-cpu interrupt in `sie`.
+pub(super) fn set_percpu_enable(irq: usize, enable: bool) {
+    unsafe {
+        match (irq, enable) {
+            (TIMER_IRQ, true) => sie::set_stimer(),
+            (TIMER_IRQ, false) => sie::clear_stimer(),
+            (IPI_IRQ, true) => sie::set_ssoft(),
+            (IPI_IRQ, false) => sie::clear_ssoft(),
+            _ => {}
+        }
+    }
+}
+
+/// Raise the supervisor software interrupt of the hart through SBI.
+pub(crate) fn send_ipi(hart_id: usize) {
+    let ret = sbi_rt::send_ipi(1, hart_id);
+    if ret.is_err() {
+        log::warn!("Failed to send IPI to hart {}: {:?}", hart_id, ret);
+    }
+}
+
+/// Acknowledge the per-cpu interrupt.
+///
+/// The timer interrupt is cleared by [crate::timer] before the handlers.
+pub(super) fn ack_percpu(irq: usize) {
+    if irq == IPI_IRQ {
+        unsafe { sip::clear_ssoft() };
+    }
+}
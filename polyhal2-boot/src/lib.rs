@@ -9,28 +9,61 @@
 #![feature(used_with_arg)]
 #![feature(naked_functions)]
 
-use core::slice::Iter;
+use core::{
+    slice::Iter,
+    sync::atomic::{AtomicPtr, Ordering},
+};
 
 /// EntryPoint per architecture
 mod entry;
 
-/// Default Trap Handler
-mod trap;
+/// Trap frame and trap handler
+pub mod trap;
 
+/// Kernel thread context and context switch
+pub mod context;
+
+/// Interrupt controller drivers
+pub mod irq;
+
+/// Timer ticks and one-shot timer interrupt
+pub mod timer;
+
+/// Inter-processor interrupt
+pub mod ipi;
+
+/// Remote TLB invalidation
+mod tlb;
+
+/// Map the device registers to the kernel space
+pub mod mmio;
+
+/// Boot information given by the bootloader.
+pub mod boot_info;
 /// Input and output function
 pub mod console;
 /// The helpful macros.
 pub mod macros;
+/// Multi-core support, start the secondary CPUs.
+pub mod smp;
 
 mod panic;
 
+pub use boot_info::BootInfo;
+
 unsafe extern "Rust" {
     /// The real entry of the program
-    pub fn __polyhal_real_entry(hart_id: usize);
+    pub fn __polyhal_real_entry(hart_id: usize, boot_info: &'static boot_info::BootInfo);
+    /// The real entry of the secondary CPUs
+    pub fn __polyhal_secondary_entry(hart_id: usize);
     /// The start symbol of the init section
     pub fn __start_ph_init();
     /// The stop symbol of the init section
     pub fn __stop_ph_init();
+    /// Handle the trap
+    pub fn __polyhal_trap_handler(tf: &mut trap::TrapFrame, kind: trap::TrapKind);
+    /// Handle the inter-processor interrupt
+    pub fn __polyhal_ipi_handler(kind: ipi::IpiKind);
     /// Put a charactor to console
     #[linkage = "extern_weak"]
     pub fn __polyhal_putchar(c: u8);
@@ -42,12 +75,35 @@ unsafe extern "Rust" {
 /// and its priority.
 /// The lower the priority, the earlier it will be called
 pub struct PHInitWrap {
+    /// The name of the constructor
+    pub name: &'static str,
     /// The priority of the init function
-    pub _priority: usize,
+    pub priority: usize,
     /// The Initialize function
     pub func: fn(),
 }
 
+/// The stages of the constructors.
+///
+/// The stage is used as the priority of the constructor,
+/// the constructors in the earlier stage will be called first.
+/// The order of the constructors in the same stage is unspecified.
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PHInitStage {
+    /// Basic initialization, nothing can be output.
+    Early = 0x1000,
+    /// Initialize the console device.
+    Console = 0x2000,
+    /// Initialize the platform, the console is available.
+    Platform = 0x3000,
+    /// The other constructors.
+    Late = 0x4000,
+}
+
+/// The constructor which is running now.
+static RUNNING_PH_INIT: AtomicPtr<PHInitWrap> = AtomicPtr::new(core::ptr::null_mut());
+
 /// Polyhal Constructor placeholder
 #[used(linker)]
 #[unsafe(link_section = "ph_init")]
@@ -68,8 +124,53 @@ fn ph_init_iter<'a>() -> Iter<'a, PHInitWrap> {
     unsafe { core::slice::from_raw_parts_mut(__start_ph_init as *mut PHInitWrap, len).iter() }
 }
 
+/// Call all constructors in the polyhal init section.
+///
+/// The constructors are called in the order of the priority,
+/// the constructors with the same priority are called in the link order.
+fn ph_init_call() {
+    let mut last = None;
+    while let Some((i, phw)) = ph_init_iter()
+        .enumerate()
+        .filter(|(i, phw)| last.is_none_or(|last| (phw.priority, *i) > last))
+        .min_by_key(|(i, phw)| (phw.priority, *i))
+    {
+        last = Some((phw.priority, i));
+        RUNNING_PH_INIT.store(phw as *const _ as *mut _, Ordering::SeqCst);
+        (phw.func)();
+    }
+    RUNNING_PH_INIT.store(core::ptr::null_mut(), Ordering::SeqCst);
+}
+
+/// Get the constructor which is running now.
+fn running_ph_init() -> Option<&'static PHInitWrap> {
+    unsafe { RUNNING_PH_INIT.load(Ordering::SeqCst).as_ref() }
+}
+
 /// Weak function
 /// Put a character to console
 #[linkage = "weak"]
 #[unsafe(export_name = "__polyhal_putchar")]
 fn putc(_c: u8) {}
+
+/// Weak function
+/// The entry of the secondary CPUs if the kernel doesn't provide it.
+#[linkage = "weak"]
+#[unsafe(export_name = "__polyhal_secondary_entry")]
+fn secondary_entry(_hart_id: usize) {}
+
+/// Weak function
+/// Handle the trap if the kernel doesn't provide a handler.
+#[linkage = "weak"]
+#[unsafe(export_name = "__polyhal_trap_handler")]
+fn trap_handler(tf: &mut trap::TrapFrame, kind: trap::TrapKind) {
+    panic!("Unhandled Trap {:x?} @ {:#x?}", kind, tf)
+}
+
+/// Weak function
+/// Ignore the inter-processor interrupt if the kernel doesn't provide a handler.
+#[linkage = "weak"]
+#[unsafe(export_name = "__polyhal_ipi_handler")]
+fn ipi_handler(kind: ipi::IpiKind) {
+    log::trace!("Unhandled IPI {:?}", kind);
+}
@@ -1,33 +1,54 @@
 /// Definition the entry point of the program
 ///
+/// The boot stack is reserved for every cpu, each cpu uses `$boot_stack` bytes.
+/// The optional secondary entry will be called on every secondary cpu started
+/// by [crate::smp::start_secondary_cpus].
+///
 /// ## Demo
 ///
 /// ```rust
-/// fn main(hart) {
+/// fn main(hart: usize, boot_info: &BootInfo) {
 ///     println!("This is main function");
 /// }
 ///
+/// fn secondary_main(hart) {
+///     println!("This is secondary main function");
+/// }
+///
 /// entry_point!(main, 0x1000)
+/// // Or with secondary entry
+/// entry_point!(main, secondary_main, 0x1000)
 /// ```
 #[macro_export]
 macro_rules! entry_point {
+    ($entry:ident, $secondary:ident, $boot_stack:literal) => {
+        #[unsafe(no_mangle)]
+        unsafe fn __polyhal_secondary_entry(hart_id: usize) {
+            $secondary(hart_id as _);
+        }
+        $crate::entry_point!($entry, $boot_stack);
+    };
     ($entry:ident, $boot_stack:literal) => {
         #[unsafe(no_mangle)]
-        unsafe fn __polyhal_real_entry(hart_id: usize) {
-            $entry(hart_id as _);
+        unsafe fn __polyhal_real_entry(
+            hart_id: usize,
+            boot_info: &'static $crate::boot_info::BootInfo,
+        ) {
+            $entry(hart_id as _, boot_info);
         }
-        /// Definition a boot stack through `global_asm!`
-        core::arch::global_asm!(concat!(
+        /// Definition boot stacks for all cpus through `global_asm!`
+        core::arch::global_asm!(
             "
             .section .bss
+            .global bstack_bottom
             .global bstack_top
             bstack_bottom:
-            .fill ",
-            $boot_stack,
-            "
+            .fill {size} * {cpus}
             bstack_top:
-        "
-        ));
+            ",
+            size = const $boot_stack,
+            cpus = const $crate::smp::MAX_CPUS,
+        );
     };
 }
 
@@ -58,27 +79,96 @@ macro_rules! uart_interface {
     };
 }
 
+/// Specific the trap handler.
+///
+/// The handler is called with the saved [crate::trap::TrapFrame] and the
+/// decoded [crate::trap::TrapKind] when a trap happens. The execution is
+/// resumed with the modified trap frame after the handler returns.
+///
+/// ## Demo
+///
+/// ```rust
+/// fn handle_trap(tf: &mut TrapFrame, kind: TrapKind) {
+///     todo!("handle trap")
+/// }
+///
+/// trap_handler!(handle_trap);
+/// ```
+#[macro_export]
+macro_rules! trap_handler {
+    ($handler:expr) => {
+        #[unsafe(no_mangle)]
+        fn __polyhal_trap_handler(tf: &mut $crate::trap::TrapFrame, kind: $crate::trap::TrapKind) {
+            $handler(tf, kind);
+        }
+    };
+}
+
+/// Specific the inter-processor interrupt handler.
+///
+/// The handler is called on the target cpu with every [crate::ipi::IpiKind]
+/// sent by [crate::ipi::send_ipi].
+///
+/// ## Demo
+///
+/// ```rust
+/// fn handle_ipi(kind: IpiKind) {
+///     todo!("handle ipi")
+/// }
+///
+/// ipi_handler!(handle_ipi);
+/// ```
+#[macro_export]
+macro_rules! ipi_handler {
+    ($handler:expr) => {
+        #[unsafe(no_mangle)]
+        fn __polyhal_ipi_handler(kind: $crate::ipi::IpiKind) {
+            $handler(kind);
+        }
+    };
+}
+
 /// Definiation a constructer
 ///
 /// This constructor will be called by polyhal when booting.
 /// Please add `#![feature(used_with_arg)]` at the top of your `lib.rs` file.
 ///
+/// The constructors are called in the order of the priority, the lower
+/// priority will be called earlier. The priority could be a [crate::PHInitStage]
+/// given by `stage = Name`, or an expression of the number.
+/// The default stage is [crate::PHInitStage::Late].
+///
 /// ## Demo
 ///
 /// ```rust
 /// ph_ctor!(ctor_name, || {
 ///     // Ctor block
 /// });
+/// // Specify the init stage.
+/// ph_ctor!(ctor_name, stage = Console, || {
+///     // Ctor block
+/// });
+/// // Specify the priority.
+/// ph_ctor!(ctor_name, PHInitStage::Console as usize + 1, || {
+///     // Ctor block
+/// });
 /// ```
 #[macro_export]
 macro_rules! ph_ctor {
-    ($name:ident, $f:expr) => {
+    ($name:ident, stage = $stage:ident, $f:expr) => {
+        $crate::ph_ctor!($name, $crate::PHInitStage::$stage, $f);
+    };
+    ($name:ident, $priority:expr, $f:expr) => {
         #[used(linker)]
         #[unsafe(no_mangle)]
         #[unsafe(link_section = "ph_init")]
         static $name: $crate::PHInitWrap = $crate::PHInitWrap {
-            _priority: 0,
+            name: stringify!($name),
+            priority: $priority as usize,
             func: $f,
         };
     };
+    ($name:ident, $f:expr) => {
+        $crate::ph_ctor!($name, stage = Late, $f);
+    };
 }
@@ -0,0 +1,238 @@
+//! Map the device registers into a dedicated window of the kernel space.
+//!
+//! [ioremap] allocates the virtual pages from the window and maps them as
+//! device memory, the [MmioRegion] unmaps them when it is dropped. The root
+//! entry of the window is linked at boot, so the user spaces created by
+//! [VSpace::new_user] share it. The page tables under it are allocated by
+//! the page allocator given to [polyhal2_pagetable::set_page_allocator].
+//!
+//! [VSpace::new_user]: polyhal2_pagetable::VSpace::new_user
+//!
+//! loongarch64 uses the uncached direct mapping windows instead.
+
+use polyhal2_core::addr::{PhysAddr, VirtAddr};
+use polyhal2_pagetable::MappingFlags;
+#[cfg(not(target_arch = "loongarch64"))]
+use polyhal2_pagetable::{MappingSize, VSpace, VSpaceAO};
+#[cfg(not(target_arch = "loongarch64"))]
+use spin::Mutex;
+
+/// The size of the page mapped in the window.
+#[cfg(not(target_arch = "loongarch64"))]
+const PAGE_SIZE: usize = VSpace::PAGE_SIZE;
+/// The shift of the page size.
+#[cfg(not(target_arch = "loongarch64"))]
+const PAGE_SHIFT: usize = PAGE_SIZE.trailing_zeros() as usize;
+
+/// The shift of the address covered by a root entry, every level below
+/// the root indexes `PAGE_SHIFT - 3` bits.
+#[cfg(not(target_arch = "loongarch64"))]
+const ROOT_SHIFT: usize = PAGE_SHIFT + (PAGE_SHIFT - 3) * (VSpace::PAGE_LEVEL - 1);
+/// The root entry of the window counted from the end of the address space,
+/// x86_64 uses the last one for the kernel image.
+#[cfg(not(target_arch = "loongarch64"))]
+const WINDOW_ROOT_FROM_END: usize = match cfg!(target_arch = "x86_64") {
+    true => 2,
+    false => 1,
+};
+/// The start of the window, the start of its root entry in the higher half.
+#[cfg(not(target_arch = "loongarch64"))]
+const WINDOW_BASE: usize = 0usize.wrapping_sub(WINDOW_ROOT_FROM_END << ROOT_SHIFT);
+/// The strongly-ordered uncached direct mapping window `DMW0` set at boot.
+#[cfg(target_arch = "loongarch64")]
+const UNCACHED_WINDOW: usize = 0x8000_0000_0000_0000;
+/// The weakly-ordered uncached direct mapping window `DMW2` set at boot.
+#[cfg(target_arch = "loongarch64")]
+const WRITE_COMBINE_WINDOW: usize = 0xa000_0000_0000_0000;
+
+/// The size of the window.
+#[cfg(not(target_arch = "loongarch64"))]
+const WINDOW_SIZE: usize = 0x4000_0000;
+/// The number of the pages in the window.
+#[cfg(not(target_arch = "loongarch64"))]
+const WINDOW_PAGES: usize = WINDOW_SIZE / PAGE_SIZE;
+
+/// The used pages of the window, a bit for every page.
+#[cfg(not(target_arch = "loongarch64"))]
+static USED: Mutex<[u64; WINDOW_PAGES / 64]> = Mutex::new([0; WINDOW_PAGES / 64]);
+
+/// The device registers mapped by [ioremap], they are unmapped when dropped.
+///
+/// ## Demo
+///
+/// ```rust,ignore
+/// let uart = ioremap(PhysAddr::new(0x1000_0000), 0x100).expect("Failed to map UART");
+/// uart.write::<u8>(0, b'A');
+/// let lsr = uart.read::<u8>(5);
+/// ```
+pub struct MmioRegion {
+    vaddr: VirtAddr,
+    size: usize,
+}
+
+impl MmioRegion {
+    /// Get the virtual address of the registers.
+    pub const fn vaddr(&self) -> VirtAddr {
+        self.vaddr
+    }
+
+    /// Get the size of the registers.
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Get the pointer to the register at `offset`.
+    ///
+    /// Panic if the register is out of the region or isn't aligned.
+    fn reg<T>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset + size_of::<T>() <= self.size,
+            "The register at {:#x} is out of the region",
+            offset
+        );
+        assert!(offset % align_of::<T>() == 0, "The register isn't aligned");
+        (self.vaddr.raw() + offset) as *mut T
+    }
+
+    /// Read the register at `offset` with a volatile load.
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { self.reg::<T>(offset).read_volatile() }
+    }
+
+    /// Write the register at `offset` with a volatile store.
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { self.reg::<T>(offset).write_volatile(value) }
+    }
+}
+
+/// Map the device registers from `paddr` with `size` bytes as device memory.
+///
+/// Return None if there is no free space in the window or the page tables
+/// can't be allocated.
+pub fn ioremap(paddr: PhysAddr, size: usize) -> Option<MmioRegion> {
+    map(paddr, size, MappingFlags::Device)
+}
+
+/// Map the memory from `paddr` with `size` bytes as write-combining memory,
+/// such as the framebuffer.
+pub fn ioremap_wc(paddr: PhysAddr, size: usize) -> Option<MmioRegion> {
+    map(paddr, size, MappingFlags::Device | MappingFlags::Cache)
+}
+
+/// Map the pages containing the registers to the window.
+#[cfg(not(target_arch = "loongarch64"))]
+fn map(paddr: PhysAddr, size: usize, kind: MappingFlags) -> Option<MmioRegion> {
+    if size == 0 {
+        return None;
+    }
+    let offset = paddr.raw() % PAGE_SIZE;
+    let pages = (offset + size).div_ceil(PAGE_SIZE);
+    // Align the large region to use the huge pages.
+    let align = match pages * PAGE_SIZE >= MappingSize::Page2MB.size() {
+        true => MappingSize::Page2MB.size() / PAGE_SIZE,
+        false => 1,
+    };
+    let vaddr = alloc_pages(pages, align)?;
+    let vrange = vaddr..VirtAddr::new(vaddr.raw() + pages * PAGE_SIZE);
+    let flags = MappingFlags::R | MappingFlags::W | MappingFlags::G | kind;
+    if let Err(err) = kernel_vspace().map_region(vrange, paddr.floor(PAGE_SIZE), flags) {
+        log::warn!(
+            "Failed to map the registers at {:#x}: {:?}",
+            paddr.raw(),
+            err
+        );
+        free_pages(vaddr, pages);
+        return None;
+    }
+    Some(MmioRegion {
+        vaddr: VirtAddr::new(vaddr.raw() + offset),
+        size,
+    })
+}
+
+/// The registers are accessed through the uncached direct mapping windows.
+#[cfg(target_arch = "loongarch64")]
+fn map(paddr: PhysAddr, size: usize, kind: MappingFlags) -> Option<MmioRegion> {
+    if size == 0 {
+        return None;
+    }
+    let window = match kind.contains(MappingFlags::Cache) {
+        true => WRITE_COMBINE_WINDOW,
+        false => UNCACHED_WINDOW,
+    };
+    Some(MmioRegion {
+        vaddr: VirtAddr::new(window | paddr.raw()),
+        size,
+    })
+}
+
+#[cfg(not(target_arch = "loongarch64"))]
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        let start = self.vaddr.floor(PAGE_SIZE);
+        let pages = (self.vaddr.raw() - start.raw() + self.size).div_ceil(PAGE_SIZE);
+        let end = VirtAddr::new(start.raw() + pages * PAGE_SIZE);
+        if let Err(err) = kernel_vspace().unmap_region(start..end) {
+            log::warn!(
+                "Failed to unmap the registers at {:#x}: {:?}",
+                start.raw(),
+                err
+            );
+        }
+        free_pages(start, pages);
+    }
+}
+
+/// Get the page table of the kernel space.
+#[cfg(not(target_arch = "loongarch64"))]
+fn kernel_vspace() -> VSpace {
+    #[cfg(target_arch = "aarch64")]
+    {
+        use aarch64_cpu::registers::TTBR1_EL1;
+        VSpace::from_paddr(PhysAddr::new(TTBR1_EL1.get_baddr() as _))
+    }
+    #[cfg(not(target_arch = "aarch64"))]
+    VSpace::current()
+}
+
+/// Allocate `count` continuous pages aligned to `align` pages in the window.
+#[cfg(not(target_arch = "loongarch64"))]
+fn alloc_pages(count: usize, align: usize) -> Option<VirtAddr> {
+    let mut used = USED.lock();
+    let is_used = |used: &[u64], page: usize| used[page / 64] & (1 << (page % 64)) != 0;
+    let mut start = 0;
+    while start + count <= WINDOW_PAGES {
+        match (start..start + count).rfind(|page| is_used(&*used, *page)) {
+            Some(page) => start = (page + 1).next_multiple_of(align),
+            None => {
+                (start..start + count).for_each(|page| used[page / 64] |= 1 << (page % 64));
+                return Some(VirtAddr::new(WINDOW_BASE + start * PAGE_SIZE));
+            }
+        }
+    }
+    None
+}
+
+/// Free the `count` pages from `vaddr` in the window.
+#[cfg(not(target_arch = "loongarch64"))]
+fn free_pages(vaddr: VirtAddr, count: usize) {
+    let first = (vaddr.raw() - WINDOW_BASE) / PAGE_SIZE;
+    let mut used = USED.lock();
+    (first..first + count).for_each(|page| used[page / 64] &= !(1 << (page % 64)));
+}
+
+/// Link the table of the window to the kernel space.
+///
+/// It should be called before any user space is created.
+pub(crate) fn init() {
+    #[cfg(not(target_arch = "loongarch64"))]
+    {
+        // The table of the window is reserved in the boot pages.
+        let table = crate::entry::BootPageAlloc
+            .alloc_page()
+            .expect("No boot page for the window table");
+        kernel_vspace()
+            .link_root_table(VirtAddr::new(WINDOW_BASE), table)
+            .expect("The ioremap window is used");
+    }
+}
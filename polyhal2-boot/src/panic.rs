@@ -2,6 +2,10 @@ use core::panic::PanicInfo;
 
 #[panic_handler]
 fn panic_handler(message: &PanicInfo) -> ! {
+    // The logger may not be initialized, output the constructor directly.
+    if let Some(phw) = crate::running_ph_init() {
+        crate::display_info!("Panic in Constructor", "{}", phw.name);
+    }
     log::error!("Panic Message: {}", message.message());
     crate::entry::hlt_forever()
 }
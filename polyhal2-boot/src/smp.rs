@@ -0,0 +1,198 @@
+//! Secondary CPU bring-up.
+//!
+//! Only the boot cpu enters the kernel entry after booting.
+//! The kernel calls [start_secondary_cpus] to start the others, every
+//! secondary cpu gets its own boot stack and calls the secondary entry
+//! given in [crate::entry_point].
+//!
+//! The method of starting cpu is architecture specific:
+//! - riscv64: SBI HSM extension
+//! - aarch64: PSCI CPU_ON
+//! - x86_64: INIT-SIPI-SIPI through local APIC
+//! - loongarch64: IOCSR mailbox and IPI
+//!
+//! The hart ids given by the firmware may be sparse, such as the MPIDR
+//! affinity on aarch64 and the APIC id on x86_64. Every cpu also has a
+//! logical cpu id, the boot cpu is 0 and the others are numbered in the
+//! order they are started. [CpuMask] and the per-cpu states use it.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use polyhal2_core::addr::VirtAddr;
+pub use polyhal2_core::consts::MAX_CPUS;
+
+const _: () = assert!(MAX_CPUS <= CpuMask::BITS, "Too many cpus for CpuMask");
+
+/// The hart id of every cpu, indexed by the cpu id.
+static HART_IDS: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(usize::MAX) }; MAX_CPUS];
+/// The number of cpus which have entered the kernel.
+static CPU_ONLINE: AtomicUsize = AtomicUsize::new(1);
+/// The cpu ids of the cpus which have entered the kernel.
+static ONLINE_MASK: AtomicU64 = AtomicU64::new(0);
+
+/// Wait this many loops for a secondary cpu before giving up.
+const START_TIMEOUT: usize = 0x1000_0000;
+
+/// Record the hart id of the boot cpu, it is cpu 0.
+pub(crate) fn set_boot_hart_id(hart_id: usize) {
+    HART_IDS[0].store(hart_id, Ordering::Release);
+    ONLINE_MASK.fetch_or(CpuMask::single(0).bits(), Ordering::AcqRel);
+}
+
+/// Mark the current cpu online.
+pub(crate) fn mark_cpu_online(hart_id: usize) {
+    let cpu_id = cpu_id_of(hart_id).expect("The cpu isn't started by start_secondary_cpus");
+    ONLINE_MASK.fetch_or(CpuMask::single(cpu_id).bits(), Ordering::AcqRel);
+    CPU_ONLINE.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Get the hart id of the boot cpu.
+pub fn boot_hart_id() -> usize {
+    HART_IDS[0].load(Ordering::Acquire)
+}
+
+/// Get the hart id of the current cpu.
+///
+/// It is the same as the id passed to the entry.
+pub fn current_hart_id() -> usize {
+    crate::entry::current_hart_id()
+}
+
+/// Get the cpu id of the current cpu.
+///
+/// The boot cpu is cpu 0 before its hart id is recorded.
+pub fn current_cpu_id() -> usize {
+    cpu_id_of(current_hart_id()).unwrap_or(0)
+}
+
+/// Get the hart id of the cpu `cpu_id`.
+///
+/// Return None if no cpu is started with the cpu id.
+pub fn hart_id_of(cpu_id: usize) -> Option<usize> {
+    let hart_id = HART_IDS.get(cpu_id)?.load(Ordering::Acquire);
+    (hart_id != usize::MAX).then_some(hart_id)
+}
+
+/// Get the cpu id of the cpu `hart_id`.
+fn cpu_id_of(hart_id: usize) -> Option<usize> {
+    HART_IDS
+        .iter()
+        .position(|id| id.load(Ordering::Acquire) == hart_id)
+}
+
+/// Get the number of the cpus which have entered the kernel.
+pub fn cpu_online() -> usize {
+    CPU_ONLINE.load(Ordering::Acquire)
+}
+
+/// A set of cpus, every bit is a cpu id.
+///
+/// The cpu id must be less than [MAX_CPUS].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CpuMask(u64);
+
+impl CpuMask {
+    /// The number of the bits in the mask.
+    const BITS: usize = u64::BITS as usize;
+
+    /// Create an empty mask.
+    pub const fn new() -> Self {
+        Self(0)
+    }
+
+    /// Create a mask from the bits, the bit `n` is the cpu id `n`.
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    /// Get the bits of the mask.
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Create a mask which only contains the cpu `cpu_id`.
+    pub const fn single(cpu_id: usize) -> Self {
+        assert!(cpu_id < MAX_CPUS, "The cpu id is out of CpuMask");
+        Self(1 << cpu_id)
+    }
+
+    /// Create a mask of all the cpus which have entered the kernel.
+    pub fn online() -> Self {
+        Self(ONLINE_MASK.load(Ordering::Acquire))
+    }
+
+    /// Create a mask of the online cpus except the current cpu.
+    pub fn others() -> Self {
+        let mut mask = Self::online();
+        mask.remove(current_cpu_id());
+        mask
+    }
+
+    /// Add the cpu `cpu_id` to the mask.
+    pub const fn insert(&mut self, cpu_id: usize) {
+        self.0 |= Self::single(cpu_id).0;
+    }
+
+    /// Remove the cpu `cpu_id` from the mask.
+    pub const fn remove(&mut self, cpu_id: usize) {
+        self.0 &= !Self::single(cpu_id).0;
+    }
+
+    /// Return true if the cpu `cpu_id` is in the mask.
+    pub const fn contains(&self, cpu_id: usize) -> bool {
+        cpu_id < Self::BITS && self.0 & (1 << cpu_id) != 0
+    }
+
+    /// Return true if there is no cpu in the mask.
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Iterate the cpu ids in the mask.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..MAX_CPUS).filter(|cpu_id| self.contains(*cpu_id))
+    }
+}
+
+/// Get the boot stack top of the cpu with the given cpu id.
+///
+/// The boot cpu uses index 0, the stacks are placed from `bstack_top` downward.
+fn boot_stack_top(index: usize) -> VirtAddr {
+    unsafe extern "C" {
+        fn bstack_bottom();
+        fn bstack_top();
+    }
+    let stack_size = (bstack_top as usize - bstack_bottom as usize) / MAX_CPUS;
+    VirtAddr::new(bstack_top as usize - index * stack_size)
+}
+
+/// Start all secondary cpus described by the firmware.
+///
+/// The cpus are started one by one, wait for every cpu to enter the kernel
+/// before starting the next one. At most [MAX_CPUS] cpus will be online.
+/// Return the number of the cpus which are online.
+pub fn start_secondary_cpus() -> usize {
+    let mut index = cpu_online();
+    crate::entry::for_each_cpu(|hart_id| {
+        if hart_id == boot_hart_id() || index >= MAX_CPUS {
+            return;
+        }
+        let online = cpu_online();
+        HART_IDS[index].store(hart_id, Ordering::Release);
+        if !crate::entry::start_cpu(hart_id, boot_stack_top(index)) {
+            log::warn!("Failed to start cpu {}", hart_id);
+            HART_IDS[index].store(usize::MAX, Ordering::Release);
+            return;
+        }
+        // The stack can't be reused even if the cpu comes up too late.
+        index += 1;
+        let started = (0..START_TIMEOUT).any(|_| {
+            core::hint::spin_loop();
+            cpu_online() != online
+        });
+        if !started {
+            log::warn!("Timeout while waiting for cpu {}", hart_id);
+        }
+    });
+    cpu_online()
+}
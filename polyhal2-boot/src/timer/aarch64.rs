@@ -0,0 +1,21 @@
+use aarch64_cpu::registers::{
+    CNTFRQ_EL0, CNTP_CTL_EL0, CNTP_CVAL_EL0, CNTPCT_EL0, Readable, Writeable,
+};
+
+pub(super) fn frequency() -> u64 {
+    CNTFRQ_EL0.get()
+}
+
+pub(super) fn current_ticks() -> u64 {
+    CNTPCT_EL0.get()
+}
+
+pub(super) fn set_oneshot(deadline: u64) {
+    CNTP_CVAL_EL0.set(deadline);
+    CNTP_CTL_EL0.write(CNTP_CTL_EL0::ENABLE::SET + CNTP_CTL_EL0::IMASK::CLEAR);
+}
+
+/// The timer interrupt is pending until the timer is disabled or the next deadline is set.
+pub(super) fn disarm() {
+    CNTP_CTL_EL0.write(CNTP_CTL_EL0::ENABLE::CLEAR);
+}
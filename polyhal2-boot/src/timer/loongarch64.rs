@@ -0,0 +1,24 @@
+use loongArch64::{register::ticlr, time::Time};
+
+/// The enable bit in `TCFG`.
+const TCFG_EN: usize = 1 << 0;
+
+pub(super) fn frequency() -> u64 {
+    loongArch64::time::get_timer_freq() as _
+}
+
+pub(super) fn current_ticks() -> u64 {
+    Time::read() as _
+}
+
+/// The timer counts down from the initial value in `TCFG`, which must be a multiple of 4.
+pub(super) fn set_oneshot(deadline: u64) {
+    let ticks = deadline.saturating_sub(current_ticks()).max(4) as usize;
+    let tcfg = ticks.next_multiple_of(4) | TCFG_EN;
+    unsafe { core::arch::asm!("csrwr {}, 0x41", inout(reg) tcfg => _) };
+}
+
+/// The one-shot timer stops after expiry, clear the interrupt.
+pub(super) fn disarm() {
+    ticlr::clear_timer_interrupt();
+}
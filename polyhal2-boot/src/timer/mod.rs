@@ -0,0 +1,80 @@
+//! Timer
+//!
+//! The timer counts the ticks with a fixed frequency:
+//! - riscv64: `time` CSR, the frequency is `timebase-frequency` in the device tree
+//! - aarch64: generic timer, the frequency is `CNTFRQ_EL0`
+//! - x86_64: TSC, the frequency is given by CPUID or calibrated with the PIT
+//! - loongarch64: stable counter, the frequency is given by CPUCFG
+//!
+//! [set_oneshot] arms the timer of the current cpu, [crate::irq::TIMER_IRQ]
+//! is raised when the deadline is reached and the trap is [TrapKind::Timer].
+//! The expired timer is disarmed before the irq handlers are called.
+//!
+//! [TrapKind::Timer]: crate::trap::TrapKind::Timer
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
+#[cfg(target_arch = "loongarch64")]
+mod loongarch64;
+#[cfg(target_arch = "riscv64")]
+mod riscv64;
+#[cfg(target_arch = "x86_64")]
+mod x86_64;
+
+#[cfg(target_arch = "aarch64")]
+use aarch64 as arch;
+#[cfg(target_arch = "loongarch64")]
+use loongarch64 as arch;
+#[cfg(target_arch = "riscv64")]
+use riscv64 as arch;
+#[cfg(target_arch = "x86_64")]
+use x86_64 as arch;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// The number of nanoseconds in a second.
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// The frequency of the timer, in Hz.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Get the frequency of the timer, in Hz.
+pub fn timer_frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Get the current ticks of the timer.
+#[inline]
+pub fn current_ticks() -> u64 {
+    arch::current_ticks()
+}
+
+/// Convert the ticks to nanoseconds.
+pub fn ticks_to_nanos(ticks: u64) -> u64 {
+    (ticks as u128 * NANOS_PER_SEC / timer_frequency() as u128) as u64
+}
+
+/// Convert the nanoseconds to ticks.
+pub fn nanos_to_ticks(nanos: u64) -> u64 {
+    (nanos as u128 * timer_frequency() as u128 / NANOS_PER_SEC) as u64
+}
+
+/// Raise the timer interrupt on the current cpu when the ticks reach `deadline`.
+///
+/// The previous deadline is replaced, the interrupt is raised immediately
+/// if the deadline has passed.
+pub fn set_oneshot(deadline: u64) {
+    arch::set_oneshot(deadline);
+}
+
+/// Disarm the expired timer of the current cpu.
+pub(crate) fn disarm() {
+    arch::disarm();
+}
+
+/// Get the frequency of the timer on the boot cpu.
+pub(crate) fn init() {
+    let freq = arch::frequency();
+    log::debug!("timer frequency: {} Hz", freq);
+    FREQUENCY.store(freq, Ordering::Relaxed);
+}
@@ -0,0 +1,21 @@
+use riscv::register::time;
+
+/// The frequency used if there is no `timebase-frequency`, the QEMU virt machine.
+const DEFAULT_FREQUENCY: u64 = 10_000_000;
+
+pub(super) fn frequency() -> u64 {
+    polyhal2_device::timebase_frequency().map_or(DEFAULT_FREQUENCY, |freq| freq as _)
+}
+
+pub(super) fn current_ticks() -> u64 {
+    time::read64()
+}
+
+pub(super) fn set_oneshot(deadline: u64) {
+    sbi_rt::set_timer(deadline);
+}
+
+/// The timer interrupt is pending until the next deadline is set.
+pub(super) fn disarm() {
+    sbi_rt::set_timer(u64::MAX);
+}
@@ -0,0 +1,85 @@
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use raw_cpuid::CpuId;
+use x86_64::{instructions::port::Port, registers::model_specific::Msr};
+
+use crate::irq::{has_tsc_deadline, set_timer_count, timer_count};
+
+/// IA32_TSC_DEADLINE MSR, the local APIC timer fires when the TSC reaches it.
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+/// The frequency of the PIT.
+const PIT_FREQUENCY: u64 = 1_193_182;
+/// The time used to calibrate the TSC, in milliseconds.
+const CALIBRATE_MS: u64 = 10;
+
+/// The local APIC timer counts per TSC tick in 32.32 fixed point,
+/// zero if the TSC-deadline mode is used.
+static APIC_TIMER_RATIO: AtomicU64 = AtomicU64::new(0);
+
+/// Get the TSC frequency, and calibrate the local APIC timer against the
+/// TSC if the TSC-deadline mode isn't supported.
+pub(super) fn frequency() -> u64 {
+    let freq = tsc_frequency();
+    if !has_tsc_deadline() {
+        log::warn!("The TSC-deadline mode isn't supported, use the one-shot mode");
+        let ticks = freq * CALIBRATE_MS / 1000;
+        set_timer_count(u32::MAX);
+        let start = current_ticks();
+        while current_ticks() - start < ticks {
+            core::hint::spin_loop();
+        }
+        let counts = (u32::MAX - timer_count()) as u64;
+        set_timer_count(0);
+        APIC_TIMER_RATIO.store(((counts << 32) / ticks).max(1), Ordering::Relaxed);
+    }
+    freq
+}
+
+/// Get the TSC frequency from CPUID, or calibrate it with the PIT channel 2.
+fn tsc_frequency() -> u64 {
+    let cpuid = CpuId::new();
+    if let Some(freq) = cpuid.get_tsc_info().and_then(|info| info.tsc_frequency()) {
+        return freq;
+    }
+    let latch = PIT_FREQUENCY * CALIBRATE_MS / 1000;
+    let mut gate = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel2 = Port::<u8>::new(0x42);
+    unsafe {
+        // Enable the gate of channel 2 and disable the speaker.
+        let value = gate.read();
+        gate.write((value & !0x02) | 0x01);
+        // Channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count).
+        command.write(0xb0);
+        channel2.write(latch as u8);
+        channel2.write((latch >> 8) as u8);
+        let start = _rdtsc();
+        while gate.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        (_rdtsc() - start) * 1000 / CALIBRATE_MS
+    }
+}
+
+pub(super) fn current_ticks() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Write the TSC deadline, or convert it to the initial count of the
+/// one-shot mode.
+pub(super) fn set_oneshot(deadline: u64) {
+    match APIC_TIMER_RATIO.load(Ordering::Relaxed) {
+        0 => unsafe { Msr::new(IA32_TSC_DEADLINE).write(deadline.max(1)) },
+        ratio => {
+            let ticks = deadline.saturating_sub(current_ticks()) as u128;
+            let count = ((ticks * ratio as u128) >> 32).clamp(1, u32::MAX as u128);
+            set_timer_count(count as u32);
+        }
+    }
+}
+
+/// The timer stops by itself after it fires in both modes.
+pub(super) fn disarm() {}
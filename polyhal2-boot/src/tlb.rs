@@ -0,0 +1,177 @@
+//! Remote TLB invalidation for [polyhal2_pagetable::TlbBatch].
+//!
+//! The page table asks the other cpus using it to flush the changed entries:
+//! - riscv64: SBI RFENCE extension, one remote fence for the whole batch
+//! - aarch64: nothing to send, the `tlbi ...is` instructions are broadcast
+//! - x86_64 and loongarch64: the batch is merged into the request of every
+//!   target cpu and [IpiKind::TlbShootdown] is sent, the sender waits until
+//!   the targets have flushed.
+//!
+//! [IpiKind::TlbShootdown]: crate::ipi::IpiKind::TlbShootdown
+
+use polyhal2_pagetable::TlbBatch;
+
+use crate::smp::{CpuMask, current_cpu_id};
+
+#[cfg(any(target_arch = "x86_64", target_arch = "loongarch64"))]
+pub(crate) use shootdown::handle_shootdown;
+
+/// The cpu id used by the page table to track the active cpus.
+#[unsafe(no_mangle)]
+fn __polyhal_current_cpu_id() -> usize {
+    current_cpu_id()
+}
+
+/// Flush the batch on the cpus in `targets` except the current cpu.
+#[unsafe(no_mangle)]
+fn __polyhal_flush_remote(targets: u64, batch: &TlbBatch) {
+    let targets = CpuMask::from_bits(targets & CpuMask::others().bits());
+    if !targets.is_empty() {
+        flush_targets(targets, batch);
+    }
+}
+
+/// Fence the range covering the batch on the targets, SBI flushes the whole
+/// TLB if the range is too large.
+#[cfg(target_arch = "riscv64")]
+fn flush_targets(targets: CpuMask, batch: &TlbBatch) {
+    let (start, size) = match batch.is_full() {
+        true => (0, usize::MAX),
+        false => {
+            let start = batch.vaddrs().iter().map(|x| x.raw()).min().unwrap_or(0);
+            let end = batch.vaddrs().iter().map(|x| x.raw()).max().unwrap_or(0);
+            (start, end - start + polyhal2_pagetable::VSpace::PAGE_SIZE)
+        }
+    };
+    // The hart ids may be sparse, fence them one by one.
+    for hart_id in targets.iter().filter_map(crate::smp::hart_id_of) {
+        let ret = sbi_rt::remote_sfence_vma(1, hart_id, start, size);
+        if ret.is_err() {
+            log::warn!(
+                "Failed to fence the remote TLB of hart {}: {:?}",
+                hart_id,
+                ret
+            );
+        }
+    }
+}
+
+/// The single entries are flushed by the broadcast `tlbi vaale1is`, only the
+/// whole TLB flush needs to be broadcast.
+#[cfg(target_arch = "aarch64")]
+fn flush_targets(_targets: CpuMask, batch: &TlbBatch) {
+    if batch.is_full() {
+        unsafe { core::arch::asm!("tlbi vmalle1is; dsb ish; isb") };
+    }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "loongarch64"))]
+use shootdown::flush_targets;
+
+#[cfg(any(target_arch = "x86_64", target_arch = "loongarch64"))]
+mod shootdown {
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    use polyhal2_core::addr::VirtAddr;
+    use polyhal2_pagetable::{TLB, TLB_BATCH_SIZE, TlbBatch};
+    use spin::Mutex;
+
+    use crate::{
+        ipi::{IpiKind, send_ipi},
+        smp::{CpuMask, MAX_CPUS, current_cpu_id},
+    };
+
+    /// The entries the other cpus ask a cpu to flush.
+    struct FlushRequest {
+        vaddrs: [VirtAddr; TLB_BATCH_SIZE],
+        len: usize,
+        /// Flush the whole TLB.
+        full: bool,
+        /// Flush the global entries with the whole TLB.
+        global: bool,
+        /// The number of the requests merged so far.
+        requested: u64,
+    }
+
+    impl FlushRequest {
+        /// Merge the batch into the request.
+        fn merge(&mut self, batch: &TlbBatch) {
+            let vaddrs = batch.vaddrs();
+            match self.vaddrs.get_mut(self.len..self.len + vaddrs.len()) {
+                Some(slots) if !batch.is_full() => {
+                    slots.copy_from_slice(vaddrs);
+                    self.len += vaddrs.len();
+                }
+                _ => self.full = true,
+            }
+            self.global |= batch.is_global();
+            self.requested += 1;
+        }
+
+        /// Flush the requested entries on the current cpu and clear the request.
+        fn flush(&mut self) {
+            match (self.full, self.global) {
+                (true, true) => TLB::flush_all_global(),
+                (true, false) => TLB::flush_all(),
+                (false, _) => self.vaddrs[..self.len]
+                    .iter()
+                    .for_each(|vaddr| TLB::flush_vaddr(*vaddr)),
+            }
+            self.len = 0;
+            self.full = false;
+            self.global = false;
+        }
+    }
+
+    /// The pending request of every cpu, indexed by the cpu id.
+    static REQUESTS: [Mutex<FlushRequest>; MAX_CPUS] = [const {
+        Mutex::new(FlushRequest {
+            vaddrs: [VirtAddr::new(0); TLB_BATCH_SIZE],
+            len: 0,
+            full: false,
+            global: false,
+            requested: 0,
+        })
+    }; MAX_CPUS];
+
+    /// The number of the requests every cpu has completed.
+    static COMPLETED: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+    /// Ask the targets to flush the batch and wait for them.
+    pub(super) fn flush_targets(targets: CpuMask, batch: &TlbBatch) {
+        let mut tickets = [0; MAX_CPUS];
+        for cpu_id in targets.iter() {
+            let mut request = REQUESTS[cpu_id].lock();
+            request.merge(batch);
+            tickets[cpu_id] = request.requested;
+        }
+        send_ipi(targets, IpiKind::TlbShootdown);
+        for cpu_id in targets.iter() {
+            while COMPLETED[cpu_id].load(Ordering::Acquire) < tickets[cpu_id] {
+                // Serve the requests to the current cpu, the other senders may wait for it.
+                handle_shootdown();
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Flush the entries requested by the other cpus.
+    ///
+    /// The request is skipped if it is locked, the sender locking it will
+    /// send another interrupt after unlocking.
+    pub(crate) fn handle_shootdown() {
+        let cpu_id = current_cpu_id();
+        let Some(mut request) = REQUESTS[cpu_id].try_lock() else {
+            return;
+        };
+        if COMPLETED[cpu_id].load(Ordering::Relaxed) == request.requested {
+            return;
+        }
+        request.flush();
+        COMPLETED[cpu_id].store(request.requested, Ordering::Release);
+    }
+}
+
+/// The remote TLB is flushed without the interrupt.
+#[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
+pub(crate) fn handle_shootdown() {}
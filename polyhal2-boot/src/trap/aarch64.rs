@@ -1,35 +1,337 @@
-use core::arch::global_asm;
+use core::{
+    arch::global_asm,
+    mem::{offset_of, size_of},
+    ops::{Deref, DerefMut},
+};
 
-use aarch64_cpu::registers::{VBAR_EL1, Writeable};
+use aarch64_cpu::registers::{CNTP_CTL_EL0, Readable, TPIDR_EL0, VBAR_EL1, Writeable};
+use polyhal2_core::addr::VirtAddr;
+use polyhal2_pagetable::MappingFlags;
+
+use super::TrapKind;
+use crate::irq::SPURIOUS_IRQ;
+
+/// Saved registers when a trap happens.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct TrapFrame {
+    /// General purpose registers, `x0` - `x30`.
+    pub x: [usize; 31],
+    /// The stack pointer before the trap.
+    pub sp: usize,
+    /// Exception link register, the return address.
+    pub elr: usize,
+    /// Saved program status register.
+    pub spsr: usize,
+    /// Exception syndrome register, the cause of the trap.
+    pub esr: usize,
+    /// Fault address register.
+    pub far: usize,
+}
+
+impl TrapFrame {
+    /// Get the program counter of the trap.
+    #[inline]
+    pub const fn pc(&self) -> usize {
+        self.elr
+    }
+
+    /// Set the program counter to return.
+    #[inline]
+    pub fn set_pc(&mut self, pc: usize) {
+        self.elr = pc;
+    }
+
+    /// Get the stack pointer before the trap.
+    #[inline]
+    pub const fn sp(&self) -> usize {
+        self.sp
+    }
+
+    /// Get the system call number in `x8`.
+    #[inline]
+    pub const fn syscall_num(&self) -> usize {
+        self.x[8]
+    }
+
+    /// Get the six system call arguments in `x0` - `x5`.
+    #[inline]
+    pub fn args(&self) -> [usize; 6] {
+        self.x[0..6].try_into().unwrap()
+    }
+
+    /// Set the return value of the system call in `x0`.
+    #[inline]
+    pub fn set_ret(&mut self, ret: usize) {
+        self.x[0] = ret;
+    }
+}
+
+/// The user context, the registers of the user program.
+///
+/// The trap frame is saved here when a trap happens in EL0.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct UserContext {
+    tf: TrapFrame,
+    /// The stack pointer of the kernel when running the user program.
+    kernel_sp: usize,
+    /// The kind of the exception vector of the last trap.
+    vector_kind: usize,
+    /// The thread pointer of the user program, `TPIDR_EL0`.
+    pub tls: usize,
+}
+
+impl UserContext {
+    /// Create a user context which starts at `pc` with the stack pointer `sp`.
+    pub fn new(pc: usize, sp: usize) -> Self {
+        let mut ctx = Self::default();
+        ctx.tf.sp = sp;
+        ctx.tf.elr = pc;
+        // EL0t with all interrupts unmasked.
+        ctx.tf.spsr = 0;
+        ctx
+    }
+
+    /// Enter EL0 and run until a trap happens.
+    ///
+    /// Return the kind of the trap, interrupts are masked when it returns.
+    pub fn run(&mut self) -> TrapKind {
+        TPIDR_EL0.set(self.tls as _);
+        unsafe { aarch64_user_run(self) };
+        self.tls = TPIDR_EL0.get() as _;
+        let kind = trap_kind(&self.tf, self.vector_kind);
+        match super::dispatch_irq(&self.tf, kind) {
+            Ok(kind) | Err(kind) => kind,
+        }
+    }
+}
+
+impl Deref for UserContext {
+    type Target = TrapFrame;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tf
+    }
+}
+
+impl DerefMut for UserContext {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.tf
+    }
+}
+
+unsafe extern "C" {
+    /// Save the kernel registers and return to EL0 with `ctx`.
+    fn aarch64_user_run(ctx: &mut UserContext);
+}
+
+/// The kind of the exception vector, passed by the vector table.
+mod vector_kind {
+    pub const SYNC: usize = 0;
+    pub const IRQ: usize = 1;
+    pub const FIQ: usize = 2;
+}
+
+/// The vector table entry for the lower EL using aarch64.
+const SOURCE_LOWER_AARCH64: usize = 2;
+/// The vector table entry for the lower EL using aarch32.
+const SOURCE_LOWER_AARCH32: usize = 3;
 
 global_asm!("
-.macro INVALID_EXCP, kind, source
+.macro TRAP_ENTRY, kind, source
 .p2align 7
+    sub     sp, sp, {trapframe_size}
+    stp     x0, x1, [sp]
     mov     x0, \\kind
     mov     x1, \\source
-    bl      {trap_handler}
+    b       .Ltrap_common
 .endm
 
 .section .text
 .p2align 12
 exception_vector_base:
     // current EL, with SP_EL0
-    INVALID_EXCP 0 0
-    INVALID_EXCP 1 0
-    INVALID_EXCP 2 0
-    INVALID_EXCP 3 0
+    TRAP_ENTRY 0 0
+    TRAP_ENTRY 1 0
+    TRAP_ENTRY 2 0
+    TRAP_ENTRY 3 0
 
     // current EL, with SP_ELx
-    INVALID_EXCP 0 1
-    INVALID_EXCP 1 1
-    INVALID_EXCP 2 1
-    INVALID_EXCP 3 1
+    TRAP_ENTRY 0 1
+    TRAP_ENTRY 1 1
+    TRAP_ENTRY 2 1
+    TRAP_ENTRY 3 1
+
+    // lower EL, aarch64
+    TRAP_ENTRY 0 2
+    TRAP_ENTRY 1 2
+    TRAP_ENTRY 2 2
+    TRAP_ENTRY 3 2
+
+    // lower EL, aarch32
+    TRAP_ENTRY 0 3
+    TRAP_ENTRY 1 3
+    TRAP_ENTRY 2 3
+    TRAP_ENTRY 3 3
+
+.Ltrap_common:
+    stp     x2, x3, [sp, 2 * 8]
+    stp     x4, x5, [sp, 4 * 8]
+    stp     x6, x7, [sp, 6 * 8]
+    stp     x8, x9, [sp, 8 * 8]
+    stp     x10, x11, [sp, 10 * 8]
+    stp     x12, x13, [sp, 12 * 8]
+    stp     x14, x15, [sp, 14 * 8]
+    stp     x16, x17, [sp, 16 * 8]
+    stp     x18, x19, [sp, 18 * 8]
+    stp     x20, x21, [sp, 20 * 8]
+    stp     x22, x23, [sp, 22 * 8]
+    stp     x24, x25, [sp, 24 * 8]
+    stp     x26, x27, [sp, 26 * 8]
+    stp     x28, x29, [sp, 28 * 8]
+    // Save SP_EL0 if the trap comes from EL0.
+    add     x9, sp, {trapframe_size}
+    mrs     x10, sp_el0
+    cmp     x1, {lower_aarch64}
+    csel    x9, x10, x9, eq
+    stp     x30, x9, [sp, 30 * 8]
+    mrs     x10, elr_el1
+    mrs     x11, spsr_el1
+    stp     x10, x11, [sp, 32 * 8]
+    mrs     x12, esr_el1
+    mrs     x13, far_el1
+    stp     x12, x13, [sp, 34 * 8]
+    b.eq    .Luser_trap
+
+    mov     x2, x1
+    mov     x1, x0
+    mov     x0, sp
+    bl      {trap_handler}
+
+.Ltrap_return:
+    ldp     x10, x11, [sp, 32 * 8]
+    msr     elr_el1, x10
+    msr     spsr_el1, x11
+    ldr     x30, [sp, 30 * 8]
+    ldp     x28, x29, [sp, 28 * 8]
+    ldp     x26, x27, [sp, 26 * 8]
+    ldp     x24, x25, [sp, 24 * 8]
+    ldp     x22, x23, [sp, 22 * 8]
+    ldp     x20, x21, [sp, 20 * 8]
+    ldp     x18, x19, [sp, 18 * 8]
+    ldp     x16, x17, [sp, 16 * 8]
+    ldp     x14, x15, [sp, 14 * 8]
+    ldp     x12, x13, [sp, 12 * 8]
+    ldp     x10, x11, [sp, 10 * 8]
+    ldp     x8, x9, [sp, 8 * 8]
+    ldp     x6, x7, [sp, 6 * 8]
+    ldp     x4, x5, [sp, 4 * 8]
+    ldp     x2, x3, [sp, 2 * 8]
+    ldp     x0, x1, [sp]
+    add     sp, sp, {trapframe_size}
+    eret
+
+// sp is the user context, restore the kernel registers saved by aarch64_user_run.
+.Luser_trap:
+    str     x0, [sp, {vector_kind}]
+    ldr     x9, [sp, {kernel_sp}]
+    mov     sp, x9
+    ldp     x19, x20, [sp]
+    ldp     x21, x22, [sp, 2 * 8]
+    ldp     x23, x24, [sp, 4 * 8]
+    ldp     x25, x26, [sp, 6 * 8]
+    ldp     x27, x28, [sp, 8 * 8]
+    ldp     x29, x30, [sp, 10 * 8]
+    add     sp, sp, 12 * 8
+    ret
+
+// SP_EL1 points to the end of the trap frame in the user context after
+// returning to EL0, so the trap frame is saved into the user context.
+.global aarch64_user_run
+aarch64_user_run:
+    // Mask all interrupts until returning to EL0.
+    msr     daifset, 0xf
+    sub     sp, sp, 12 * 8
+    stp     x19, x20, [sp]
+    stp     x21, x22, [sp, 2 * 8]
+    stp     x23, x24, [sp, 4 * 8]
+    stp     x25, x26, [sp, 6 * 8]
+    stp     x27, x28, [sp, 8 * 8]
+    stp     x29, x30, [sp, 10 * 8]
+    mov     x9, sp
+    str     x9, [x0, {kernel_sp}]
+
+    ldr     x9, [x0, 31 * 8]
+    msr     sp_el0, x9
+    mov     sp, x0
+    b       .Ltrap_return
     ",
-    trap_handler = sym trap_handler
+    trapframe_size = const size_of::<TrapFrame>(),
+    kernel_sp = const offset_of!(UserContext, kernel_sp),
+    vector_kind = const offset_of!(UserContext, vector_kind),
+    lower_aarch64 = const SOURCE_LOWER_AARCH64,
+    trap_handler = sym aarch64_trap_handler,
 );
 
-unsafe extern "C" fn trap_handler(kind: usize, source: usize) -> ! {
-    panic!("Unhandled Trap @ SP_EL{source}, kind: {:#x} ", kind)
+/// Decode the synchronous exception by the exception class in ESR.
+fn decode_sync(tf: &TrapFrame) -> TrapKind {
+    let fault_addr = VirtAddr::new(tf.far);
+    match (tf.esr >> 26) & 0x3f {
+        // SVC instruction execution in AArch64 state,
+        // the elr is already the next instruction of `svc`.
+        0x15 => TrapKind::Syscall,
+        // Instruction abort from a lower or the same exception level
+        0x20 | 0x21 => TrapKind::PageFault(fault_addr, MappingFlags::X),
+        // Data abort from a lower or the same exception level
+        // WnR (bit 6) indicates whether it is caused by writing.
+        0x24 | 0x25 => match tf.esr & (1 << 6) {
+            0 => TrapKind::PageFault(fault_addr, MappingFlags::R),
+            _ => TrapKind::PageFault(fault_addr, MappingFlags::W),
+        },
+        // BRK instruction execution in AArch64 state
+        0x3c => TrapKind::Breakpoint,
+        // Unknown reason or illegal execution state
+        0x00 | 0x0e => TrapKind::IllegalInstruction,
+        _ => TrapKind::Unknown(tf.esr),
+    }
+}
+
+/// Return true if the EL1 physical timer raises the interrupt.
+fn timer_pending() -> bool {
+    CNTP_CTL_EL0.matches_all(
+        CNTP_CTL_EL0::ENABLE::SET + CNTP_CTL_EL0::IMASK::CLEAR + CNTP_CTL_EL0::ISTATUS::SET,
+    )
+}
+
+/// Decode the trap by the kind of the exception vector.
+fn trap_kind(tf: &TrapFrame, kind: usize) -> TrapKind {
+    match kind {
+        vector_kind::SYNC => decode_sync(tf),
+        // The timer interrupt is also claimed from the GIC.
+        vector_kind::IRQ | vector_kind::FIQ if timer_pending() => TrapKind::Timer,
+        vector_kind::IRQ | vector_kind::FIQ => TrapKind::Irq(SPURIOUS_IRQ),
+        // SError
+        _ => TrapKind::Unknown(tf.esr),
+    }
+}
+
+/// Get the per-cpu interrupt of the trap.
+///
+/// The pending timer isn't claimed from the GIC, it is cleared by disarming
+/// the timer. The other interrupts are claimed from the GIC.
+pub(super) fn local_irq(_tf: &TrapFrame) -> Option<usize> {
+    timer_pending().then_some(crate::irq::TIMER_IRQ)
+}
+
+/// Decode the trap in EL1 and call the trap handler.
+extern "C" fn aarch64_trap_handler(tf: &mut TrapFrame, kind: usize, source: usize) {
+    let kind = match source {
+        // Traps from aarch32 are not supported.
+        SOURCE_LOWER_AARCH32 => TrapKind::Unknown(tf.esr),
+        _ => trap_kind(tf, kind),
+    };
+    super::handle_trap(tf, kind);
 }
 
 pub(crate) fn init() {
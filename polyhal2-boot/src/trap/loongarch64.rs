@@ -1,17 +1,379 @@
-use loongArch64::register::{ecfg, eentry, era, estat};
+use core::{
+    arch::global_asm,
+    mem::{offset_of, size_of},
+    ops::{Deref, DerefMut},
+};
 
-unsafe extern "C" fn trap_vector_base() {
-    // Align function with 4096(2^12)
-    unsafe { core::arch::asm!(".p2align 12") };
-    panic!(
-        "Unhandled Trap @ ip: {:#x}, estat: {:x?}{{ bits: {:#x} }}",
-        era::read().raw(),
-        estat::read().cause(),
-        estat::read().raw()
-    )
+use loongArch64::register::{ecfg, eentry, pwch, pwcl, stlbps, tlbrehi, tlbrentry};
+use polyhal2_core::{
+    addr::VirtAddr,
+    consts::{KERNEL_OFFSET, PAGE_SHIFT},
+};
+use polyhal2_pagetable::MappingFlags;
+
+use super::TrapKind;
+use crate::irq::SPURIOUS_IRQ;
+
+/// Saved registers when a trap happens.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct TrapFrame {
+    /// General purpose registers, `regs[0]` is always zero.
+    pub regs: [usize; 32],
+    /// Exception return address.
+    pub era: usize,
+    /// Pre-exception mode information.
+    pub prmd: usize,
+    /// Exception status, the cause of the trap.
+    pub estat: usize,
+    /// Bad virtual address, the fault address.
+    pub badv: usize,
+}
+
+impl TrapFrame {
+    /// Get the program counter of the trap.
+    #[inline]
+    pub const fn pc(&self) -> usize {
+        self.era
+    }
+
+    /// Set the program counter to return.
+    #[inline]
+    pub fn set_pc(&mut self, pc: usize) {
+        self.era = pc;
+    }
+
+    /// Get the stack pointer before the trap.
+    #[inline]
+    pub const fn sp(&self) -> usize {
+        self.regs[3]
+    }
+
+    /// Get the system call number in `a7`.
+    #[inline]
+    pub const fn syscall_num(&self) -> usize {
+        self.regs[11]
+    }
+
+    /// Get the six system call arguments in `a0` - `a5`.
+    #[inline]
+    pub fn args(&self) -> [usize; 6] {
+        self.regs[4..10].try_into().unwrap()
+    }
+
+    /// Set the return value of the system call in `a0`.
+    #[inline]
+    pub fn set_ret(&mut self, ret: usize) {
+        self.regs[4] = ret;
+    }
+}
+
+/// The size of the `syscall` instruction.
+const SYSCALL_SIZE: usize = 4;
+
+/// The user context, the registers of the user program.
+///
+/// The trap frame is saved here when a trap happens in PLV3.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct UserContext {
+    tf: TrapFrame,
+    /// The stack pointer of the kernel when running the user program.
+    kernel_sp: usize,
+}
+
+impl UserContext {
+    /// Create a user context which starts at `pc` with the stack pointer `sp`.
+    pub fn new(pc: usize, sp: usize) -> Self {
+        let mut ctx = Self::default();
+        ctx.tf.regs[3] = sp;
+        ctx.tf.era = pc;
+        // Return to PLV3 with interrupts enabled.
+        ctx.tf.prmd = PRMD_PPLV_USER | PRMD_PIE;
+        ctx
+    }
+
+    /// Enter PLV3 and run until a trap happens.
+    ///
+    /// Return the kind of the trap, interrupts are disabled when it returns.
+    /// Panic if the paging mode is off, it is only enabled with a non-zero
+    /// `KERNEL_OFFSET`.
+    pub fn run(&mut self) -> TrapKind {
+        if KERNEL_OFFSET == 0 {
+            panic!("User programs need the paging mode, which needs a non-zero KERNEL_OFFSET");
+        }
+        unsafe { loongarch64_user_run(self) };
+        let kind = trap_kind(&mut self.tf);
+        match super::dispatch_irq(&self.tf, kind) {
+            Ok(kind) | Err(kind) => kind,
+        }
+    }
+}
+
+impl Deref for UserContext {
+    type Target = TrapFrame;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tf
+    }
+}
+
+impl DerefMut for UserContext {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.tf
+    }
+}
+
+/// Previous privilege level PLV3 in `PRMD`.
+const PRMD_PPLV_USER: usize = 0b11;
+/// Previous interrupt enable bit in `PRMD`.
+const PRMD_PIE: usize = 1 << 2;
+
+unsafe extern "C" {
+    /// Save the kernel registers and return to PLV3 with `ctx`.
+    fn loongarch64_user_run(ctx: &mut UserContext);
+}
+
+// KSave0 holds the pointer of the running user context in PLV3,
+// and it is zero in PLV0.
+global_asm!(
+    r"
+.altmacro
+.macro SAVE_GP n
+    st.d    $r\n, $sp, \n*8
+.endm
+.macro LOAD_GP n
+    ld.d    $r\n, $sp, \n*8
+.endm
+.macro SAVE_REGS
+    st.d    $ra, $sp, 1*8
+    st.d    $tp, $sp, 2*8
+    .set    n, 4
+    .rept   28
+        SAVE_GP %n
+        .set    n, n + 1
+    .endr
+.endm
+.macro LOAD_REGS
+    ld.d    $ra, $sp, 1*8
+    ld.d    $tp, $sp, 2*8
+    .set    n, 4
+    .rept   28
+        LOAD_GP %n
+        .set    n, n + 1
+    .endr
+.endm
+.macro SAVE_CSRS
+    csrrd   $t0, 0x6            # LOONGARCH_CSR_ERA
+    csrrd   $t1, 0x1            # LOONGARCH_CSR_PRMD
+    csrrd   $t2, 0x5            # LOONGARCH_CSR_ESTAT
+    csrrd   $t3, 0x7            # LOONGARCH_CSR_BADV
+    st.d    $t0, $sp, 32*8
+    st.d    $t1, $sp, 33*8
+    st.d    $t2, $sp, 34*8
+    st.d    $t3, $sp, 35*8
+.endm
+
+.section .text
+.p2align 12
+trap_vector_base:
+    csrwr   $sp, 0x30           # LOONGARCH_CSR_KS0
+    bnez    $sp, .Luser_trap
+    csrwr   $sp, 0x30           # LOONGARCH_CSR_KS0
+
+    addi.d  $sp, $sp, -{trapframe_size}
+    SAVE_REGS
+    addi.d  $t0, $sp, {trapframe_size}
+    st.d    $t0, $sp, 3*8
+    SAVE_CSRS
+
+    move    $a0, $sp
+    bl      {trap_handler}
+
+.Ltrap_return:
+    ld.d    $t0, $sp, 32*8
+    ld.d    $t1, $sp, 33*8
+    csrwr   $t0, 0x6            # LOONGARCH_CSR_ERA
+    csrwr   $t1, 0x1            # LOONGARCH_CSR_PRMD
+    LOAD_REGS
+    ld.d    $sp, $sp, 3*8
+    ertn
+
+# sp is the user context, KSave0 is the user stack pointer.
+.Luser_trap:
+    SAVE_REGS
+    move    $t0, $zero
+    csrwr   $t0, 0x30           # LOONGARCH_CSR_KS0
+    st.d    $t0, $sp, 3*8
+    SAVE_CSRS
+
+    # Restore the kernel registers saved by loongarch64_user_run.
+    ld.d    $sp, $sp, {kernel_sp}
+    ld.d    $ra, $sp, 0*8
+    ld.d    $tp, $sp, 1*8
+    ld.d    $r21, $sp, 2*8
+    ld.d    $fp, $sp, 3*8
+    ld.d    $s0, $sp, 4*8
+    ld.d    $s1, $sp, 5*8
+    ld.d    $s2, $sp, 6*8
+    ld.d    $s3, $sp, 7*8
+    ld.d    $s4, $sp, 8*8
+    ld.d    $s5, $sp, 9*8
+    ld.d    $s6, $sp, 10*8
+    ld.d    $s7, $sp, 11*8
+    ld.d    $s8, $sp, 12*8
+    addi.d  $sp, $sp, 14*8
+    jr      $ra
+
+.global loongarch64_user_run
+loongarch64_user_run:
+    # Disable interrupts until returning to PLV3.
+    li.w    $t0, 0x4
+    csrxchg $zero, $t0, 0x0     # LOONGARCH_CSR_CRMD
+    addi.d  $sp, $sp, -14*8
+    st.d    $ra, $sp, 0*8
+    st.d    $tp, $sp, 1*8
+    st.d    $r21, $sp, 2*8
+    st.d    $fp, $sp, 3*8
+    st.d    $s0, $sp, 4*8
+    st.d    $s1, $sp, 5*8
+    st.d    $s2, $sp, 6*8
+    st.d    $s3, $sp, 7*8
+    st.d    $s4, $sp, 8*8
+    st.d    $s5, $sp, 9*8
+    st.d    $s6, $sp, 10*8
+    st.d    $s7, $sp, 11*8
+    st.d    $s8, $sp, 12*8
+    st.d    $sp, $a0, {kernel_sp}
+
+    move    $t0, $a0
+    csrwr   $t0, 0x30           # LOONGARCH_CSR_KS0
+    move    $sp, $a0
+    b       .Ltrap_return
+
+# Refill the TLB from the page table, it runs in direct address mode.
+.p2align 12
+tlb_refill_entry:
+    csrwr   $t0, 0x8b           # LOONGARCH_CSR_TLBRSAVE
+    csrrd   $t0, 0x1b           # LOONGARCH_CSR_PGD
+    lddir   $t0, $t0, 3
+    lddir   $t0, $t0, 1
+    ldpte   $t0, 0
+    ldpte   $t0, 1
+    tlbfill
+    csrrd   $t0, 0x8b           # LOONGARCH_CSR_TLBRSAVE
+    ertn
+",
+    trapframe_size = const size_of::<TrapFrame>(),
+    kernel_sp = const offset_of!(UserContext, kernel_sp),
+    trap_handler = sym loongarch64_trap_handler,
+);
+
+/// The timer interrupt bit in `ESTAT` and `ECFG`.
+const INT_TIMER: usize = 1 << 11;
+/// The inter-processor interrupt bit in `ESTAT` and `ECFG`.
+const INT_IPI: usize = 1 << 12;
+/// The hardware interrupt bits HWI0-7 in `ESTAT` and `ECFG`.
+const INT_HWI: usize = 0xff << 2;
+
+/// Get the pending and enabled interrupts of the trap.
+fn pending_irq(tf: &TrapFrame) -> usize {
+    tf.estat & ecfg::read().lie().bits()
+}
+
+/// Decode the trap by the saved `ESTAT` and `BADV`.
+///
+/// The pc is advanced past the `syscall` if it's a system call.
+fn trap_kind(tf: &mut TrapFrame) -> TrapKind {
+    let fault_addr = VirtAddr::new(tf.badv);
+    let ecode = (tf.estat >> 16) & 0x3f;
+    match ecode {
+        0 if pending_irq(tf) & INT_TIMER != 0 => TrapKind::Timer,
+        0 if pending_irq(tf) & (INT_IPI | INT_HWI) != 0 => TrapKind::Irq(SPURIOUS_IRQ),
+        // PIL, PNR
+        0x1 | 0x5 => TrapKind::PageFault(fault_addr, MappingFlags::R),
+        // PIS, PME
+        0x2 | 0x4 => TrapKind::PageFault(fault_addr, MappingFlags::W),
+        // PIF, PNX
+        0x3 | 0x6 => TrapKind::PageFault(fault_addr, MappingFlags::X),
+        // SYS
+        0xb => {
+            tf.era += SYSCALL_SIZE;
+            TrapKind::Syscall
+        }
+        // BRK
+        0xc => TrapKind::Breakpoint,
+        // INE, IPE
+        0xd | 0xe => TrapKind::IllegalInstruction,
+        _ => TrapKind::Unknown(tf.estat),
+    }
+}
+
+/// Get the per-cpu interrupt of the trap, the hardware interrupts are from the EIOINTC.
+pub(super) fn local_irq(tf: &TrapFrame) -> Option<usize> {
+    let pending = pending_irq(tf);
+    if pending & INT_TIMER != 0 {
+        Some(crate::irq::TIMER_IRQ)
+    } else if pending & INT_IPI != 0 {
+        Some(crate::irq::IPI_IRQ)
+    } else {
+        None
+    }
+}
+
+/// Decode the trap in PLV0 and call the trap handler.
+extern "C" fn loongarch64_trap_handler(tf: &mut TrapFrame) {
+    let kind = trap_kind(tf);
+    super::handle_trap(tf, kind);
+}
+
+/// Direct address translation mode bit in `CRMD`.
+const CRMD_DA: usize = 1 << 3;
+/// Paging mode bit in `CRMD`.
+const CRMD_PG: usize = 1 << 4;
+
+/// Configure the page walker for the 3 levels page table, every level
+/// indexes `PAGE_SHIFT - 3` bits with 4K or 16K pages.
+fn init_page_walker() {
+    let width = PAGE_SHIFT - 3;
+    pwcl::set_pte_width(8);
+    pwcl::set_ptbase(PAGE_SHIFT);
+    pwcl::set_ptwidth(width);
+    pwcl::set_dir1_base(PAGE_SHIFT + width);
+    pwcl::set_dir1_width(width);
+    pwch::set_dir3_base(PAGE_SHIFT + width * 2);
+    pwch::set_dir3_width(width);
+    stlbps::set_ps(PAGE_SHIFT);
+    tlbrehi::set_ps(PAGE_SHIFT);
 }
 
 pub(crate) fn init() {
+    unsafe extern "C" {
+        fn trap_vector_base();
+        fn tlb_refill_entry();
+    }
     ecfg::set_vs(0);
     eentry::set_eentry(trap_vector_base as usize);
+    // The TLB refill exception is handled in direct address mode.
+    tlbrentry::set_tlbrentry(
+        VirtAddr::new(tlb_refill_entry as usize)
+            .mapped_paddr()
+            .raw(),
+    );
+    init_page_walker();
+    // User programs need the paging mode, the kernel must run in the
+    // direct mapped window before enabling it. The paging mode stays off
+    // with a zero KERNEL_OFFSET, UserContext::run panics then.
+    if KERNEL_OFFSET != 0 {
+        unsafe {
+            core::arch::asm!(
+                // LOONGARCH_CSR_CRMD
+                "csrxchg {val}, {mask}, 0x0",
+                val = inout(reg) CRMD_PG => _,
+                mask = in(reg) CRMD_DA | CRMD_PG,
+            );
+        }
+    }
+    // Clear KSave0, the trap comes from PLV0.
+    unsafe { core::arch::asm!("csrwr $zero, 0x30") };
 }
@@ -1,9 +1,21 @@
 //! Trap Handler
 //!
-//! Default Trap Handler for PolyHAL Boot
-//! Just panic when trap happened.
+//! Save all registers into the [TrapFrame] when a trap happens, decode
+//! the [TrapKind] and call the handler given by [crate::trap_handler].
+//! The execution is resumed after the handler returns.
+//! Panic if the kernel doesn't provide a handler.
+//!
+//! The traps in user mode are not passed to the handler, they return
+//! from [UserContext::run] with the saved registers in the [UserContext].
+//!
+//! The interrupts are dispatched to the handlers registered by
+//! [crate::irq::register_irq] first, in both kernel and user mode.
+//! The trap handler only receives the interrupts without irq handlers.
 //!
 
+use polyhal2_core::addr::VirtAddr;
+use polyhal2_pagetable::MappingFlags;
+
 #[cfg(target_arch = "aarch64")]
 pub(crate) mod aarch64;
 #[cfg(target_arch = "loongarch64")]
@@ -12,3 +24,81 @@ pub(crate) mod loongarch64;
 pub(crate) mod riscv64;
 #[cfg(target_arch = "x86_64")]
 pub(crate) mod x86_64;
+
+#[cfg(target_arch = "aarch64")]
+use aarch64::local_irq;
+#[cfg(target_arch = "aarch64")]
+pub use aarch64::{TrapFrame, UserContext};
+#[cfg(target_arch = "loongarch64")]
+use loongarch64::local_irq;
+#[cfg(target_arch = "loongarch64")]
+pub use loongarch64::{TrapFrame, UserContext};
+#[cfg(target_arch = "riscv64")]
+use riscv64::local_irq;
+#[cfg(target_arch = "riscv64")]
+pub use riscv64::{TrapFrame, UserContext};
+#[cfg(target_arch = "x86_64")]
+use x86_64::local_irq;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::{TrapFrame, UserContext};
+
+/// The decoded kind of the trap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapKind {
+    /// System call.
+    ///
+    /// The pc has been advanced past the system call instruction, use
+    /// [TrapFrame::syscall_num], [TrapFrame::args] and [TrapFrame::set_ret]
+    /// to handle it.
+    Syscall,
+    /// Page fault, with the fault address and the access type.
+    ///
+    /// The access type is one of [MappingFlags::R], [MappingFlags::W]
+    /// and [MappingFlags::X].
+    PageFault(VirtAddr, MappingFlags),
+    /// External interrupt, with the irq number.
+    ///
+    /// The trap handler receives the irq without handler, it has been
+    /// completed. Use [crate::irq::register_irq] or disable it in the
+    /// [crate::irq::irq_controller] to stop it. [UserContext::run] returns
+    /// the last handled irq, it is [crate::irq::SPURIOUS_IRQ] if no
+    /// interrupt is pending.
+    Irq(usize),
+    /// Timer interrupt.
+    Timer,
+    /// Breakpoint.
+    Breakpoint,
+    /// Illegal instruction.
+    IllegalInstruction,
+    /// The other traps, with the architecture specific cause.
+    Unknown(usize),
+}
+
+/// Dispatch the interrupt to the handlers registered by [crate::irq::register_irq].
+///
+/// Return the kind with the irq number, it is in Err if it isn't an
+/// interrupt or there is no handler.
+fn dispatch_irq(tf: &TrapFrame, kind: TrapKind) -> Result<TrapKind, TrapKind> {
+    if !matches!(kind, TrapKind::Irq(_) | TrapKind::Timer) {
+        return Err(kind);
+    }
+    // The pending timer is read before it is disarmed.
+    let local = local_irq(tf);
+    if kind == TrapKind::Timer {
+        crate::timer::disarm();
+    }
+    let with_irq = |irq| match kind {
+        TrapKind::Timer => TrapKind::Timer,
+        _ => TrapKind::Irq(irq),
+    };
+    crate::irq::handle_irq(local)
+        .map(with_irq)
+        .map_err(with_irq)
+}
+
+/// Call the trap handler given by the kernel if the irq handlers don't handle it.
+fn handle_trap(tf: &mut TrapFrame, kind: TrapKind) {
+    if let Err(kind) = dispatch_irq(tf, kind) {
+        unsafe { crate::__polyhal_trap_handler(tf, kind) }
+    }
+}
@@ -1,31 +1,361 @@
+use core::{
+    arch::global_asm,
+    mem::{offset_of, size_of},
+    ops::{Deref, DerefMut},
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use polyhal2_core::{addr::VirtAddr, consts::MAX_CPUS};
+use polyhal2_pagetable::MappingFlags;
 use riscv::{
     ExceptionNumber, InterruptNumber,
     interrupt::supervisor::{Exception, Interrupt},
-    register::{scause, sepc, sstatus, stvec},
+    register::{sscratch, stvec},
 };
 
-unsafe extern "C" fn trap_handler() -> ! {
-    unsafe { core::arch::asm!(".p2align 2") };
-    match scause::read().cause() {
-        scause::Trap::Interrupt(n) => panic!(
-            "Unhandled Trap @ ip: {:#x}, scause: {:x?} {{ bits: {:#x} }}, sstatus: {:x?}",
-            sepc::read(),
-            Interrupt::from_number(n).unwrap(),
-            n,
-            sstatus::read()
-        ),
-        scause::Trap::Exception(n) => panic!(
-            "Unhandled Trap @ ip: {:#x}, scause: {:x?} {{ bits: {:#x} }}, sstatus: {:x?}",
-            sepc::read(),
-            Exception::from_number(n).unwrap(),
-            n,
-            sstatus::read()
-        ),
-    }
-}
-
-pub(crate) fn init() {
+use super::TrapKind;
+use crate::irq::SPURIOUS_IRQ;
+
+/// Saved registers when a trap happens.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct TrapFrame {
+    /// General purpose registers, `x[0]` is always zero.
+    pub x: [usize; 32],
+    /// Supervisor exception program counter.
+    pub sepc: usize,
+    /// Supervisor status register.
+    pub sstatus: usize,
+    /// Supervisor trap cause.
+    pub scause: usize,
+    /// Supervisor trap value, the fault address.
+    pub stval: usize,
+}
+
+impl TrapFrame {
+    /// Get the program counter of the trap.
+    #[inline]
+    pub const fn pc(&self) -> usize {
+        self.sepc
+    }
+
+    /// Set the program counter to return.
+    #[inline]
+    pub fn set_pc(&mut self, pc: usize) {
+        self.sepc = pc;
+    }
+
+    /// Get the stack pointer before the trap.
+    #[inline]
+    pub const fn sp(&self) -> usize {
+        self.x[2]
+    }
+
+    /// Get the system call number in `a7`.
+    #[inline]
+    pub const fn syscall_num(&self) -> usize {
+        self.x[17]
+    }
+
+    /// Get the six system call arguments in `a0` - `a5`.
+    #[inline]
+    pub fn args(&self) -> [usize; 6] {
+        self.x[10..16].try_into().unwrap()
+    }
+
+    /// Set the return value of the system call in `a0`.
+    #[inline]
+    pub fn set_ret(&mut self, ret: usize) {
+        self.x[10] = ret;
+    }
+}
+
+/// The size of the `ecall` instruction.
+const ECALL_SIZE: usize = 4;
+
+/// The user context, the registers of the user program.
+///
+/// The trap frame is saved here when a trap happens in user mode.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct UserContext {
+    tf: TrapFrame,
+    /// The stack pointer of the kernel when running the user program.
+    kernel_sp: usize,
+}
+
+impl UserContext {
+    /// Create a user context which starts at `pc` with the stack pointer `sp`.
+    pub fn new(pc: usize, sp: usize) -> Self {
+        let mut ctx = Self::default();
+        ctx.tf.x[2] = sp;
+        ctx.tf.sepc = pc;
+        // Keep the FS and SUM bits of the kernel, return to user mode
+        // with interrupts enabled.
+        let sstatus: usize;
+        unsafe { core::arch::asm!("csrr {}, sstatus", out(reg) sstatus) };
+        ctx.tf.sstatus = (sstatus & !(SSTATUS_SIE | SSTATUS_SPP)) | SSTATUS_SPIE;
+        ctx
+    }
+
+    /// Enter user mode and run until a trap happens.
+    ///
+    /// Return the kind of the trap, interrupts are disabled when it returns.
+    pub fn run(&mut self) -> TrapKind {
+        unsafe { riscv64_user_run(self) };
+        let kind = trap_kind(&mut self.tf);
+        match super::dispatch_irq(&self.tf, kind) {
+            Ok(kind) | Err(kind) => kind,
+        }
+    }
+}
+
+impl Deref for UserContext {
+    type Target = TrapFrame;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tf
+    }
+}
+
+impl DerefMut for UserContext {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.tf
+    }
+}
+
+/// The per-cpu area, `sscratch` points to it in supervisor mode and user mode.
+#[repr(C)]
+struct PerCpu {
+    /// The running user context in user mode, it is zero in supervisor mode.
+    user_ctx: usize,
+    /// Save `t0` while the trap entry decides the mode of the trap.
+    scratch: usize,
+    /// The hart id of the cpu.
+    hart_id: usize,
+}
+
+static mut PER_CPU: [PerCpu; MAX_CPUS] = [const {
+    PerCpu {
+        user_ctx: 0,
+        scratch: 0,
+        hart_id: 0,
+    }
+}; MAX_CPUS];
+/// The number of the used [PER_CPU].
+static PER_CPU_USED: AtomicUsize = AtomicUsize::new(0);
+
+/// Interrupt enable bit in `sstatus`.
+const SSTATUS_SIE: usize = 1 << 1;
+/// Previous interrupt enable bit in `sstatus`.
+const SSTATUS_SPIE: usize = 1 << 5;
+/// Previous privilege mode bit in `sstatus`.
+const SSTATUS_SPP: usize = 1 << 8;
+
+unsafe extern "C" {
+    /// Save the kernel registers and return to user mode with `ctx`.
+    fn riscv64_user_run(ctx: &mut UserContext);
+}
+
+// `sscratch` holds the per-cpu area, the running user context in it is
+// zero in supervisor mode.
+global_asm!(
+    r"
+.altmacro
+.macro SAVE_GP n
+    sd      x\n, \n*8(sp)
+.endm
+.macro LOAD_GP n
+    ld      x\n, \n*8(sp)
+.endm
+.macro SAVE_REGS
+    sd      x1, 1*8(sp)
+    .set    n, 3
+    .rept   29
+        SAVE_GP %n
+        .set    n, n + 1
+    .endr
+.endm
+.macro LOAD_REGS
+    ld      x1, 1*8(sp)
+    .set    n, 3
+    .rept   29
+        LOAD_GP %n
+        .set    n, n + 1
+    .endr
+.endm
+.macro SAVE_CSRS
+    csrr    t0, sepc
+    csrr    t1, sstatus
+    csrr    t2, scause
+    csrr    t3, stval
+    sd      t0, 32*8(sp)
+    sd      t1, 33*8(sp)
+    sd      t2, 34*8(sp)
+    sd      t3, 35*8(sp)
+.endm
+
+.section .text
+.p2align 2
+trap_vector_base:
+    csrrw   sp, sscratch, sp
+    sd      t0, {scratch}(sp)
+    ld      t0, {user_ctx}(sp)
+    bnez    t0, .Luser_trap
+    ld      t0, {scratch}(sp)
+    csrrw   sp, sscratch, sp
+
+    addi    sp, sp, -{trapframe_size}
+    SAVE_REGS
+    addi    t0, sp, {trapframe_size}
+    sd      t0, 2*8(sp)
+    SAVE_CSRS
+
+    mv      a0, sp
+    call    {trap_handler}
+
+.Ltrap_return:
+    ld      t0, 32*8(sp)
+    ld      t1, 33*8(sp)
+    csrw    sepc, t0
+    csrw    sstatus, t1
+    LOAD_REGS
+    ld      sp, 2*8(sp)
+    sret
+
+// sp is the per-cpu area, t0 is the user context, sscratch is the user
+// stack pointer.
+.Luser_trap:
+    sd      zero, {user_ctx}(sp)
+    csrrw   sp, sscratch, sp
+    sd      sp, 2*8(t0)
+    mv      sp, t0
+    csrr    t0, sscratch
+    ld      t0, {scratch}(t0)
+    SAVE_REGS
+    SAVE_CSRS
+
+    // Restore the kernel registers saved by riscv64_user_run.
+    ld      sp, {kernel_sp}(sp)
+    ld      ra, 0*8(sp)
+    ld      gp, 1*8(sp)
+    ld      tp, 2*8(sp)
+    ld      s0, 3*8(sp)
+    ld      s1, 4*8(sp)
+    ld      s2, 5*8(sp)
+    ld      s3, 6*8(sp)
+    ld      s4, 7*8(sp)
+    ld      s5, 8*8(sp)
+    ld      s6, 9*8(sp)
+    ld      s7, 10*8(sp)
+    ld      s8, 11*8(sp)
+    ld      s9, 12*8(sp)
+    ld      s10, 13*8(sp)
+    ld      s11, 14*8(sp)
+    addi    sp, sp, 16*8
+    ret
+
+.global riscv64_user_run
+riscv64_user_run:
+    // Disable interrupts until returning to user mode.
+    csrci   sstatus, 0x2
+    addi    sp, sp, -16*8
+    sd      ra, 0*8(sp)
+    sd      gp, 1*8(sp)
+    sd      tp, 2*8(sp)
+    sd      s0, 3*8(sp)
+    sd      s1, 4*8(sp)
+    sd      s2, 5*8(sp)
+    sd      s3, 6*8(sp)
+    sd      s4, 7*8(sp)
+    sd      s5, 8*8(sp)
+    sd      s6, 9*8(sp)
+    sd      s7, 10*8(sp)
+    sd      s8, 11*8(sp)
+    sd      s9, 12*8(sp)
+    sd      s10, 13*8(sp)
+    sd      s11, 14*8(sp)
+    sd      sp, {kernel_sp}(a0)
+
+    csrr    t0, sscratch
+    sd      a0, {user_ctx}(t0)
+    mv      sp, a0
+    j       .Ltrap_return
+",
+    trapframe_size = const size_of::<TrapFrame>(),
+    kernel_sp = const offset_of!(UserContext, kernel_sp),
+    user_ctx = const offset_of!(PerCpu, user_ctx),
+    scratch = const offset_of!(PerCpu, scratch),
+    trap_handler = sym riscv64_trap_handler,
+);
+
+/// Decode the trap by the saved `scause` and `stval`.
+///
+/// The pc is advanced past the `ecall` if it's a system call.
+fn trap_kind(tf: &mut TrapFrame) -> TrapKind {
+    let fault_addr = VirtAddr::new(tf.stval);
+    let code = tf.scause & !(1 << (usize::BITS - 1));
+    if tf.scause >> (usize::BITS - 1) != 0 {
+        match Interrupt::from_number(code) {
+            Ok(Interrupt::SupervisorTimer) => TrapKind::Timer,
+            Ok(Interrupt::SupervisorExternal | Interrupt::SupervisorSoft) => {
+                TrapKind::Irq(SPURIOUS_IRQ)
+            }
+            _ => TrapKind::Unknown(tf.scause),
+        }
+    } else {
+        match Exception::from_number(code) {
+            Ok(Exception::UserEnvCall) => {
+                tf.sepc += ECALL_SIZE;
+                TrapKind::Syscall
+            }
+            Ok(Exception::Breakpoint) => TrapKind::Breakpoint,
+            Ok(Exception::IllegalInstruction) => TrapKind::IllegalInstruction,
+            Ok(Exception::LoadPageFault) => TrapKind::PageFault(fault_addr, MappingFlags::R),
+            Ok(Exception::StorePageFault) => TrapKind::PageFault(fault_addr, MappingFlags::W),
+            Ok(Exception::InstructionPageFault) => TrapKind::PageFault(fault_addr, MappingFlags::X),
+            _ => TrapKind::Unknown(tf.scause),
+        }
+    }
+}
+
+/// Get the per-cpu interrupt of the trap, the external interrupts are from the PLIC.
+pub(super) fn local_irq(tf: &TrapFrame) -> Option<usize> {
+    let code = tf.scause & !(1 << (usize::BITS - 1));
+    match Interrupt::from_number(code) {
+        Ok(Interrupt::SupervisorTimer) => Some(crate::irq::TIMER_IRQ),
+        Ok(Interrupt::SupervisorSoft) => Some(crate::irq::IPI_IRQ),
+        _ => None,
+    }
+}
+
+/// Decode the trap in supervisor mode and call the trap handler.
+extern "C" fn riscv64_trap_handler(tf: &mut TrapFrame) {
+    let kind = trap_kind(tf);
+    super::handle_trap(tf, kind);
+}
+
+/// Set the trap vector and the per-cpu area of the hart `hart_id`.
+pub(crate) fn init(hart_id: usize) {
+    unsafe extern "C" {
+        fn trap_vector_base();
+    }
+    let index = PER_CPU_USED.fetch_add(1, Ordering::Relaxed);
+    assert!(
+        index < MAX_CPUS,
+        "Too many cpus to initialize the per-cpu area"
+    );
+    let percpu = unsafe { &mut *addr_of_mut!(PER_CPU[index]) };
+    percpu.hart_id = hart_id;
     unsafe {
-        stvec::write(trap_handler as _, stvec::TrapMode::Direct);
+        sscratch::write(percpu as *mut PerCpu as usize);
+        stvec::write(trap_vector_base as _, stvec::TrapMode::Direct);
     }
 }
+
+/// Get the hart id of the current hart from the per-cpu area.
+pub(crate) fn current_hart_id() -> usize {
+    let percpu = sscratch::read() as *const PerCpu;
+    unsafe { (*percpu).hart_id }
+}
@@ -1,30 +1,241 @@
-use core::arch::global_asm;
+use core::{
+    arch::global_asm,
+    mem::{offset_of, size_of},
+    ops::{Deref, DerefMut},
+    ptr::{addr_of, addr_of_mut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
+use polyhal2_core::{addr::VirtAddr, consts::MAX_CPUS};
+use polyhal2_pagetable::MappingFlags;
 use x86_64::{
-    registers::model_specific::LStar,
-    structures::idt::{Entry, HandlerFunc, InterruptDescriptorTable},
+    PrivilegeLevel,
+    instructions::{
+        segmentation::{CS, DS, ES, SS, Segment},
+        tables::{load_tss, sgdt},
+    },
+    registers::{
+        control::{Cr2, EferFlags},
+        model_specific::{Efer, FsBase, KernelGsBase, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    structures::{
+        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+        idt::{Entry, HandlerFunc, InterruptDescriptorTable},
+        tss::TaskStateSegment,
+    },
 };
 
+use super::TrapKind;
+use crate::irq::SPURIOUS_IRQ;
+
 const NUM_INT: usize = 256;
 static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
 
+/// The kernel code segment selector.
+const KERNEL_CS: SegmentSelector = SegmentSelector::new(1, PrivilegeLevel::Ring0);
+/// The kernel data segment selector.
+const KERNEL_DS: SegmentSelector = SegmentSelector::new(2, PrivilegeLevel::Ring0);
+/// The user data segment selector.
+const USER_DS: SegmentSelector = SegmentSelector::new(3, PrivilegeLevel::Ring3);
+/// The user code segment selector.
+const USER_CS: SegmentSelector = SegmentSelector::new(4, PrivilegeLevel::Ring3);
+/// The task state segment selector.
+const TSS_SEL: SegmentSelector = SegmentSelector::new(5, PrivilegeLevel::Ring0);
+
+/// The interrupt enable flag in `rflags`.
+const RFLAGS_IF: usize = 1 << 9;
+/// The reserved bit in `rflags`, always one.
+const RFLAGS_RESERVED: usize = 1 << 1;
+
+/// The GDT and TSS of a cpu, the GDT must be the first field.
+///
+/// The kernel `gs` base points to it, `syscall` uses it to find the kernel stack.
+#[repr(C)]
+struct CpuTables {
+    gdt: GlobalDescriptorTable,
+    tss: TaskStateSegment,
+    /// The user stack pointer saved by `syscall`.
+    user_rsp: usize,
+}
+
+/// The GDT and TSS of every cpu.
+static mut CPU_TABLES: [CpuTables; MAX_CPUS] = [const {
+    CpuTables {
+        gdt: GlobalDescriptorTable::new(),
+        tss: TaskStateSegment::new(),
+        user_rsp: 0,
+    }
+}; MAX_CPUS];
+/// The number of the used [CPU_TABLES].
+static CPU_TABLES_USED: AtomicUsize = AtomicUsize::new(0);
+
+/// Saved registers when a trap happens.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct TrapFrame {
+    /// General purpose register `rax`.
+    pub rax: usize,
+    /// General purpose register `rcx`.
+    pub rcx: usize,
+    /// General purpose register `rdx`.
+    pub rdx: usize,
+    /// General purpose register `rbx`.
+    pub rbx: usize,
+    /// General purpose register `rbp`.
+    pub rbp: usize,
+    /// General purpose register `rsi`.
+    pub rsi: usize,
+    /// General purpose register `rdi`.
+    pub rdi: usize,
+    /// General purpose register `r8`.
+    pub r8: usize,
+    /// General purpose register `r9`.
+    pub r9: usize,
+    /// General purpose register `r10`.
+    pub r10: usize,
+    /// General purpose register `r11`.
+    pub r11: usize,
+    /// General purpose register `r12`.
+    pub r12: usize,
+    /// General purpose register `r13`.
+    pub r13: usize,
+    /// General purpose register `r14`.
+    pub r14: usize,
+    /// General purpose register `r15`.
+    pub r15: usize,
+    /// The interrupt vector.
+    pub vector: usize,
+    /// The error code pushed by the cpu, zero if there is no error code.
+    pub error_code: usize,
+
+    /// The instruction pointer, pushed by the cpu.
+    pub rip: usize,
+    /// The code segment, pushed by the cpu.
+    pub cs: usize,
+    /// The flags register, pushed by the cpu.
+    pub rflags: usize,
+    /// The stack pointer, pushed by the cpu.
+    pub rsp: usize,
+    /// The stack segment, pushed by the cpu.
+    pub ss: usize,
+}
+
+impl TrapFrame {
+    /// Get the program counter of the trap.
+    #[inline]
+    pub const fn pc(&self) -> usize {
+        self.rip
+    }
+
+    /// Set the program counter to return.
+    #[inline]
+    pub fn set_pc(&mut self, pc: usize) {
+        self.rip = pc;
+    }
+
+    /// Get the stack pointer before the trap.
+    #[inline]
+    pub const fn sp(&self) -> usize {
+        self.rsp
+    }
+
+    /// Get the system call number in `rax`.
+    #[inline]
+    pub const fn syscall_num(&self) -> usize {
+        self.rax
+    }
+
+    /// Get the six system call arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`.
+    #[inline]
+    pub const fn args(&self) -> [usize; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+
+    /// Set the return value of the system call in `rax`.
+    #[inline]
+    pub fn set_ret(&mut self, ret: usize) {
+        self.rax = ret;
+    }
+}
+
+/// The user context, the registers of the user program.
+///
+/// The trap frame is saved here when a trap happens in ring 3.
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Default)]
+pub struct UserContext {
+    tf: TrapFrame,
+    /// The stack pointer of the kernel when running the user program.
+    kernel_sp: usize,
+    /// The thread pointer of the user program, the base of `fs`.
+    pub fs_base: usize,
+}
+
+impl UserContext {
+    /// Create a user context which starts at `pc` with the stack pointer `sp`.
+    pub fn new(pc: usize, sp: usize) -> Self {
+        let mut ctx = Self::default();
+        ctx.tf.rip = pc;
+        ctx.tf.rsp = sp;
+        ctx.tf.cs = USER_CS.0 as _;
+        ctx.tf.ss = USER_DS.0 as _;
+        // Return to ring 3 with interrupts enabled.
+        ctx.tf.rflags = RFLAGS_IF | RFLAGS_RESERVED;
+        ctx
+    }
+
+    /// Enter ring 3 and run until a trap happens.
+    ///
+    /// Return the kind of the trap, interrupts are disabled when it returns.
+    pub fn run(&mut self) -> TrapKind {
+        // The cpu pushes the trap frame into the user context when
+        // a trap happens in ring 3.
+        let tf_end = addr_of!(self.tf) as usize + size_of::<TrapFrame>();
+        current_tss().privilege_stack_table[0] = x86_64::VirtAddr::new(tf_end as _);
+        FsBase::write(x86_64::VirtAddr::new_truncate(self.fs_base as _));
+        unsafe { x86_64_user_run(self) };
+        self.fs_base = FsBase::read().as_u64() as _;
+        let kind = trap_kind(&self.tf);
+        match super::dispatch_irq(&self.tf, kind) {
+            Ok(kind) | Err(kind) => kind,
+        }
+    }
+}
+
+impl Deref for UserContext {
+    type Target = TrapFrame;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tf
+    }
+}
+
+impl DerefMut for UserContext {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.tf
+    }
+}
+
+unsafe extern "C" {
+    /// Save the kernel registers and return to ring 3 with `ctx`.
+    fn x86_64_user_run(ctx: &mut UserContext);
+}
+
 global_asm!(
     r#"
 .equ NUM_INT, {num_int}
 .altmacro
 .macro DEF_HANDLER, i
 .Ltrap_handler_\i:
-.if \i == 8 || (\i >= 10 && \i <= 14) || \i == 17
+.if \i == 8 || (\i >= 10 && \i <= 14) || \i == 17 || \i == 21 || \i == 29 || \i == 30
     # error code pushed by CPU
-    pop     rsi
-    mov     rdi, \i          # interrupt vector
-    pop     rdx
-    jmp     {trap_handler}
+    push    \i              # interrupt vector
+    jmp     .Ltrap_common
 .else
-    mov     rsi, 0           # fill in error code in TrapFrame
-    mov     rdi, \i          # interrupt vector
-    pop     rdx
-    jmp     {trap_handler}
+    push    0               # fill in error code in TrapFrame
+    push    \i              # interrupt vector
+    jmp     .Ltrap_common
 .endif
 .endm
 
@@ -41,6 +252,92 @@ _trap_handlers:
     .set i, i + 1
 .endr
 
+.Ltrap_common:
+    push    r15
+    push    r14
+    push    r13
+    push    r12
+    push    r11
+    push    r10
+    push    r9
+    push    r8
+    push    rdi
+    push    rsi
+    push    rbp
+    push    rbx
+    push    rdx
+    push    rcx
+    push    rax
+
+    test    byte ptr [rsp + 18 * 8], 3  # cs of the trap frame
+    jnz     .Luser_trap
+
+    mov     rdi, rsp
+    call    {trap_handler}
+
+.Ltrap_return:
+    pop     rax
+    pop     rcx
+    pop     rdx
+    pop     rbx
+    pop     rbp
+    pop     rsi
+    pop     rdi
+    pop     r8
+    pop     r9
+    pop     r10
+    pop     r11
+    pop     r12
+    pop     r13
+    pop     r14
+    pop     r15
+
+    add     rsp, 16         # pop vector, error_code
+    iretq
+
+# rsp is the user context, restore the kernel registers saved by x86_64_user_run.
+.Luser_trap:
+    mov     rsp, [rsp + {kernel_sp}]
+    pop     r15
+    pop     r14
+    pop     r13
+    pop     r12
+    pop     rbx
+    pop     rbp
+    ret
+
+.global x86_64_user_run
+x86_64_user_run:
+    # Disable interrupts until returning to ring 3.
+    cli
+    push    rbp
+    push    rbx
+    push    r12
+    push    r13
+    push    r14
+    push    r15
+    mov     [rdi + {kernel_sp}], rsp
+
+    mov     rsp, rdi
+    jmp     .Ltrap_return
+
+# The entry of `syscall` in ring 3, rcx is the next rip and r11 is the rflags.
+# Build the trap frame on the kernel stack in TSS, which is the user context.
+.global x86_64_syscall_entry
+x86_64_syscall_entry:
+    swapgs
+    mov     gs:[{user_rsp}], rsp
+    mov     rsp, gs:[{tss_rsp0}]
+    push    {user_ds}       # ss
+    push    gs:[{user_rsp}] # rsp
+    push    r11             # rflags
+    push    {user_cs}       # cs
+    push    rcx             # rip
+    swapgs
+    push    0               # error_code
+    push    {syscall_vector}
+    jmp     .Ltrap_common
+
 .section .rodata
 .global trap_handler_table
 trap_handler_table:
@@ -50,15 +347,107 @@ trap_handler_table:
     .set i, i + 1
 .endr
 "#,
-    trap_handler = sym trap_handler,
+    trap_handler = sym x86_64_trap_handler,
+    kernel_sp = const offset_of!(UserContext, kernel_sp),
+    user_rsp = const offset_of!(CpuTables, user_rsp),
+    tss_rsp0 = const offset_of!(CpuTables, tss) + offset_of!(TaskStateSegment, privilege_stack_table),
+    user_cs = const USER_CS.0,
+    user_ds = const USER_DS.0,
+    syscall_vector = const SYSCALL_VECTOR,
     num_int = const NUM_INT
 );
 
-unsafe extern "C" fn trap_handler(vector: usize, error_code: usize, rip: usize) -> ! {
-    panic!(
-        "Unhandled Trap @ ip: {:#x}, vector: {:#x?} error_code: {:#x}",
-        rip, vector, error_code
-    )
+/// The vector of the breakpoint exception, `int3`.
+const BREAKPOINT_VECTOR: usize = 3;
+/// The first vector of the external interrupts.
+pub(crate) const IRQ_VECTOR_START: usize = 0x20;
+/// The vector in the trap frame for `syscall`, it isn't in the IDT.
+const SYSCALL_VECTOR: usize = NUM_INT;
+
+/// Decode the trap by the interrupt vector.
+fn trap_kind(tf: &TrapFrame) -> TrapKind {
+    match tf.vector {
+        BREAKPOINT_VECTOR => TrapKind::Breakpoint,
+        // Invalid Opcode
+        6 => TrapKind::IllegalInstruction,
+        // Page Fault, the error code indicates the access type
+        14 => {
+            let fault_addr = VirtAddr::new(Cr2::read_raw() as _);
+            let access = if tf.error_code & (1 << 4) != 0 {
+                MappingFlags::X
+            } else if tf.error_code & (1 << 1) != 0 {
+                MappingFlags::W
+            } else {
+                MappingFlags::R
+            };
+            TrapKind::PageFault(fault_addr, access)
+        }
+        // The rip is already the next instruction of `syscall`.
+        SYSCALL_VECTOR => TrapKind::Syscall,
+        vector if vector == IRQ_VECTOR_START + crate::irq::TIMER_IRQ => TrapKind::Timer,
+        IRQ_VECTOR_START.. => TrapKind::Irq(SPURIOUS_IRQ),
+        _ => TrapKind::Unknown(tf.vector),
+    }
+}
+
+/// Get the per-cpu interrupt of the trap, all interrupts are claimed from the local APIC.
+pub(super) fn local_irq(_tf: &TrapFrame) -> Option<usize> {
+    None
+}
+
+/// Decode the trap in ring 0 and call the trap handler.
+extern "C" fn x86_64_trap_handler(tf: &mut TrapFrame) {
+    let kind = trap_kind(tf);
+    super::handle_trap(tf, kind);
+}
+
+/// Get the TSS of the current cpu, which is placed after the GDT.
+#[allow(static_mut_refs)]
+fn current_tss() -> &'static mut TaskStateSegment {
+    let gdt = sgdt().base.as_u64() as usize;
+    let index = (gdt - addr_of!(CPU_TABLES) as usize) / size_of::<CpuTables>();
+    unsafe { &mut CPU_TABLES[index].tss }
+}
+
+/// Load the GDT with the user segments and the TSS for the current cpu.
+fn init_gdt() {
+    let index = CPU_TABLES_USED.fetch_add(1, Ordering::Relaxed);
+    assert!(index < MAX_CPUS, "Too many cpus to initialize GDT");
+    let tables = unsafe { &mut *addr_of_mut!(CPU_TABLES[index]) };
+    tables.gdt.add_entry(Descriptor::kernel_code_segment());
+    tables.gdt.add_entry(Descriptor::kernel_data_segment());
+    tables.gdt.add_entry(Descriptor::user_data_segment());
+    tables.gdt.add_entry(Descriptor::user_code_segment());
+    tables
+        .gdt
+        .add_entry(unsafe { Descriptor::tss_segment_unchecked(addr_of!(tables.tss)) });
+    unsafe {
+        tables.gdt.load_unsafe();
+        CS::set_reg(KERNEL_CS);
+        SS::set_reg(KERNEL_DS);
+        DS::set_reg(SegmentSelector(0));
+        ES::set_reg(SegmentSelector(0));
+        load_tss(TSS_SEL);
+    }
+    KernelGsBase::write(x86_64::VirtAddr::new(tables as *mut _ as _));
+}
+
+/// Enable `syscall` and set its entry.
+fn init_syscall() {
+    unsafe extern "C" {
+        fn x86_64_syscall_entry();
+    }
+    Star::write(USER_CS, USER_DS, KERNEL_CS, KERNEL_DS)
+        .expect("The GDT layout doesn't match the requirement of STAR");
+    // Disable interrupts and clear the flags in kernel.
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    LStar::write(x86_64::VirtAddr::new(x86_64_syscall_entry as usize as _));
+    unsafe { Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS) };
 }
 
 #[allow(static_mut_refs)]
@@ -67,13 +456,19 @@ pub(crate) fn init() {
         #[link_name = "trap_handler_table"]
         static ENTRIES: [extern "C" fn(); NUM_INT];
     }
-    LStar::write(x86_64::VirtAddr::new(trap_handler as _));
+    // The IDT entries use the code segment of the new GDT.
+    init_gdt();
     unsafe {
         let entries =
             core::slice::from_raw_parts_mut(&mut IDT as *mut _ as *mut Entry<HandlerFunc>, NUM_INT);
         for i in 0..NUM_INT {
-            entries[i].set_handler_fn(core::mem::transmute(ENTRIES[i]));
+            let options = entries[i].set_handler_fn(core::mem::transmute(ENTRIES[i]));
+            // Allow `int3` in ring 3.
+            if i == BREAKPOINT_VECTOR {
+                options.set_privilege_level(PrivilegeLevel::Ring3);
+            }
         }
         IDT.load();
     };
+    init_syscall();
 }
@@ -25,6 +25,13 @@ impl PhysAddr {
 pub struct VirtAddr(pub(crate) usize);
 
 impl VirtAddr {
+    /// Get the physical address of a kernel virtual address.
+    /// This is the reverse operation of [PhysAddr::mapped_vaddr].
+    /// Physical address = Virtual address - kernel offset.
+    pub const fn mapped_paddr(&self) -> PhysAddr {
+        PhysAddr(self.0 & !KERNEL_OFFSET)
+    }
+
     /// Get the ptr for the given `VirtAddr`
     #[inline]
     pub const fn get_ptr<T>(&self) -> *const T {
@@ -2,5 +2,22 @@ use crate::declare_env_var;
 
 /// Kernel Offset is the offset between
 pub const KERNEL_OFFSET: usize = declare_env_var!("KERNEL_OFFSET", usize);
-/// The size of the page.
-pub const PAGE_SIZE: usize = declare_env_var!("PAGE_SIZE", usize);
+/// The size of the page, it is selected by the granule features.
+pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
+/// The shift of the page size.
+///
+/// aarch64 supports 16K and 64K pages, loongarch64 supports 16K pages,
+/// the other architectures always use 4K pages.
+pub const PAGE_SHIFT: usize = match (
+    cfg!(any(target_arch = "aarch64", target_arch = "loongarch64")),
+    cfg!(feature = "granule-16k"),
+    cfg!(all(target_arch = "aarch64", feature = "granule-64k")),
+) {
+    (true, _, true) => 16,
+    (true, true, false) => 14,
+    _ => 12,
+};
+/// The maximum number of CPUs supported by polyhal2.
+///
+/// Per-CPU resources such as boot stacks are reserved for this many CPUs.
+pub const MAX_CPUS: usize = 8;
@@ -11,7 +11,7 @@ const UART_BASE: PhysAddr = PhysAddr::new(0x0900_0000);
 static UART: Mutex<Pl011Uart> = Mutex::new(Pl011Uart::new(UART_BASE.mapped_vaddr().get_mut_ptr()));
 
 // Initialize the UART
-polyhal2_boot::ph_ctor!(UART_INIT, || UART.lock().init());
+polyhal2_boot::ph_ctor!(UART_INIT, stage = Console, || UART.lock().init());
 
 impl DebugConsole {
     /// Writes a byte to the console.
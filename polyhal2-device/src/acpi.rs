@@ -0,0 +1,169 @@
+//! ACPI Tables
+//!
+//! <https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html>
+//!
+//! Only the tables needed by polyhal2 are parsed here.
+//! All tables are read through the linear mapping of the physical memory.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use polyhal2_core::addr::PhysAddr;
+
+static RSDP_PTR: AtomicUsize = AtomicUsize::new(0);
+
+/// The size of the common header of the system description tables.
+const SDT_HEADER_SIZE: usize = 36;
+
+/// Read a value from the given physical address.
+#[inline]
+fn read<T: Copy>(paddr: usize) -> T {
+    unsafe {
+        PhysAddr::new(paddr)
+            .mapped_vaddr()
+            .get_ptr::<T>()
+            .read_unaligned()
+    }
+}
+
+/// Check the signature and checksum of the table at `paddr`.
+fn check_table(paddr: usize, signature: &[u8], len: usize) -> bool {
+    let bytes = PhysAddr::new(paddr)
+        .mapped_vaddr()
+        .slice_with_len::<u8>(len);
+    bytes.starts_with(signature) && bytes.iter().fold(0u8, |acc, x| acc.wrapping_add(*x)) == 0
+}
+
+/// Search the RSDP in the given physical range.
+fn search_rsdp(start: usize, end: usize) -> Option<usize> {
+    (start..end)
+        .step_by(16)
+        .find(|paddr| check_table(*paddr, b"RSD PTR ", 20))
+}
+
+/// Initialize ACPI with the RSDP given by the bootloader.
+pub fn init_rsdp(rsdp: PhysAddr) {
+    RSDP_PTR.store(rsdp.raw(), Ordering::SeqCst);
+}
+
+/// Get the physical address of the RSDP.
+///
+/// Search the EBDA and the BIOS read-only area if the bootloader
+/// doesn't provide it.
+pub fn get_rsdp_ptr() -> Option<PhysAddr> {
+    let mut rsdp = RSDP_PTR.load(Ordering::SeqCst);
+    if rsdp == 0 {
+        let ebda = (read::<u16>(0x40e) as usize) << 4;
+        rsdp = search_rsdp(ebda, ebda + 0x400)
+            .or_else(|| search_rsdp(0xe_0000, 0x10_0000))
+            .unwrap_or(0);
+        RSDP_PTR.store(rsdp, Ordering::SeqCst);
+    }
+    match rsdp {
+        0 => None,
+        _ => Some(PhysAddr::new(rsdp)),
+    }
+}
+
+/// Find the system description table with the given signature.
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let rsdp = get_rsdp_ptr()?.raw();
+    // Use XSDT if the revision of the RSDP is 2 or higher.
+    let (sdt, entry_size) = match read::<u8>(rsdp + 15) {
+        0 => (read::<u32>(rsdp + 16) as usize, 4),
+        _ => (read::<u64>(rsdp + 24) as usize, 8),
+    };
+    let len = read::<u32>(sdt + 4) as usize;
+    (sdt + SDT_HEADER_SIZE..sdt + len)
+        .step_by(entry_size)
+        .map(|entry| match entry_size {
+            4 => read::<u32>(entry) as usize,
+            _ => read::<u64>(entry) as usize,
+        })
+        .find(|table| check_table(*table, signature, read::<u32>(table + 4) as usize))
+        .map(PhysAddr::new)
+}
+
+/// The interrupt controller structure in the MADT.
+#[derive(Debug, Clone, Copy)]
+pub enum MadtEntry {
+    /// Processor Local APIC
+    LocalApic {
+        /// The ACPI processor UID
+        processor_id: u8,
+        /// The local APIC id of the processor
+        apic_id: u8,
+        /// The processor is enabled, the online capable ones are excluded.
+        enabled: bool,
+    },
+    /// I/O APIC
+    IoApic {
+        /// The I/O APIC id
+        id: u8,
+        /// The physical address of the I/O APIC registers
+        address: PhysAddr,
+        /// The first global system interrupt handled by this I/O APIC
+        gsi_base: u32,
+    },
+    /// Interrupt Source Override, map an ISA irq to a global system interrupt
+    InterruptOverride {
+        /// The ISA irq number
+        source: u8,
+        /// The global system interrupt that the irq will signal
+        gsi: u32,
+        /// MPS INTI flags, polarity and trigger mode
+        flags: u16,
+    },
+    /// Local APIC Address Override
+    LocalApicAddress(PhysAddr),
+}
+
+/// Get the physical address of the local APIC in the MADT.
+pub fn local_apic_addr() -> Option<PhysAddr> {
+    let madt = find_table(b"APIC")?.raw();
+    let mut addr = PhysAddr::new(read::<u32>(madt + SDT_HEADER_SIZE) as usize);
+    for_each_madt_entry(|entry| {
+        if let MadtEntry::LocalApicAddress(paddr) = entry {
+            addr = paddr;
+        }
+    });
+    Some(addr)
+}
+
+/// Call `f` with every interrupt controller structure in the MADT.
+pub fn for_each_madt_entry(mut f: impl FnMut(MadtEntry)) {
+    let Some(madt) = find_table(b"APIC") else {
+        return;
+    };
+    let madt = madt.raw();
+    let end = madt + read::<u32>(madt + 4) as usize;
+    // Skip the local interrupt controller address and flags.
+    let mut entry = madt + SDT_HEADER_SIZE + 8;
+    while entry + 2 <= end {
+        let len = read::<u8>(entry + 1) as usize;
+        if len < 2 {
+            break;
+        }
+        match read::<u8>(entry) {
+            0 => f(MadtEntry::LocalApic {
+                processor_id: read(entry + 2),
+                apic_id: read(entry + 3),
+                enabled: read::<u32>(entry + 4) & 1 != 0,
+            }),
+            1 => f(MadtEntry::IoApic {
+                id: read(entry + 2),
+                address: PhysAddr::new(read::<u32>(entry + 4) as usize),
+                gsi_base: read(entry + 8),
+            }),
+            2 => f(MadtEntry::InterruptOverride {
+                source: read(entry + 3),
+                gsi: read(entry + 4),
+                flags: read(entry + 8),
+            }),
+            5 => f(MadtEntry::LocalApicAddress(PhysAddr::new(
+                read::<u64>(entry + 4) as usize,
+            ))),
+            _ => {}
+        }
+        entry += len;
+    }
+}
@@ -8,7 +8,13 @@
 #![deny(warnings)]
 #![deny(missing_docs)]
 
-use core::sync::atomic::{AtomicUsize, Ordering};
+/// ACPI tables parser, used on the platform without device tree.
+pub mod acpi;
+
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use polyhal2_core::addr::PhysAddr;
 
@@ -40,3 +46,159 @@ pub fn init_dtb(dtb_ptr: PhysAddr) -> Option<()> {
 pub fn get_dtb_ptr() -> PhysAddr {
     PhysAddr::new(DTB_PTR.load(Ordering::SeqCst))
 }
+
+/// Get the device tree initialized by [init_dtb].
+fn get_fdt() -> Option<fdt::Fdt<'static>> {
+    let dtb_ptr = get_dtb_ptr();
+    if dtb_ptr.raw() == 0 {
+        return None;
+    }
+    unsafe { fdt::Fdt::from_ptr(dtb_ptr.mapped_vaddr().get_ptr()).ok() }
+}
+
+/// Call `f` with the hardware id of every cpu in the device tree.
+///
+/// The id is the `reg` property of the cpu node, it is the hart id
+/// on riscv64, the MPIDR affinity on aarch64 and the cpuid on loongarch64.
+pub fn for_each_cpu(mut f: impl FnMut(usize)) {
+    if let Some(fdt) = get_fdt() {
+        fdt.cpus().for_each(|cpu| f(cpu.ids().first()));
+    }
+}
+
+/// Get the method used to call the PSCI firmware, `hvc` or `smc`.
+pub fn psci_method() -> Option<&'static str> {
+    get_fdt()?
+        .find_compatible(&["arm,psci-1.0", "arm,psci-0.2", "arm,psci"])?
+        .property("method")?
+        .as_str()
+}
+
+/// Call `f` with the start address and size of every register region of
+/// the first device compatible with one of `compatible`.
+///
+/// Return false if there is no such device.
+pub fn for_each_compatible_reg(compatible: &[&str], mut f: impl FnMut(PhysAddr, usize)) -> bool {
+    let Some(fdt) = get_fdt() else {
+        return false;
+    };
+    let Some(node) = fdt.find_compatible(compatible) else {
+        return false;
+    };
+    node.reg().into_iter().flatten().for_each(|mr| {
+        f(
+            PhysAddr::new(mr.starting_address as _),
+            mr.size.unwrap_or(0),
+        )
+    });
+    true
+}
+
+/// Get the frequency of the timer in the `/cpus` node.
+pub fn timebase_frequency() -> Option<usize> {
+    let fdt = get_fdt()?;
+    let cpus = fdt.find_node("/cpus")?;
+    if let Some(freq) = cpus.property("timebase-frequency") {
+        return freq.as_usize();
+    }
+    cpus.children()
+        .find_map(|cpu| cpu.property("timebase-frequency")?.as_usize())
+}
+
+/// Return true if all cpus in the device tree have the riscv ISA extension `name`.
+///
+/// The extension is found in `riscv,isa-extensions` or the `riscv,isa` string.
+pub fn has_isa_extension(name: &str) -> bool {
+    let Some(fdt) = get_fdt() else {
+        return false;
+    };
+    let mut found = false;
+    for cpu in fdt.cpus() {
+        let has = match cpu.property("riscv,isa-extensions") {
+            Some(exts) => exts
+                .value
+                .split(|c| *c == 0)
+                .any(|ext| ext == name.as_bytes()),
+            None => cpu
+                .property("riscv,isa")
+                .and_then(|isa| isa.as_str())
+                .is_some_and(|isa| isa.split('_').skip(1).any(|ext| ext == name)),
+        };
+        if !has {
+            return false;
+        }
+        found = true;
+    }
+    found
+}
+
+/// Get the size of the device tree binary.
+pub fn dtb_size() -> usize {
+    get_fdt().map_or(0, |fdt| fdt.total_size())
+}
+
+/// Get the kernel command line in the `/chosen` node.
+pub fn bootargs() -> Option<&'static str> {
+    get_fdt()?.chosen().bootargs()
+}
+
+/// Get the physical range of the initrd in the `/chosen` node.
+pub fn initrd() -> Option<Range<PhysAddr>> {
+    let fdt = get_fdt()?;
+    let chosen = fdt.find_node("/chosen")?;
+    let start = chosen.property("linux,initrd-start")?.as_usize()?;
+    let end = chosen.property("linux,initrd-end")?.as_usize()?;
+    Some(PhysAddr::new(start)..PhysAddr::new(end))
+}
+
+/// Call `f` with the start address and size of every memory region.
+pub fn for_each_memory(mut f: impl FnMut(PhysAddr, usize)) {
+    if let Some(fdt) = get_fdt() {
+        fdt.memory().regions().for_each(|mr| {
+            f(
+                PhysAddr::new(mr.starting_address as _),
+                mr.size.unwrap_or(0),
+            )
+        });
+    }
+}
+
+/// Call `f` with the start address and size of every reserved memory region.
+///
+/// Includes the memory reservation block and the `/reserved-memory` node.
+pub fn for_each_reserved_memory(mut f: impl FnMut(PhysAddr, usize)) {
+    let Some(fdt) = get_fdt() else {
+        return;
+    };
+    fdt.memory_reservations()
+        .for_each(|m| f(PhysAddr::new(m.address() as _), m.size()));
+    if let Some(reserved) = fdt.find_node("/reserved-memory") {
+        reserved
+            .children()
+            .filter_map(|node| node.reg())
+            .flatten()
+            .for_each(|mr| {
+                f(
+                    PhysAddr::new(mr.starting_address as _),
+                    mr.size.unwrap_or(0),
+                )
+            });
+    }
+}
+
+/// Call `f` with the start address and size of the registers of every device.
+pub fn for_each_mmio(mut f: impl FnMut(PhysAddr, usize)) {
+    let Some(fdt) = get_fdt() else {
+        return;
+    };
+    fdt.all_nodes()
+        .filter(|node| node.compatible().is_some())
+        .filter(|node| !node.name.starts_with("memory") && !node.name.starts_with("cpu"))
+        .filter_map(|node| node.reg())
+        .flatten()
+        .for_each(|mr| {
+            if let Some(size) = mr.size {
+                f(PhysAddr::new(mr.starting_address as _), size)
+            }
+        });
+}
@@ -0,0 +1,183 @@
+//! Address space identifiers.
+//!
+//! [VSpace::switch] gives every page table an ASID, so the TLB entries of
+//! the other page tables are kept across the switch. The ASIDs are allocated
+//! in generations, all of them are reclaimed when they run out and every cpu
+//! flushes the whole TLB when it switches in the new generation.
+//!
+//! A cpu may keep the entries of an ASID after switching away, so every ASID
+//! records the cpus whose entries are up to date. [TlbBatch] removes the cpus
+//! which don't use the page table, they flush the ASID when they switch to it
+//! again. ASID 0 isn't allocated, it is used if the cpu has no ASIDs.
+//!
+//! [TlbBatch]: crate::TlbBatch
+
+use spin::Mutex;
+
+use crate::{
+    VSpace,
+    shootdown::{MAX_CPU_ID, cpu_id, current_root},
+};
+
+/// The maximum number of the ASIDs, the same as the x86_64 PCIDs.
+const MAX_ASIDS: usize = 1 << 12;
+
+/// The TLB entries to flush after switching the page table.
+pub(crate) enum AsidFlush {
+    /// The entries of the ASID are up to date.
+    None,
+    /// The entries of the ASID may be stale.
+    Asid,
+    /// The entries of the previous generation may be stale.
+    All,
+}
+
+/// The allocated ASIDs of the current generation.
+struct AsidTable {
+    /// The number of the ASIDs supported by the cpu, 0 if it isn't read yet.
+    limit: usize,
+    generation: usize,
+    /// The number of the allocated ASIDs.
+    used: usize,
+    /// The root of the page table owning every ASID, 0 if it is free.
+    roots: [usize; MAX_ASIDS],
+    /// The cpus whose entries of every ASID are up to date.
+    synced: [u64; MAX_ASIDS],
+    /// The generation which every cpu has flushed.
+    cpu_generation: [usize; MAX_CPU_ID],
+}
+
+static TABLE: Mutex<AsidTable> = Mutex::new(AsidTable {
+    limit: 0,
+    generation: 1,
+    used: 0,
+    roots: [0; MAX_ASIDS],
+    synced: [0; MAX_ASIDS],
+    cpu_generation: [0; MAX_CPU_ID],
+});
+
+impl AsidTable {
+    /// Find the ASID of the page table `root`.
+    ///
+    /// Return the free ASID for it if it isn't found, 0 if all are used.
+    fn find(&self, root: usize) -> Result<usize, usize> {
+        let count = self.limit - 1;
+        let start = (root / VSpace::PAGE_SIZE) % count;
+        for i in 0..count {
+            let asid = 1 + (start + i) % count;
+            match self.roots[asid] {
+                0 => return Err(asid),
+                owner if owner == root => return Ok(asid),
+                _ => {}
+            }
+        }
+        Err(0)
+    }
+
+    /// Allocate an ASID for `root`, start a new generation if most are used.
+    fn alloc(&mut self, root: usize) -> usize {
+        let free = match self.find(root) {
+            Ok(asid) => return asid,
+            // Keep some ASIDs free to make the search short.
+            Err(free) if free != 0 && self.used * 4 < (self.limit - 1) * 3 => free,
+            Err(_) => {
+                self.generation += 1;
+                self.used = 0;
+                self.roots.fill(0);
+                self.synced.fill(0);
+                self.find(root).unwrap_err()
+            }
+        };
+        self.roots[free] = root;
+        self.used += 1;
+        free
+    }
+
+    /// Get the ASID used by the current cpu and the bit of the cpu.
+    fn current(&self) -> Option<(usize, u64)> {
+        let cpu_id = cpu_id();
+        let asid = self.find(current_root()).ok()?;
+        (cpu_id < MAX_CPU_ID).then_some((asid, 1 << cpu_id))
+    }
+
+    /// Remove the cpus which may have stale entries from the synced mask of
+    /// the ASID, `current` is kept since it has been flushed.
+    fn unsync(&mut self, asid: usize, current: Option<(usize, u64)>) {
+        self.synced[asid] &= match current {
+            Some((used, bit)) if used == asid => bit,
+            _ => 0,
+        };
+    }
+}
+
+/// Mark `vspace` used by the current cpu and get its ASID.
+///
+/// Return the ASID and the entries to flush after switching to it.
+pub(crate) fn activate(vspace: &VSpace) -> (usize, AsidFlush) {
+    let mut table = TABLE.lock();
+    crate::shootdown::set_active(vspace);
+    if table.limit == 0 {
+        table.limit = VSpace::asid_limit().min(MAX_ASIDS);
+    }
+    if table.limit <= 1 {
+        return (0, AsidFlush::All);
+    }
+    let asid = table.alloc(vspace.0.raw());
+    let generation = table.generation;
+    let cpu_id = cpu_id();
+    // The cpus which aren't tracked always flush the whole TLB.
+    let Some(cpu_generation) = table.cpu_generation.get_mut(cpu_id) else {
+        return (asid, AsidFlush::All);
+    };
+    let flush = match *cpu_generation == generation {
+        false => {
+            *cpu_generation = generation;
+            AsidFlush::All
+        }
+        true if table.synced[asid] & (1 << cpu_id) == 0 => AsidFlush::Asid,
+        true => AsidFlush::None,
+    };
+    table.synced[asid] |= 1 << cpu_id;
+    (asid, flush)
+}
+
+/// Get the ASID of `vspace` in the current generation.
+pub(crate) fn asid_of(vspace: &VSpace) -> Option<usize> {
+    let table = TABLE.lock();
+    match table.limit {
+        0 | 1 => None,
+        _ => table.find(vspace.0.raw()).ok(),
+    }
+}
+
+/// The entries of `vspace` were changed and flushed on the current cpu,
+/// the other cpus should flush its ASID before using it again.
+///
+/// The entries above the user space are in all ASIDs if `global` is true.
+pub(crate) fn invalidate(vspace: &VSpace, global: bool) {
+    let mut table = TABLE.lock();
+    if table.limit <= 1 {
+        return;
+    }
+    let current = table.current();
+    match global {
+        true => (1..table.limit).for_each(|asid| table.unsync(asid, current)),
+        false => {
+            if let Ok(asid) = table.find(vspace.0.raw()) {
+                table.unsync(asid, current);
+            }
+        }
+    }
+}
+
+/// All the entries of `vspace` were changed without flushing, all cpus
+/// should flush its ASID before using it again.
+pub(crate) fn reset(vspace: &VSpace) {
+    let mut table = TABLE.lock();
+    if table.limit <= 1 {
+        return;
+    }
+    if let Ok(asid) = table.find(vspace.0.raw()) {
+        table.unsync(asid, None);
+    }
+}
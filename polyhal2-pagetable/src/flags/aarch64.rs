@@ -0,0 +1,117 @@
+use polyhal2_core::bit;
+
+use crate::{MappingFlags, MemoryType};
+
+bitflags::bitflags! {
+    /// Possible flags for a page table entry.
+    pub struct PTEFlags: usize {
+        // Attribute fields in stage 1 VMSAv8-64 Block and Page descriptors:
+        /// Whether the descriptor is valid.
+        const VALID =       bit!(0);
+        /// The descriptor gives the address of the next level of translation table or 4KB page.
+        /// (not a 2M, 1G block)
+        const NON_BLOCK =   bit!(1);
+        /// Memory attributes index field, the index 0 is the device memory.
+        const ATTR_INDX =   0b111 << 2;
+        /// Memory attributes index 1, the normal cacheable memory.
+        const NORMAL =      0b001 << 2;
+        /// Memory attributes index 2, the normal non-cacheable memory.
+        const NORMAL_NONCACHE = 0b010 << 2;
+        /// Non-secure bit. For memory accesses from Secure state, specifies whether the output
+        /// address is in Secure or Non-secure memory.
+        const NS =          bit!(5);
+        /// Access permission: accessable at EL0.
+        const AP_EL0 =      bit!(6);
+        /// Access permission: read-only.
+        const AP_RO =       bit!(7);
+        /// Shareability: Inner Shareable (otherwise Outer Shareable).
+        const INNER =       bit!(8);
+        /// Shareability: Inner or Outer Shareable (otherwise Non-shareable).
+        const SHAREABLE =   bit!(9);
+        /// The Access flag.
+        const AF =          bit!(10);
+        /// The not global bit.
+        const NG =          bit!(11);
+        /// Indicates that 16 adjacent translation table entries point to contiguous memory regions.
+        const CONTIGUOUS =  bit!(52);
+        /// The Privileged execute-never field.
+        const PXN =         bit!(53);
+        /// The Execute-never or Unprivileged execute-never field.
+        const UXN =         bit!(54);
+
+        // Next-level attributes in stage 1 VMSAv8-64 Table descriptors:
+
+        /// PXN limit for subsequent levels of lookup.
+        const PXN_TABLE =           bit!(59);
+        /// XN limit for subsequent levels of lookup.
+        const XN_TABLE =            bit!(60);
+        /// Access permissions limit for subsequent levels of lookup: access at EL0 not permitted.
+        const AP_NO_EL0_TABLE =     bit!(61);
+        /// Access permissions limit for subsequent levels of lookup: write access not permitted.
+        const AP_NO_WRITE_TABLE =   bit!(62);
+        /// For memory accesses from Secure state, specifies the Security state for subsequent
+        /// levels of lookup.
+        const NS_TABLE =            bit!(63);
+    }
+}
+
+impl From<MappingFlags> for PTEFlags {
+    fn from(value: MappingFlags) -> Self {
+        let mut flags = PTEFlags::VALID | PTEFlags::NON_BLOCK | PTEFlags::AF;
+        if !value.contains(MappingFlags::W) {
+            flags |= PTEFlags::AP_RO;
+        }
+
+        if !value.contains(MappingFlags::X) {
+            flags |= PTEFlags::UXN | PTEFlags::PXN;
+        }
+
+        if value.contains(MappingFlags::U) {
+            flags |= PTEFlags::AP_EL0;
+        }
+        if !value.contains(MappingFlags::G) {
+            flags |= PTEFlags::NG
+        }
+        flags |= match value.memory_type() {
+            MemoryType::Device => PTEFlags::empty(),
+            MemoryType::WriteCombine => {
+                PTEFlags::NORMAL_NONCACHE | PTEFlags::INNER | PTEFlags::SHAREABLE
+            }
+            MemoryType::Normal => PTEFlags::NORMAL | PTEFlags::INNER | PTEFlags::SHAREABLE,
+        };
+        flags
+    }
+}
+
+impl From<PTEFlags> for MappingFlags {
+    fn from(value: PTEFlags) -> Self {
+        if value.is_empty() {
+            return MappingFlags::empty();
+        };
+        // The valid pages are always readable.
+        let mut flags = MappingFlags::R;
+
+        if !value.contains(PTEFlags::AP_RO) {
+            flags |= MappingFlags::W;
+        }
+        if !value.contains(PTEFlags::UXN) || !value.contains(PTEFlags::PXN) {
+            flags |= MappingFlags::X;
+        }
+        if value.contains(PTEFlags::AP_EL0) {
+            flags |= MappingFlags::U;
+        }
+        if value.contains(PTEFlags::AF) {
+            flags |= MappingFlags::A;
+        }
+        if !value.contains(PTEFlags::NG) {
+            flags |= MappingFlags::G;
+        }
+        // The index of the memory attributes in MAIR_EL1.
+        flags |= match (value.bits() & PTEFlags::ATTR_INDX.bits()) >> 2 {
+            1 => MemoryType::Normal.flags(),
+            2 => MemoryType::WriteCombine.flags(),
+            _ => MemoryType::Device.flags(),
+        };
+        flags
+    }
+}
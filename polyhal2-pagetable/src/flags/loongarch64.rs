@@ -0,0 +1,104 @@
+use polyhal2_core::bit;
+
+use crate::{MappingFlags, MemoryType};
+
+impl From<MappingFlags> for PTEFlags {
+    fn from(value: MappingFlags) -> Self {
+        let mut flags = PTEFlags::V;
+        if value.contains(MappingFlags::W) {
+            flags |= PTEFlags::W | PTEFlags::D;
+        }
+
+        if !value.contains(MappingFlags::R) {
+            flags |= PTEFlags::NR;
+        }
+
+        if !value.contains(MappingFlags::X) {
+            flags |= PTEFlags::NX;
+        }
+
+        if value.contains(MappingFlags::U) {
+            flags |= PTEFlags::PLV_USER;
+        }
+        if value.contains(MappingFlags::G) {
+            flags |= PTEFlags::GH;
+        }
+        flags |= match value.memory_type() {
+            MemoryType::Device => PTEFlags::empty(),
+            MemoryType::WriteCombine => PTEFlags::MAT_WUC,
+            MemoryType::Normal => PTEFlags::MAT_CC,
+        };
+        flags
+    }
+}
+
+/// The flags of the huge page should be converted by `PTE::leaf_flags` first.
+impl From<PTEFlags> for MappingFlags {
+    fn from(val: PTEFlags) -> Self {
+        let mut flags = MappingFlags::empty();
+        if !val.contains(PTEFlags::NR) {
+            flags |= MappingFlags::R;
+        }
+
+        if val.contains(PTEFlags::W) {
+            flags |= MappingFlags::W;
+        }
+
+        if val.contains(PTEFlags::D) {
+            flags |= MappingFlags::D;
+        }
+
+        if !val.contains(PTEFlags::NX) {
+            flags |= MappingFlags::X;
+        }
+
+        if val.contains(PTEFlags::PLV_USER) {
+            flags |= MappingFlags::U;
+        }
+        if val.contains(PTEFlags::GH) {
+            flags |= MappingFlags::G;
+        }
+        flags |= match (val.bits() & PTEFlags::MAT.bits()) >> 4 {
+            1 => MemoryType::Normal.flags(),
+            2 => MemoryType::WriteCombine.flags(),
+            _ => MemoryType::Device.flags(),
+        };
+        flags
+    }
+}
+
+bitflags::bitflags! {
+    /// Possible flags for a page table entry.
+    pub struct PTEFlags: usize {
+        /// Page Valid
+        const V = bit!(0);
+        /// Dirty, The page has been writed.
+        const D = bit!(1);
+
+        const PLV_USER = 0b11 << 2;
+
+        /// Memory access type, 0 is the strongly-ordered uncached memory.
+        const MAT = 0b11 << 4;
+        /// Coherent cached memory.
+        const MAT_CC = 0b01 << 4;
+        /// Weakly-ordered uncached memory.
+        const MAT_WUC = 0b10 << 4;
+
+        /// Global for the page OR Huge for the directory entry.
+        const GH = bit!(6);
+
+        /// Page is existing.
+        const P = bit!(7);
+        /// Page is writeable.
+        const W = bit!(8);
+        /// Global for the huge page, `ldpte` moves it to the G bit.
+        const HG = bit!(12);
+        /// Page is not readable.
+        const NR = bit!(61);
+        /// Page is not executable.
+        const NX = bit!(62);
+        /// Whether the privilege Level is restricted. When RPLV is 0, the PTE
+        /// can be accessed by any program with privilege Level highter than PLV.
+        const RPLV = bit!(63);
+    }
+}
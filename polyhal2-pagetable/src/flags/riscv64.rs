@@ -0,0 +1,110 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use bitflags::bitflags;
+use polyhal2_core::bit;
+
+use crate::{MappingFlags, MemoryType};
+
+/// The cpus support Svpbmt, the memory type is set in the PBMT bits.
+static SVPBMT: AtomicBool = AtomicBool::new(false);
+
+/// Use the Svpbmt extension to set the memory type of the mappings.
+///
+/// It should be enabled only if all cpus support it.
+pub fn enable_svpbmt() {
+    SVPBMT.store(true, Ordering::Relaxed);
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct PTEFlags: u64 {
+        const V = bit!(0);
+        const R = bit!(1);
+        const W = bit!(2);
+        const X = bit!(3);
+        const U = bit!(4);
+        const G = bit!(5);
+        const A = bit!(6);
+        const D = bit!(7);
+        /// Svpbmt: non-cacheable, idempotent, weakly-ordered memory.
+        const PBMT_NC = bit!(61);
+        /// Svpbmt: non-cacheable, non-idempotent, strongly-ordered I/O memory.
+        const PBMT_IO = bit!(62);
+
+        const VRWX  = Self::V.bits() | Self::R.bits() | Self::W.bits() | Self::X.bits();
+        const ADUVRX = Self::A.bits() | Self::D.bits() | Self::U.bits() | Self::V.bits() | Self::R.bits() | Self::X.bits();
+        const ADVRWX = Self::A.bits() | Self::D.bits() | Self::VRWX.bits();
+        const ADGVRWX = Self::G.bits() | Self::ADVRWX.bits();
+    }
+}
+
+impl From<MappingFlags> for PTEFlags {
+    fn from(flags: MappingFlags) -> Self {
+        if flags.is_empty() {
+            Self::empty()
+        } else {
+            let mut res = Self::V;
+            if flags.contains(MappingFlags::R) {
+                res |= PTEFlags::R | PTEFlags::A;
+            }
+            if flags.contains(MappingFlags::W) {
+                res |= PTEFlags::W | PTEFlags::D;
+            }
+            if flags.contains(MappingFlags::X) {
+                res |= PTEFlags::X;
+            }
+            if flags.contains(MappingFlags::U) {
+                res |= PTEFlags::U;
+            }
+            if flags.contains(MappingFlags::G) {
+                res |= PTEFlags::G;
+            }
+            // The memory type is decided by the PMAs without Svpbmt.
+            if SVPBMT.load(Ordering::Relaxed) {
+                res |= match flags.memory_type() {
+                    MemoryType::Device => PTEFlags::PBMT_IO,
+                    MemoryType::WriteCombine => PTEFlags::PBMT_NC,
+                    MemoryType::Normal => PTEFlags::empty(),
+                };
+            }
+            res
+        }
+    }
+}
+
+impl From<PTEFlags> for MappingFlags {
+    fn from(value: PTEFlags) -> Self {
+        let mut mapping_flags = MappingFlags::empty();
+        if value.contains(PTEFlags::V) {
+            mapping_flags |= MappingFlags::P;
+        }
+        if value.contains(PTEFlags::R) {
+            mapping_flags |= MappingFlags::R;
+        }
+        if value.contains(PTEFlags::W) {
+            mapping_flags |= MappingFlags::W;
+        }
+        if value.contains(PTEFlags::X) {
+            mapping_flags |= MappingFlags::X;
+        }
+        if value.contains(PTEFlags::U) {
+            mapping_flags |= MappingFlags::U;
+        }
+        if value.contains(PTEFlags::G) {
+            mapping_flags |= MappingFlags::G;
+        }
+        if value.contains(PTEFlags::A) {
+            mapping_flags |= MappingFlags::A;
+        }
+        if value.contains(PTEFlags::D) {
+            mapping_flags |= MappingFlags::D;
+        }
+        mapping_flags |= match value.intersection(PTEFlags::PBMT_NC | PTEFlags::PBMT_IO) {
+            PTEFlags::PBMT_IO => MemoryType::Device.flags(),
+            PTEFlags::PBMT_NC => MemoryType::WriteCombine.flags(),
+            _ => MemoryType::Normal.flags(),
+        };
+
+        mapping_flags
+    }
+}
@@ -0,0 +1,102 @@
+use bitflags::bitflags;
+use polyhal2_core::bit;
+
+use crate::{MappingFlags, MemoryType};
+
+bitflags! {
+    pub struct PTEFlags: u64 {
+        /// Page is present in the page table
+        const P         = bit!(0);
+        /// Read/Write; if 0, Only read
+        const RW        = bit!(1);
+        /// User/Supervisor; if 0, Only supervisor
+        const US        = bit!(2);
+        /// Page-level wright-through
+        const PWT       = bit!(3);
+        /// Page-level cache disable.
+        const PCD       = bit!(4);
+        /// Accessed; indicates whether software has accessed the 4-KByte page
+        const A         = bit!(5);
+        /// Dirty; indicates whether software has written to the 4-KByte page referenced by this entry.
+        const D         = bit!(6);
+        /// Page size; if set this entry maps a 2-MByte page; otherwise, this entry references a page directory.
+        const PS      = bit!(7);
+        /// Global; if CR4.PGE = 1, determines whether the translation is global (see Section 4.10); ignored otherwise
+        const G         = bit!(8);
+        /// User defined flag -- ignored by hardware (bit 9)
+        const USER_9    = bit!(9);
+        /// User defined flag -- ignored by hardware (bit 10)
+        const USER_10   = bit!(10);
+        /// User defined flag -- ignored by hardware (bit 11)
+        const USER_11   = bit!(11);
+        ///  If IA32_EFER.NXE = 1, execute-disable
+        ///  If 1, instruction fetches are not allowed from the 512-GByte region.
+        const XD        = bit!(63);
+    }
+}
+
+/// The present pages are always readable, `IA32_EFER.NXE` is set by the
+/// boot code to use the XD bit.
+impl From<MappingFlags> for PTEFlags {
+    fn from(flags: MappingFlags) -> Self {
+        let mut res = Self::P;
+        if flags.contains(MappingFlags::W) {
+            res |= Self::RW;
+        }
+        if flags.contains(MappingFlags::U) {
+            res |= Self::US;
+        }
+        if flags.contains(MappingFlags::A) {
+            res |= Self::A;
+        }
+        if flags.contains(MappingFlags::D) {
+            res |= Self::D;
+        }
+        if flags.contains(MappingFlags::G) {
+            res |= Self::G;
+        }
+        if !flags.contains(MappingFlags::X) {
+            res |= Self::XD;
+        }
+        // The PAT entry 1 is write-combining, it is set by the boot code.
+        res |= match flags.memory_type() {
+            MemoryType::Device => Self::PCD | Self::PWT,
+            MemoryType::WriteCombine => Self::PWT,
+            MemoryType::Normal => Self::empty(),
+        };
+        res
+    }
+}
+
+impl From<PTEFlags> for MappingFlags {
+    fn from(value: PTEFlags) -> Self {
+        if !value.contains(PTEFlags::P) {
+            return MappingFlags::empty();
+        }
+        let mut res = MappingFlags::R;
+        if value.contains(PTEFlags::RW) {
+            res |= MappingFlags::W;
+        }
+        if value.contains(PTEFlags::US) {
+            res |= MappingFlags::U;
+        }
+        if value.contains(PTEFlags::A) {
+            res |= MappingFlags::A;
+        }
+        if value.contains(PTEFlags::D) {
+            res |= MappingFlags::D;
+        }
+        if value.contains(PTEFlags::G) {
+            res |= MappingFlags::G;
+        }
+        if !value.contains(PTEFlags::XD) {
+            res |= MappingFlags::X;
+        }
+        res |= match (value.contains(PTEFlags::PCD), value.contains(PTEFlags::PWT)) {
+            (true, _) => MemoryType::Device.flags(),
+            (false, true) => MemoryType::WriteCombine.flags(),
+            (false, false) => MemoryType::Normal.flags(),
+        };
+        res
+    }
+}
@@ -1,16 +1,16 @@
-use aarch64_cpu::registers::{TTBR0_EL1, Writeable};
+use aarch64_cpu::registers::{Readable, TCR_EL1, TTBR0_EL1, Writeable};
 use polyhal2_core::{
     addr::{PhysAddr, VirtAddr},
-    bit,
-    consts::PAGE_SIZE,
+    consts::PAGE_SHIFT,
 };
 
-use crate::{MappingFlags, MappingSize, PTE, TLB, VSpace};
+use crate::{MappingSize, PTE, TLB, VSpace, asid::AsidFlush, flags::PTEFlags};
 
 impl PTE {
+    /// The output address is in the bits 12-47, the upper bits are attributes.
     #[inline]
     pub(crate) const fn paddr(&self) -> PhysAddr {
-        PhysAddr::new(self.0).floor(PAGE_SIZE)
+        PhysAddr::new(self.0 & 0x0000_ffff_ffff_ffff).floor(VSpace::PAGE_SIZE)
     }
 
     #[inline]
@@ -39,143 +39,83 @@ impl PTE {
         Self(paddr.raw() | 0b11)
     }
 
+    /// The flags of the block entry used to create the smaller pages.
+    #[inline]
+    pub(crate) const fn leaf_flags(&self) -> PTEFlags {
+        self.flags().union(PTEFlags::NON_BLOCK)
+    }
+
     /// Create a new PageTableEntry from ppn and flags
+    ///
+    /// The size is checked against [VSpace::max_page_size] by the caller.
     pub(crate) const fn new_page(paddr: PhysAddr, flags: PTEFlags, size: MappingSize) -> Self {
         match size {
             MappingSize::Page4KB => Self(paddr.raw() | flags.bits()),
-            MappingSize::Page1GB => {
-                Self(paddr.raw() | flags.difference(PTEFlags::NON_BLOCK).bits())
-            }
-            MappingSize::Page2MB => {
-                Self(paddr.raw() | flags.difference(PTEFlags::NON_BLOCK).bits())
-            }
+            _ => Self(paddr.raw() | flags.difference(PTEFlags::NON_BLOCK).bits()),
         }
     }
 }
 
-impl From<MappingFlags> for PTEFlags {
-    fn from(value: MappingFlags) -> Self {
-        let mut flags = PTEFlags::VALID | PTEFlags::NON_BLOCK | PTEFlags::AF;
-        if !value.contains(MappingFlags::W) {
-            flags |= PTEFlags::AP_RO;
-        }
-
-        if !value.contains(MappingFlags::X) {
-            flags |= PTEFlags::UXN | PTEFlags::PXN;
-        }
-
-        if value.contains(MappingFlags::U) {
-            flags |= PTEFlags::AP_EL0;
-        }
-        if !value.contains(MappingFlags::G) {
-            flags |= PTEFlags::NG
-        }
-        flags
-    }
-}
-
-impl From<PTEFlags> for MappingFlags {
-    fn from(value: PTEFlags) -> Self {
-        if value.is_empty() {
-            return MappingFlags::empty();
-        };
-        let mut flags = MappingFlags::R;
-
-        if !value.contains(PTEFlags::AP_RO) {
-            flags |= MappingFlags::W;
-        }
-        if !value.contains(PTEFlags::UXN) || !value.contains(PTEFlags::PXN) {
-            flags |= MappingFlags::X;
-        }
-        if value.contains(PTEFlags::AP_EL0) {
-            flags |= MappingFlags::U;
-        }
-        if value.contains(PTEFlags::AF) {
-            flags |= MappingFlags::A;
-        }
-        if !value.contains(PTEFlags::NG) {
-            flags |= MappingFlags::G;
-        }
-        flags
-    }
-}
-
-bitflags::bitflags! {
-    /// Possible flags for a page table entry.
-    pub struct PTEFlags: usize {
-        // Attribute fields in stage 1 VMSAv8-64 Block and Page descriptors:
-        /// Whether the descriptor is valid.
-        const VALID =       bit!(0);
-        /// The descriptor gives the address of the next level of translation table or 4KB page.
-        /// (not a 2M, 1G block)
-        const NON_BLOCK =   bit!(1);
-        /// Memory attributes index field.
-        const ATTR_INDX =   0b111 << 2;
-        ///
-        const NORMAL_NONCACHE = 0b010 << 2;
-        /// Non-secure bit. For memory accesses from Secure state, specifies whether the output
-        /// address is in Secure or Non-secure memory.
-        const NS =          bit!(5);
-        /// Access permission: accessable at EL0.
-        const AP_EL0 =      bit!(6);
-        /// Access permission: read-only.
-        const AP_RO =       bit!(7);
-        /// Shareability: Inner Shareable (otherwise Outer Shareable).
-        const INNER =       bit!(8);
-        /// Shareability: Inner or Outer Shareable (otherwise Non-shareable).
-        const SHAREABLE =   bit!(9);
-        /// The Access flag.
-        const AF =          bit!(10);
-        /// The not global bit.
-        const NG =          bit!(11);
-        /// Indicates that 16 adjacent translation table entries point to contiguous memory regions.
-        const CONTIGUOUS =  bit!(52);
-        /// The Privileged execute-never field.
-        const PXN =         bit!(53);
-        /// The Execute-never or Unprivileged execute-never field.
-        const UXN =         bit!(54);
-
-        // Next-level attributes in stage 1 VMSAv8-64 Table descriptors:
-
-        /// PXN limit for subsequent levels of lookup.
-        const PXN_TABLE =           bit!(59);
-        /// XN limit for subsequent levels of lookup.
-        const XN_TABLE =            bit!(60);
-        /// Access permissions limit for subsequent levels of lookup: access at EL0 not permitted.
-        const AP_NO_EL0_TABLE =     bit!(61);
-        /// Access permissions limit for subsequent levels of lookup: write access not permitted.
-        const AP_NO_WRITE_TABLE =   bit!(62);
-        /// For memory accesses from Secure state, specifies the Security state for subsequent
-        /// levels of lookup.
-        const NS_TABLE =            bit!(63);
-    }
-}
-
 impl VSpace {
     /// The size of the page for this platform.
-    pub const PAGE_SIZE: usize = 0x1000;
-    /// The stages of the address translation
-    pub const PAGE_LEVEL: usize = 3;
-    pub(crate) const PTE_NUM_IN_PAGE: usize = 0x200;
-    pub(crate) const GLOBAL_ROOT_PTE_RANGE: usize = 0x200;
+    pub const PAGE_SIZE: usize = polyhal2_core::consts::PAGE_SIZE;
+    /// The stages of the address translation, it covers [VSpace::VA_BITS].
+    pub const PAGE_LEVEL: usize = (Self::VA_BITS - PAGE_SHIFT).div_ceil(PAGE_SHIFT - 3);
+    /// The bits of the virtual address translated by the page table.
+    ///
+    /// They are 39 bits with 4K pages and 47 bits with 16K pages, or 48 bits
+    /// with the `level4` feature. They are always 48 bits with 64K pages.
+    pub const VA_BITS: usize = match (PAGE_SHIFT, cfg!(feature = "level4")) {
+        (16, _) | (_, true) => 48,
+        (shift, false) => shift + (shift - 3) * 3,
+    };
+    pub(crate) const PTE_NUM_IN_PAGE: usize = Self::PAGE_SIZE / size_of::<PTE>();
+    /// The block can only be replaced by a table after it is invalidated.
+    pub(crate) const BREAK_BEFORE_MAKE: bool = true;
+    pub(crate) const GLOBAL_ROOT_PTE_RANGE: usize = Self::PTE_NUM_IN_PAGE;
+
+    /// The largest page used by [VSpace::map_region], the blocks in the
+    /// third level from the bottom are only supported with 4K pages.
+    pub(crate) const fn max_page_size() -> MappingSize {
+        match Self::PAGE_SIZE {
+            0x1000 => MappingSize::Page1GB,
+            _ => MappingSize::Page2MB,
+        }
+    }
 
-    /// Create a new VirtualSpace with given physical address.
-    #[inline]
-    pub const fn from_paddr(paddr: PhysAddr) -> VSpace {
-        Self(paddr)
+    /// The kernel lives in `TTBR1_EL1`, the user space has no kernel entries.
+    pub(crate) const fn kernel_root() -> Option<PhysAddr> {
+        None
     }
 
     /// Get the using PageTable currently.
     #[inline]
     pub fn current() -> Self {
-        Self(PhysAddr::new(TTBR0_EL1.get_baddr() as _))
+        Self::from_paddr(PhysAddr::new(TTBR0_EL1.get_baddr() as _))
     }
 
     /// Change the pagetable to Virtual space.
+    ///
+    /// Only `TTBR0_EL1` is changed, the kernel stays in `TTBR1_EL1`.
+    /// The ASID is written to `TTBR0_EL1.ASID`, its entries are flushed only
+    /// if they may be stale.
     #[inline]
     pub fn switch(&self) {
-        TTBR0_EL1.set(self.0.floor(Self::PAGE_SIZE).raw() as _);
-        TLB::flush_all();
+        let (asid, flush) = crate::asid::activate(self);
+        TTBR0_EL1.set(((asid as u64) << 48) | self.0.floor(Self::PAGE_SIZE).raw() as u64);
+        match flush {
+            AsidFlush::None => unsafe { core::arch::asm!("isb") },
+            AsidFlush::Asid => TLB::flush_asid(asid),
+            AsidFlush::All => TLB::flush_all(),
+        }
+    }
+
+    /// Get the number of the ASIDs, `TCR_EL1.AS` selects 16-bit ASIDs.
+    pub(crate) fn asid_limit() -> usize {
+        match TCR_EL1.is_set(TCR_EL1::AS) {
+            true => 1 << 16,
+            false => 1 << 8,
+        }
     }
 }
 
@@ -199,6 +139,19 @@ impl TLB {
         }
     }
 
+    /// Flush the TLB entries of the ASID on all cpus, the global entries are kept.
+    #[inline]
+    pub fn flush_asid(asid: usize) {
+        unsafe { core::arch::asm!("tlbi aside1is, {}; dsb sy; isb", in(reg) asid << 48) }
+    }
+
+    /// Flush the TLB entry of the virtual address in the ASID on all cpus.
+    #[inline]
+    pub fn flush_vaddr_asid(vaddr: VirtAddr, asid: usize) {
+        let arg = (asid << 48) | ((vaddr.raw() >> 12) & 0xFFFF_FFFF_FFFF);
+        unsafe { core::arch::asm!("tlbi vae1is, {}; dsb sy; isb", in(reg) arg) }
+    }
+
     /// flush all tlb entry
     ///
     /// TLB::flush_all();
@@ -206,16 +159,11 @@ impl TLB {
     pub fn flush_all() {
         unsafe { core::arch::asm!("tlbi vmalle1; dsb sy; isb") }
     }
-}
-
-/// Get n level page table index of the given virtual address
-#[inline]
-pub const fn pg_index(vaddr: VirtAddr, n: usize) -> usize {
-    (vaddr.raw() >> (12 + 9 * n)) & 0x1ff
-}
 
-/// Get n level page table offset of the given virtual address
-#[inline]
-pub const fn pg_offest(vaddr: VirtAddr, n: usize) -> usize {
-    vaddr.raw() % (1 << (12 + 9 * n))
+    /// Flush all tlb entries including the global entries, they are
+    /// flushed by [TLB::flush_all] on this platform.
+    #[inline]
+    pub fn flush_all_global() {
+        Self::flush_all()
+    }
 }
@@ -1,11 +1,10 @@
-use loongArch64::register::pgdl;
+use loongArch64::register::{asid, pgdl};
 use polyhal2_core::{
     addr::{PhysAddr, VirtAddr},
-    bit,
-    consts::PAGE_SIZE,
+    consts::PAGE_SHIFT,
 };
 
-use crate::{MappingFlags, MappingSize, PTE, TLB, VSpace};
+use crate::{MappingSize, PTE, TLB, VSpace, asid::AsidFlush, flags::PTEFlags};
 
 impl PTE {
     #[inline]
@@ -18,14 +17,28 @@ impl PTE {
         PTEFlags::from_bits_truncate(self.0)
     }
 
+    /// The physical address is in the bits 12-47, the upper bits are NR, NX and RPLV.
     #[inline]
     pub(crate) const fn paddr(&self) -> PhysAddr {
-        PhysAddr::new(self.0).floor(PAGE_SIZE)
+        PhysAddr::new(self.0 & 0x0000_ffff_ffff_f000)
     }
 
+    /// The flags of the huge page used to create the smaller pages.
+    ///
+    /// The global bit of the huge page is moved to the G bit.
+    #[inline]
+    pub(crate) const fn leaf_flags(&self) -> PTEFlags {
+        let flags = self.flags().difference(PTEFlags::GH.union(PTEFlags::HG));
+        match self.flags().contains(PTEFlags::HG) {
+            true => flags.union(PTEFlags::GH),
+            false => flags,
+        }
+    }
+
+    /// The huge page is a leaf entry in the directory with the GH bit.
     #[inline]
     pub(crate) const fn is_table(&self) -> bool {
-        self.0 != 0
+        self.0 != 0 && !self.flags().contains(PTEFlags::GH)
     }
 
     #[inline]
@@ -33,107 +46,70 @@ impl PTE {
         Self(paddr.raw())
     }
 
+    /// Create a leaf entry, the huge page sets the GH bit in the directory entry.
+    ///
+    /// `lddir` stops at the huge entry and `ldpte` fills the TLB with the
+    /// half of the huge page. The global bit of the huge page is HG.
     #[inline]
     pub(crate) const fn new_page(paddr: PhysAddr, flags: PTEFlags, size: MappingSize) -> Self {
-        match size {
-            MappingSize::Page4KB => Self(paddr.raw() | flags.bits()),
-            MappingSize::Page2MB | MappingSize::Page1GB => panic!("Unsupported page size"),
-        }
-    }
-}
-
-impl From<MappingFlags> for PTEFlags {
-    fn from(value: MappingFlags) -> Self {
-        let mut flags = PTEFlags::V;
-        if value.contains(MappingFlags::W) {
-            flags |= PTEFlags::W | PTEFlags::D;
-        }
-
-        // if !value.contains(MappingFlags::X) {
-        //     flags |= PTEFlags::NX;
-        // }
-
-        if value.contains(MappingFlags::U) {
-            flags |= PTEFlags::PLV_USER;
-        }
-        flags
-    }
-}
-
-impl From<PTEFlags> for MappingFlags {
-    fn from(val: PTEFlags) -> Self {
-        let mut flags = MappingFlags::empty();
-        if val.contains(PTEFlags::W) {
-            flags |= MappingFlags::W;
+        match (size, flags.contains(PTEFlags::GH)) {
+            (MappingSize::Page4KB, _) => Self(paddr.raw() | flags.bits()),
+            (_, true) => Self(paddr.raw() | flags.union(PTEFlags::HG).bits()),
+            (_, false) => Self(paddr.raw() | flags.union(PTEFlags::GH).bits()),
         }
-
-        if val.contains(PTEFlags::D) {
-            flags |= MappingFlags::D;
-        }
-
-        // if !self.contains(PTEFlags::NX) {
-        //     flags |= MappingFlags::X;
-        // }
-
-        if val.contains(PTEFlags::PLV_USER) {
-            flags |= MappingFlags::U;
-        }
-        flags
-    }
-}
-
-bitflags::bitflags! {
-    /// Possible flags for a page table entry.
-    pub struct PTEFlags: usize {
-        /// Page Valid
-        const V = bit!(0);
-        /// Dirty, The page has been writed.
-        const D = bit!(1);
-
-        const PLV_USER = 0b11 << 2;
-
-        const MAT_NOCACHE = 0b01 << 4;
-
-        /// Designates a global mapping OR Whether the page is huge page.
-        const GH = bit!(6);
-
-        /// Page is existing.
-        const P = bit!(7);
-        /// Page is writeable.
-        const W = bit!(8);
-        /// Is a Global Page if using huge page(GH bit).
-        const G = bit!(10);
-        /// Page is not readable.
-        const NR = bit!(11);
-        /// Page is not executable.
-        /// FIXME: Is it just for a huge page?
-        /// Linux related url: https://github.com/torvalds/linux/blob/master/arch/loongarch/include/asm/pgtable-bits.h
-        const NX = bit!(12);
-        /// Whether the privilege Level is restricted. When RPLV is 0, the PTE
-        /// can be accessed by any program with privilege Level highter than PLV.
-        const RPLV = bit!(63);
     }
 }
 
 impl VSpace {
     /// The size of the page for this platform.
-    pub const PAGE_SIZE: usize = 0x1000;
-    /// The stages of the address translation
+    pub const PAGE_SIZE: usize = polyhal2_core::consts::PAGE_SIZE;
+    /// The stages of the address translation, the `level4` and `level5`
+    /// features aren't supported yet.
     pub const PAGE_LEVEL: usize = 3;
-    pub(crate) const PTE_NUM_IN_PAGE: usize = 0x200;
-    pub(crate) const GLOBAL_ROOT_PTE_RANGE: usize = 0x100;
+    /// The bits of the virtual address translated by the page table, 39 bits
+    /// with 4K pages and 47 bits with 16K pages.
+    pub const VA_BITS: usize = PAGE_SHIFT + (PAGE_SHIFT - 3) * Self::PAGE_LEVEL;
+    pub(crate) const PTE_NUM_IN_PAGE: usize = Self::PAGE_SIZE / size_of::<PTE>();
+    /// The huge page can be replaced by a table directly.
+    pub(crate) const BREAK_BEFORE_MAKE: bool = false;
+    pub(crate) const GLOBAL_ROOT_PTE_RANGE: usize = Self::PTE_NUM_IN_PAGE / 2;
+
+    /// The largest page used by [VSpace::map_region].
+    pub(crate) const fn max_page_size() -> MappingSize {
+        MappingSize::Page1GB
+    }
+
+    /// The higher half is translated through `PGDH` and the direct mapping
+    /// windows, the user space has no kernel entries.
+    pub(crate) const fn kernel_root() -> Option<PhysAddr> {
+        None
+    }
 
     /// Get the using PageTable currently.
     #[inline]
     pub fn current() -> Self {
-        Self(PhysAddr::new(pgdl::read().base()))
+        Self::from_paddr(PhysAddr::new(pgdl::read().base()))
     }
 
     /// Change the pagetable to Virtual space.
+    ///
+    /// The ASID is written to `ASID.ASID`, its entries are flushed only if
+    /// they may be stale.
     #[inline]
     pub fn switch(&self) {
+        let (id, flush) = crate::asid::activate(self);
+        asid::set_asid(id);
         pgdl::set_base(self.0.floor(Self::PAGE_SIZE).raw());
-        TLB::flush_all();
+        match flush {
+            AsidFlush::None => {}
+            AsidFlush::Asid => TLB::flush_asid(id),
+            AsidFlush::All => TLB::flush_all(),
+        }
+    }
+
+    /// Get the number of the ASIDs from `ASID.ASIDBITS`.
+    pub(crate) fn asid_limit() -> usize {
+        1 << asid::read().asid_width()
     }
 }
 
@@ -145,8 +121,33 @@ impl TLB {
     /// TLB::flush_vaddr(arg0); // arg0 is the virtual address(VirtAddr)
     #[inline]
     pub fn flush_vaddr(vaddr: VirtAddr) {
+        // The global entries and the entries of the current ASID.
+        unsafe {
+            core::arch::asm!(
+                "dbar 0; invtlb 0x06, {asid}, {reg}",
+                asid = in(reg) asid::read().asid(),
+                reg = in(reg) vaddr.raw()
+            );
+        }
+    }
+
+    /// Flush the TLB entries of the ASID, the global entries are kept.
+    #[inline]
+    pub fn flush_asid(asid: usize) {
+        unsafe {
+            core::arch::asm!("dbar 0; invtlb 0x04, {asid}, $r0", asid = in(reg) asid);
+        }
+    }
+
+    /// Flush the TLB entry of the virtual address in the ASID.
+    #[inline]
+    pub fn flush_vaddr_asid(vaddr: VirtAddr, asid: usize) {
         unsafe {
-            core::arch::asm!("dbar 0; invtlb 0x05, $r0, {reg}", reg = in(reg) vaddr.raw());
+            core::arch::asm!(
+                "dbar 0; invtlb 0x05, {asid}, {reg}",
+                asid = in(reg) asid,
+                reg = in(reg) vaddr.raw()
+            );
         }
     }
 
@@ -161,16 +162,11 @@ impl TLB {
             core::arch::asm!("dbar 0; invtlb 0x00, $r0, $r0");
         }
     }
-}
-
-/// Get n level page table index of the given virtual address
-#[inline]
-pub const fn pg_index(vaddr: VirtAddr, n: usize) -> usize {
-    (vaddr.raw() >> (12 + 9 * n)) & 0x1ff
-}
 
-/// Get n level page table offset of the given virtual address
-#[inline]
-pub const fn pg_offest(vaddr: VirtAddr, n: usize) -> usize {
-    vaddr.raw() % (1 << (12 + 9 * n))
+    /// Flush all tlb entries including the global entries, they are
+    /// flushed by [TLB::flush_all] on this platform.
+    #[inline]
+    pub fn flush_all_global() {
+        Self::flush_all()
+    }
 }
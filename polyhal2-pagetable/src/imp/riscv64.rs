@@ -1,16 +1,12 @@
-use bitflags::bitflags;
-use polyhal2_core::{
-    addr::{PhysAddr, VirtAddr},
-    bit,
-};
+use polyhal2_core::addr::{PhysAddr, VirtAddr};
 use riscv::{asm::sfence_vma, register::satp};
 
-use crate::{MappingFlags, MappingSize, PTE, TLB, VSpace};
+use crate::{MappingSize, PTE, TLB, VSpace, asid::AsidFlush, flags::PTEFlags};
 
 impl PTE {
     #[inline]
     pub const fn flags(&self) -> PTEFlags {
-        PTEFlags::from_bits_truncate((self.0 & 0xff) as u64)
+        PTEFlags::from_bits_truncate(self.0 as u64)
     }
 
     #[inline]
@@ -36,103 +32,74 @@ impl PTE {
         Self((paddr.raw() >> 2) | flags.bits() as usize)
     }
 
+    /// The flags of the huge page used to create the smaller pages.
     #[inline]
-    pub(crate) const fn paddr(&self) -> PhysAddr {
-        PhysAddr::new(self.0 << 2).floor(VSpace::PAGE_SIZE)
+    pub(crate) const fn leaf_flags(&self) -> PTEFlags {
+        self.flags()
     }
-}
 
-bitflags! {
-    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-    pub struct PTEFlags: u64 {
-        const V = bit!(0);
-        const R = bit!(1);
-        const W = bit!(2);
-        const X = bit!(3);
-        const U = bit!(4);
-        const G = bit!(5);
-        const A = bit!(6);
-        const D = bit!(7);
-
-        const VRWX  = Self::V.bits() | Self::R.bits() | Self::W.bits() | Self::X.bits();
-        const ADUVRX = Self::A.bits() | Self::D.bits() | Self::U.bits() | Self::V.bits() | Self::R.bits() | Self::X.bits();
-        const ADVRWX = Self::A.bits() | Self::D.bits() | Self::VRWX.bits();
-        const ADGVRWX = Self::G.bits() | Self::ADVRWX.bits();
+    /// The physical page number is in the bits 10-53.
+    #[inline]
+    pub(crate) const fn paddr(&self) -> PhysAddr {
+        PhysAddr::new(((self.0 >> 10) & 0xfff_ffff_ffff) << 12)
     }
 }
 
-impl From<MappingFlags> for PTEFlags {
-    fn from(flags: MappingFlags) -> Self {
-        if flags.is_empty() {
-            Self::empty()
-        } else {
-            let mut res = Self::V;
-            if flags.contains(MappingFlags::R) {
-                res |= PTEFlags::R | PTEFlags::A;
-            }
-            if flags.contains(MappingFlags::W) {
-                res |= PTEFlags::W | PTEFlags::D;
-            }
-            if flags.contains(MappingFlags::X) {
-                res |= PTEFlags::X;
-            }
-            if flags.contains(MappingFlags::U) {
-                res |= PTEFlags::U;
-            }
-            res
-        }
+impl VSpace {
+    /// The size of the page for this platform.
+    pub const PAGE_SIZE: usize = polyhal2_core::consts::PAGE_SIZE;
+    /// The stages of the address translation, Sv39 by default, Sv48 with
+    /// the `level4` feature and Sv57 with the `level5` feature.
+    pub const PAGE_LEVEL: usize =
+        3 + cfg!(feature = "level4") as usize + cfg!(feature = "level5") as usize;
+    /// The bits of the virtual address translated by the page table.
+    pub const VA_BITS: usize = 12 + 9 * Self::PAGE_LEVEL;
+    /// The mode of SATP, 8 is Sv39, 9 is Sv48 and 10 is Sv57.
+    pub const SATP_MODE: usize = Self::PAGE_LEVEL + 5;
+    pub(crate) const PTE_NUM_IN_PAGE: usize = Self::PAGE_SIZE / size_of::<PTE>();
+    /// The huge page can be replaced by a table directly.
+    pub(crate) const BREAK_BEFORE_MAKE: bool = false;
+    pub(crate) const GLOBAL_ROOT_PTE_RANGE: usize = Self::PTE_NUM_IN_PAGE / 2;
+
+    /// The largest page used by [VSpace::map_region].
+    pub(crate) const fn max_page_size() -> MappingSize {
+        MappingSize::Page1GB
     }
-}
-
-impl From<PTEFlags> for MappingFlags {
-    fn from(value: PTEFlags) -> Self {
-        let mut mapping_flags = MappingFlags::empty();
-        if value.contains(PTEFlags::V) {
-            mapping_flags |= MappingFlags::P;
-        }
-        if value.contains(PTEFlags::R) {
-            mapping_flags |= MappingFlags::R;
-        }
-        if value.contains(PTEFlags::W) {
-            mapping_flags |= MappingFlags::W;
-        }
-        if value.contains(PTEFlags::X) {
-            mapping_flags |= MappingFlags::X;
-        }
-        if value.contains(PTEFlags::U) {
-            mapping_flags |= MappingFlags::U;
-        }
-        if value.contains(PTEFlags::A) {
-            mapping_flags |= MappingFlags::A;
-        }
-        if value.contains(PTEFlags::D) {
-            mapping_flags |= MappingFlags::D;
-        }
 
-        mapping_flags
+    /// The root of the kernel space, the kernel half of the current space.
+    pub(crate) fn kernel_root() -> Option<PhysAddr> {
+        Some(Self::current().0)
     }
-}
-
-impl VSpace {
-    /// The size of the page for this platform.
-    pub const PAGE_SIZE: usize = 0x1000;
-    /// The stages of the address translation
-    pub const PAGE_LEVEL: usize = 3;
-    pub(crate) const PTE_NUM_IN_PAGE: usize = 0x200;
-    pub(crate) const GLOBAL_ROOT_PTE_RANGE: usize = 0x100;
 
     /// Get the using PageTable currently.
     #[inline]
     pub fn current() -> Self {
-        Self(PhysAddr::new(satp::read().ppn() << 12))
+        Self::from_paddr(PhysAddr::new(satp::read().ppn() << 12))
+    }
+
+    /// Get the number of the ASIDs, the unsupported bits of SATP.ASID are read as zero.
+    pub(crate) fn asid_limit() -> usize {
+        let old = satp::read().bits();
+        satp::write(old | (0xffff << 44));
+        let bits = (satp::read().bits() >> 44) & 0xffff;
+        satp::write(old);
+        bits + 1
     }
 
     /// Change the pagetable to Virtual space.
+    ///
+    /// The ASID of the page table is written to SATP.ASID, its entries are
+    /// flushed only if they may be stale.
     #[inline]
     pub fn switch(&self) {
-        // Write page table entry for
-        satp::write((8 << 60) | (self.0.raw() >> 12));
-        TLB::flush_all();
+        let (asid, flush) = crate::asid::activate(self);
+        // The paging mode, the ASID and the root page number.
+        satp::write((Self::SATP_MODE << 60) | (asid << 44) | (self.0.raw() >> 12));
+        match flush {
+            AsidFlush::None => {}
+            AsidFlush::Asid => TLB::flush_asid(asid),
+            AsidFlush::All => TLB::flush_all(),
+        }
     }
 }
 
@@ -144,9 +111,20 @@ impl TLB {
     /// TLB::flush_vaddr(arg0); // arg0 is the virtual address(VirtAddr)
     #[inline]
     pub fn flush_vaddr(vaddr: VirtAddr) {
-        unsafe {
-            sfence_vma(vaddr.raw(), 0);
-        }
+        // All the ASIDs are flushed if rs2 is x0.
+        unsafe { core::arch::asm!("sfence.vma {}, zero", in(reg) vaddr.raw()) }
+    }
+
+    /// Flush the TLB entries of the ASID, the global entries are kept.
+    #[inline]
+    pub fn flush_asid(asid: usize) {
+        unsafe { core::arch::asm!("sfence.vma zero, {}", in(reg) asid) }
+    }
+
+    /// Flush the TLB entry of the virtual address in the ASID.
+    #[inline]
+    pub fn flush_vaddr_asid(vaddr: VirtAddr, asid: usize) {
+        unsafe { sfence_vma(asid, vaddr.raw()) }
     }
 
     /// flush all tlb entry
@@ -158,16 +136,11 @@ impl TLB {
     pub fn flush_all() {
         riscv::asm::sfence_vma_all();
     }
-}
 
-/// Get n level page table index of the given virtual address
-#[inline]
-pub const fn pg_index(vaddr: VirtAddr, n: usize) -> usize {
-    (vaddr.raw() >> (12 + 9 * n)) & 0x1ff
-}
-
-/// Get n level page table offset of the given virtual address
-#[inline]
-pub const fn pg_offest(vaddr: VirtAddr, n: usize) -> usize {
-    vaddr.raw() % (1 << (12 + 9 * n))
+    /// Flush all tlb entries including the global entries, they are
+    /// flushed by [TLB::flush_all] on this platform.
+    #[inline]
+    pub fn flush_all_global() {
+        Self::flush_all()
+    }
 }
@@ -1,87 +1,15 @@
-use bitflags::bitflags;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+
 use polyhal2_core::{
     addr::{PhysAddr, VirtAddr},
     bit,
 };
-use x86_64::{instructions::tlb, registers::control::Cr3};
-
-use crate::{MappingFlags, MappingSize, PTE, TLB, VSpace};
-
-bitflags! {
-    pub struct PTEFlags: u64 {
-        /// Page is present in the page table
-        const P         = bit!(0);
-        /// Read/Write; if 0, Only read
-        const RW        = bit!(1);
-        /// User/Supervisor; if 0, Only supervisor
-        const US        = bit!(2);
-        /// Page-level wright-through
-        const PWT       = bit!(3);
-        /// Page-level cache disable.
-        const PCD       = bit!(4);
-        /// Accessed; indicates whether software has accessed the 4-KByte page
-        const A         = bit!(5);
-        /// Dirty; indicates whether software has written to the 4-KByte page referenced by this entry.
-        const D         = bit!(6);
-        /// Page size; if set this entry maps a 2-MByte page; otherwise, this entry references a page directory.
-        const PS      = bit!(7);
-        /// Global; if CR4.PGE = 1, determines whether the translation is global (see Section 4.10); ignored otherwise
-        const G         = bit!(8);
-        /// User defined flag -- ignored by hardware (bit 9)
-        const USER_9    = bit!(9);
-        /// User defined flag -- ignored by hardware (bit 10)
-        const USER_10   = bit!(10);
-        /// User defined flag -- ignored by hardware (bit 11)
-        const USER_11   = bit!(11);
-        ///  If IA32_EFER.NXE = 1, execute-disable
-        ///  If 1, instruction fetches are not allowed from the 512-GByte region.
-        const XD        = bit!(63);
-    }
-}
-
-impl From<MappingFlags> for PTEFlags {
-    fn from(flags: MappingFlags) -> Self {
-        let mut res = Self::P;
-        if flags.contains(MappingFlags::W) {
-            res |= Self::RW;
-        }
-        if flags.contains(MappingFlags::U) {
-            res |= Self::US;
-        }
-        if flags.contains(MappingFlags::A) {
-            res |= Self::A;
-        }
-        if flags.contains(MappingFlags::D) {
-            res |= Self::D;
-        }
-        if flags.contains(MappingFlags::X) {
-            res.remove(Self::XD);
-        }
-        res
-    }
-}
+use x86_64::{
+    instructions::tlb::{self, InvPicdCommand, Pcid},
+    registers::control::{Cr3, Cr4, Cr4Flags},
+};
 
-impl From<PTEFlags> for MappingFlags {
-    fn from(value: PTEFlags) -> Self {
-        let mut res = MappingFlags::empty();
-        if value.contains(PTEFlags::RW) {
-            res |= MappingFlags::W;
-        }
-        if value.contains(PTEFlags::US) {
-            res |= MappingFlags::U;
-        }
-        if value.contains(PTEFlags::A) {
-            res |= MappingFlags::A;
-        }
-        if value.contains(PTEFlags::D) {
-            res |= MappingFlags::D;
-        }
-        if value.contains(PTEFlags::XD) {
-            res |= MappingFlags::X;
-        }
-        res
-    }
-}
+use crate::{MappingSize, PTE, TLB, VSpace, asid::AsidFlush, flags::PTEFlags};
 
 impl PTE {
     #[inline]
@@ -104,15 +32,23 @@ impl PTE {
         Self(paddr.raw() | (PTEFlags::P | PTEFlags::US | PTEFlags::RW).bits() as usize)
     }
 
+    /// Create a leaf entry, the huge page sets the PS bit in the PD or PDPT entry.
+    ///
+    /// The size is checked against [VSpace::max_page_size] by the caller.
     #[inline]
-    pub(crate) const fn new_page(paddr: PhysAddr, flags: PTEFlags, size: MappingSize) -> Self {
+    pub(crate) fn new_page(paddr: PhysAddr, flags: PTEFlags, size: MappingSize) -> Self {
         match size {
             MappingSize::Page4KB => Self(paddr.raw() | flags.bits() as usize),
-            MappingSize::Page2MB => todo!(),
-            MappingSize::Page1GB => todo!(),
+            _ => Self(paddr.raw() | (flags | PTEFlags::PS).bits() as usize),
         }
     }
 
+    /// The flags of the huge page used to create the smaller pages.
+    #[inline]
+    pub(crate) const fn leaf_flags(&self) -> PTEFlags {
+        self.flags().difference(PTEFlags::PS)
+    }
+
     #[inline]
     pub(crate) const fn paddr(&self) -> PhysAddr {
         PhysAddr::new(self.0 & 0xFFFF_FFFF_F000)
@@ -121,25 +57,63 @@ impl PTE {
 
 impl VSpace {
     /// The size of the page for this platform.
-    pub const PAGE_SIZE: usize = 0x1000;
-    /// The stages of the address translation
-    pub const PAGE_LEVEL: usize = 4;
-    pub(crate) const PTE_NUM_IN_PAGE: usize = 0x200;
-    pub(crate) const GLOBAL_ROOT_PTE_RANGE: usize = 0x100;
+    pub const PAGE_SIZE: usize = polyhal2_core::consts::PAGE_SIZE;
+    /// The stages of the address translation, five levels with the `level5`
+    /// feature, `CR4.LA57` is set by the boot code.
+    pub const PAGE_LEVEL: usize = 4 + cfg!(feature = "level5") as usize;
+    /// The bits of the virtual address translated by the page table.
+    pub const VA_BITS: usize = 12 + 9 * Self::PAGE_LEVEL;
+    pub(crate) const PTE_NUM_IN_PAGE: usize = Self::PAGE_SIZE / size_of::<PTE>();
+    /// The huge page can be replaced by a table directly.
+    pub(crate) const BREAK_BEFORE_MAKE: bool = false;
+    pub(crate) const GLOBAL_ROOT_PTE_RANGE: usize = Self::PTE_NUM_IN_PAGE / 2;
+
+    /// The largest page used by [VSpace::map_region], 1GB pages need the cpu support.
+    pub(crate) fn max_page_size() -> MappingSize {
+        match has_pdpe1gb() {
+            true => MappingSize::Page1GB,
+            false => MappingSize::Page2MB,
+        }
+    }
+
+    /// The root of the kernel space, the kernel half of the current space.
+    pub(crate) fn kernel_root() -> Option<PhysAddr> {
+        Some(Self::current().0)
+    }
 
     /// Get the using PageTable currently.
     #[inline]
     pub fn current() -> Self {
-        Self(PhysAddr::new(
+        Self::from_paddr(PhysAddr::new(
             Cr3::read().0.start_address().as_u64() as usize
         ))
     }
 
     /// Change the pagetable to Virtual space.
+    ///
+    /// The ASID is written to the PCID of `CR3` if `CR4.PCIDE` is set, its
+    /// entries are flushed only if they may be stale.
     #[inline]
     pub fn switch(&self) {
+        let (asid, flush) = crate::asid::activate(self);
+        let cr3 = match flush {
+            AsidFlush::None => CR3_NOFLUSH | asid | self.0.raw(),
+            _ => asid | self.0.raw(),
+        };
         unsafe {
-            core::arch::asm!("mov cr3, {}", in(reg) self.0.raw());
+            core::arch::asm!("mov cr3, {}", in(reg) cr3);
+        }
+        // Writing CR3 only flushes the entries of the PCID.
+        if let (AsidFlush::All, 1..) = (flush, asid) {
+            flush_all_pcids();
+        }
+    }
+
+    /// Get the number of the ASIDs, the PCIDs are used if `CR4.PCIDE` is set.
+    pub(crate) fn asid_limit() -> usize {
+        match Cr4::read().contains(Cr4Flags::PCID) {
+            true => 1 << 12,
+            false => 1,
         }
     }
 }
@@ -155,25 +129,74 @@ impl TLB {
         tlb::flush(x86_64::VirtAddr::new_truncate(vaddr.raw() as _))
     }
 
+    /// Flush the TLB entries of the ASID, the global entries are kept.
+    ///
+    /// All the PCIDs are flushed if the cpu doesn't support INVPCID.
+    #[inline]
+    pub fn flush_asid(asid: usize) {
+        match (has_invpcid(), Pcid::new(asid as _)) {
+            (true, Ok(pcid)) => unsafe { tlb::flush_pcid(InvPicdCommand::Single(pcid)) },
+            _ => flush_all_pcids(),
+        }
+    }
+
+    /// Flush the TLB entry of the virtual address in the ASID.
+    ///
+    /// All the PCIDs are flushed if the cpu doesn't support INVPCID.
+    #[inline]
+    pub fn flush_vaddr_asid(vaddr: VirtAddr, asid: usize) {
+        let addr = x86_64::VirtAddr::new_truncate(vaddr.raw() as _);
+        match (has_invpcid(), Pcid::new(asid as _)) {
+            (true, Ok(pcid)) => unsafe { tlb::flush_pcid(InvPicdCommand::Address(addr, pcid)) },
+            _ => flush_all_pcids(),
+        }
+    }
+
     /// flush all tlb entry
     ///
     /// how to use ?
     /// just
     /// TLB::flush_all();
+    ///
+    /// Only the entries of the current PCID are flushed if PCIDs are used.
     #[inline]
     pub fn flush_all() {
-        tlb::flush_all()
+        // Reload CR3 with the PCID, the read value never has the NOFLUSH bit.
+        unsafe {
+            core::arch::asm!("mov {0}, cr3", "mov cr3, {0}", out(reg) _);
+        }
+    }
+
+    /// Flush all tlb entries of all PCIDs including the global entries,
+    /// which are kept by [TLB::flush_all] when `CR4.PGE` is set.
+    #[inline]
+    pub fn flush_all_global() {
+        flush_all_pcids();
+    }
+}
+
+/// Don't flush the entries of the PCID when writing CR3.
+const CR3_NOFLUSH: usize = bit!(63);
+
+/// Flush the entries of all PCIDs, including the global entries, by toggling `CR4.PGE`.
+fn flush_all_pcids() {
+    let cr4 = Cr4::read();
+    unsafe {
+        Cr4::write(cr4 ^ Cr4Flags::PAGE_GLOBAL);
+        Cr4::write(cr4);
     }
 }
 
-/// Get n level page table index of the given virtual address
-#[inline]
-pub const fn pg_index(vaddr: VirtAddr, n: usize) -> usize {
-    (vaddr.raw() >> (12 + 9 * n)) & 0x1ff
+/// Return true if the cpu supports INVPCID, CPUID.(EAX=07H,ECX=0):EBX.INVPCID.
+fn has_invpcid() -> bool {
+    const INVPCID: u32 = bit!(10);
+    unsafe { __cpuid(0).eax >= 7 && __cpuid_count(7, 0).ebx & INVPCID != 0 }
 }
 
-/// Get n level page table offset of the given virtual address
-#[inline]
-pub const fn pg_offest(vaddr: VirtAddr, n: usize) -> usize {
-    vaddr.raw() % (1 << (12 + 9 * n))
+/// Return true if the cpu supports 1GB pages, CPUID.80000001H:EDX.Page1GB.
+fn has_pdpe1gb() -> bool {
+    const CPUID_EXT_FEATURES: u32 = 0x8000_0001;
+    const PDPE1GB: u32 = bit!(26);
+    let max_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_leaf >= CPUID_EXT_FEATURES && unsafe { __cpuid(CPUID_EXT_FEATURES) }.edx & PDPE1GB != 0
 }
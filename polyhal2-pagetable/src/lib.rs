@@ -3,22 +3,40 @@
 #![no_std]
 #![deny(warnings)]
 #![deny(missing_docs)]
+#![feature(linkage)]
 
+mod asid;
+/// The entry flags of the architectures, they are also built on the host for the tests.
+#[cfg_attr(target_arch = "aarch64", path = "flags/aarch64.rs")]
+#[cfg_attr(target_arch = "loongarch64", path = "flags/loongarch64.rs")]
+#[cfg_attr(target_arch = "x86_64", path = "flags/x86_64.rs")]
+#[cfg_attr(target_arch = "riscv64", path = "flags/riscv64.rs")]
+#[cfg_attr(test, allow(clippy::duplicate_mod))]
+mod flags;
 /// PageTable for aarch64
 #[cfg_attr(target_arch = "aarch64", path = "imp/aarch64.rs")]
 #[cfg_attr(target_arch = "loongarch64", path = "imp/loongarch64.rs")]
 #[cfg_attr(target_arch = "x86_64", path = "imp/x86_64.rs")]
 #[cfg_attr(target_arch = "riscv64", path = "imp/riscv64.rs")]
 mod imp;
+mod shootdown;
+#[cfg(test)]
+mod tests;
 
-use imp::{pg_index, pg_offest};
+use core::ops::Range;
+
+#[cfg(target_arch = "riscv64")]
+pub use flags::enable_svpbmt;
 use polyhal2_core::{
     addr::{PhysAddr, VirtAddr},
     bit,
+    consts::{KERNEL_OFFSET, PAGE_SHIFT},
+    lazy_init::LazyInit,
 };
+pub use shootdown::{TLB_BATCH_SIZE, TlbBatch};
 
-// static mut PAGE_ALLOC: &dyn VSpaceAO = &VSpaceAODummy;
-static mut PAGE_ALLOC: &dyn VSpaceAO = &VSpaceAODummy;
+/// The global allocator of the page table pages, installed by [set_page_allocator].
+static PAGE_ALLOC: LazyInit<&'static dyn VSpaceAO> = LazyInit::new();
 
 /// Page table entry structure
 ///
@@ -47,9 +65,11 @@ bitflags::bitflags! {
         const D = bit!(6);
         /// Global Flag
         const G = bit!(7);
-        /// Device Flag, indicating that the page was used for device memory
+        /// Device Flag, the page is strongly-ordered device memory.
+        /// It is write-combining memory if [MappingFlags::Cache] is also set.
         const Device = bit!(8);
-        /// Cache Flag, indicating that the page will be cached
+        /// Cache Flag, the page is normal cacheable memory.
+        /// It is the default memory type if [MappingFlags::Device] isn't set.
         const Cache = bit!(9);
 
         /// Read | Write | Executeable Flags
@@ -63,20 +83,55 @@ bitflags::bitflags! {
     }
 }
 
+/// The memory type selected by [MappingFlags::Device] and [MappingFlags::Cache].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MemoryType {
+    /// Strongly-ordered device memory, uncached.
+    Device,
+    /// Weakly-ordered uncached memory, the writes may be combined.
+    WriteCombine,
+    /// Normal cacheable memory.
+    Normal,
+}
+
+impl MappingFlags {
+    /// Get the memory type of the mapping.
+    pub(crate) const fn memory_type(&self) -> MemoryType {
+        match (self.contains(Self::Device), self.contains(Self::Cache)) {
+            (true, false) => MemoryType::Device,
+            (true, true) => MemoryType::WriteCombine,
+            (false, _) => MemoryType::Normal,
+        }
+    }
+}
+
+impl MemoryType {
+    /// Get the mapping flags of the memory type.
+    pub(crate) const fn flags(self) -> MappingFlags {
+        match self {
+            MemoryType::Device => MappingFlags::Device,
+            MemoryType::WriteCombine => MappingFlags::Device.union(MappingFlags::Cache),
+            MemoryType::Normal => MappingFlags::Cache,
+        }
+    }
+}
+
 /// Virtual Space Abstract Operation.
 pub trait VSpaceAO: Sync {
     /// Allocate a physical page
-    fn alloc_page(&self) -> PhysAddr;
+    ///
+    /// Return None if there is no free memory.
+    fn alloc_page(&self) -> Option<PhysAddr>;
     /// Free a physical page
     fn free_page(&self, paddr: PhysAddr);
 }
 
-/// A Dummy Implementation for VSpaceAO
+/// A Dummy Implementation for VSpaceAO, it has no memory.
 pub struct VSpaceAODummy;
 
 impl VSpaceAO for VSpaceAODummy {
-    fn alloc_page(&self) -> PhysAddr {
-        unreachable!("Dummy Alloc Page")
+    fn alloc_page(&self) -> Option<PhysAddr> {
+        None
     }
 
     fn free_page(&self, _paddr: PhysAddr) {
@@ -84,28 +139,85 @@ impl VSpaceAO for VSpaceAODummy {
     }
 }
 
-#[inline]
-#[allow(static_mut_refs)]
-fn alloc_page() -> PhysAddr {
-    unsafe { PAGE_ALLOC.alloc_page() }
+/// Install the global allocator of the page table pages.
+///
+/// It is used by the [VSpace] without its own allocator.
+/// The allocator can only be installed once.
+pub fn set_page_allocator(alloc: &'static dyn VSpaceAO) {
+    PAGE_ALLOC.init_by(alloc);
 }
 
-#[inline]
-#[allow(static_mut_refs)]
-fn free_page(paddr: PhysAddr) {
-    unsafe { PAGE_ALLOC.free_page(paddr) }
+/// Get the global allocator, [VSpaceAODummy] if it isn't installed.
+fn page_allocator() -> &'static dyn VSpaceAO {
+    PAGE_ALLOC.try_get().copied().unwrap_or(&VSpaceAODummy)
 }
 
+/// The error of the page table operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingError {
+    /// The page is already mapped.
+    AlreadyMapped,
+    /// The page isn't mapped.
+    NotMapped,
+    /// Failed to allocate a page for the page table.
+    NoMemory,
+    /// The address is in a larger huge page.
+    MappedToHugePage,
+    /// The address isn't aligned to the size of the page.
+    Misaligned,
+    /// The operation isn't supported by the kernel configuration.
+    Unsupported,
+    /// The page size isn't supported by the cpu or the page granule.
+    UnsupportedSize,
+}
+
+/// The result of the page table operations.
+pub type PagingResult<T = ()> = Result<T, PagingError>;
+
 /// Virtual Address Space
 ///
 /// This is just the page table defination.
 /// The implementation of the page table in the specific architecture mod.
-#[repr(C)]
-#[derive(Debug, Clone, Copy)]
-pub struct VSpace(pub(crate) PhysAddr);
+///
+/// The page table pages are allocated from the allocator of the [VSpace]
+/// given by [VSpace::with_allocator], or the global allocator.
+#[derive(Clone, Copy)]
+pub struct VSpace(pub(crate) PhysAddr, Option<&'static dyn VSpaceAO>);
+
+impl core::fmt::Debug for VSpace {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("VSpace")
+            .field("root", &self.0)
+            .field("own_allocator", &self.1.is_some())
+            .finish()
+    }
+}
 
 impl VSpace {
     const _CHECK: () = assert!(Self::PAGE_LEVEL >= 3, "Just level >= 3 supported currently");
+    /// The bits of the index in a page table.
+    const INDEX_BITS: usize = Self::PTE_NUM_IN_PAGE.trailing_zeros() as usize;
+
+    /// Create a new VirtualSpace with given physical address.
+    ///
+    /// It uses the global allocator.
+    #[inline]
+    pub const fn from_paddr(paddr: PhysAddr) -> VSpace {
+        Self(paddr, None)
+    }
+
+    /// Use `alloc` to allocate and free the page table pages of this [VSpace].
+    ///
+    /// The [VSpace] returned by [VSpace::current] uses the global allocator.
+    #[inline]
+    pub const fn with_allocator(self, alloc: &'static dyn VSpaceAO) -> VSpace {
+        Self(self.0, Some(alloc))
+    }
+
+    /// Get the allocator of the page table pages.
+    fn allocator(&self) -> &'static dyn VSpaceAO {
+        self.1.unwrap_or_else(page_allocator)
+    }
 
     /// Get the page table list through the physical address
     #[inline]
@@ -115,129 +227,436 @@ impl VSpace {
             .slice_mut_with_len::<PTE>(Self::PTE_NUM_IN_PAGE)
     }
 
+    /// Allocate an empty page table.
+    fn alloc_table(&self) -> PagingResult<PhysAddr> {
+        let paddr = self.allocator().alloc_page().ok_or(PagingError::NoMemory)?;
+        Self::get_pte_list(paddr).fill(PTE(0));
+        Ok(paddr)
+    }
+
+    /// Get the entry of `vaddr` at `level`.
+    ///
+    /// `upper` is called with every upper entry which isn't a table and its
+    /// level, it should make the entry a table or return the error.
+    fn entry_mut(
+        &self,
+        vaddr: VirtAddr,
+        level: usize,
+        mut upper: impl FnMut(&mut PTE, usize) -> PagingResult,
+    ) -> PagingResult<&'static mut PTE> {
+        let mut table = self.0;
+        for upper_level in (level + 1..Self::PAGE_LEVEL).rev() {
+            let pte = &mut Self::get_pte_list(table)[pg_index(vaddr, upper_level)];
+            if !pte.is_table() {
+                upper(pte, upper_level)?;
+            }
+            table = pte.paddr();
+        }
+        let pte_list = Self::get_pte_list(table);
+        Ok(&mut pte_list[pg_index(vaddr, level)])
+    }
+
+    /// Return Misaligned if the address isn't aligned to the size of the page.
+    const fn check_aligned(addr: usize, size: MappingSize) -> PagingResult {
+        match addr % size.size() {
+            0 => Ok(()),
+            _ => Err(PagingError::Misaligned),
+        }
+    }
+
     /// Mapping a page to specific virtual page (user space address).
     ///
     /// Ensure that PageTable is which you want to map.
     /// vpn: Virtual page will be mapped.
     /// ppn: Physical page.
     /// flags: Mapping flags, include Read, Write, Execute and so on.
+    ///
+    /// The existing mapping isn't replaced, use [VSpace::protect] to change the flags.
+    /// Return [PagingError::UnsupportedSize] if the size can't be mapped,
+    /// such as 1GB pages on the x86_64 cpu without `pdpe1gb`.
     pub fn map_page(
         &self,
         vaddr: VirtAddr,
         paddr: PhysAddr,
         flags: MappingFlags,
         size: MappingSize,
-    ) {
-        let pte_value = PTE::new_page(paddr, flags.into(), size);
+    ) -> PagingResult {
+        self.map_entry(vaddr, paddr, flags, size)?;
+        TLB::flush_vaddr(vaddr);
+        Ok(())
+    }
 
-        let mut pte_list = Self::get_pte_list(self.0);
-        if Self::PAGE_LEVEL == 4 {
-            let pte = &mut pte_list[pg_index(vaddr, 3)];
-            if !pte.is_valid() {
-                *pte = PTE::new_table(alloc_page());
-            }
-            pte_list = Self::get_pte_list(pte.paddr());
+    /// Write the leaf entry of the page without flushing the TLB.
+    fn map_entry(
+        &self,
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        flags: MappingFlags,
+        size: MappingSize,
+    ) -> PagingResult {
+        if size.size() > Self::max_page_size().size() {
+            return Err(PagingError::UnsupportedSize);
         }
-        // level 3
+        Self::check_aligned(vaddr.raw(), size)?;
+        Self::check_aligned(paddr.raw(), size)?;
+        let pte = self.entry_mut(vaddr, size.level(), |pte, _| match pte.is_valid() {
+            true => Err(PagingError::MappedToHugePage),
+            false => {
+                *pte = PTE::new_table(self.alloc_table()?);
+                Ok(())
+            }
+        })?;
+        // The table left by unmapping the smaller pages is replaced. The
+        // root entries may be shared by other page tables, they are kept.
+        let level = size.level();
+        if level > 0
+            && level < Self::PAGE_LEVEL - 1
+            && pte.is_table()
+            && Self::is_empty_table(pte.paddr(), level - 1)
         {
-            let pte = &mut pte_list[pg_index(vaddr, 2)];
-            if !pte.is_valid() && size != MappingSize::Page1GB {
-                *pte = PTE::new_table(alloc_page());
-            } else if size == MappingSize::Page1GB {
-                // TODO: Mapping huge page 1GB
-                *pte = pte_value;
-                return;
+            self.free_empty_table(pte, level - 1, vaddr);
+        }
+        // The smaller pages are mapped if it is a table.
+        if pte.is_valid() {
+            return Err(PagingError::AlreadyMapped);
+        }
+        *pte = PTE::new_page(paddr, flags.into(), size);
+        Ok(())
+    }
+
+    /// Return true if no page is mapped in the table at `level`.
+    fn is_empty_table(table: PhysAddr, level: usize) -> bool {
+        Self::get_pte_list(table)
+            .iter()
+            .all(|pte| match level > 0 && pte.is_table() {
+                true => Self::is_empty_table(pte.paddr(), level - 1),
+                false => !pte.is_valid(),
+            })
+    }
+
+    /// Clear `pte` and free the empty table at `level` under it, `vaddr` is
+    /// in the range of the table.
+    ///
+    /// The cpus may cache the entries of the table, they are flushed
+    /// before the table pages are freed.
+    fn free_empty_table(&self, pte: &mut PTE, level: usize, vaddr: VirtAddr) {
+        let table = pte.paddr();
+        *pte = PTE(0);
+        let mut batch = TlbBatch::new(*self);
+        batch.add_table(vaddr);
+        batch.flush();
+        self.free_tables(Self::get_pte_list(table), level);
+        self.allocator().free_page(table);
+    }
+
+    /// Map the virtual range `vrange` to the physical memory from `paddr`.
+    ///
+    /// The largest page that the addresses are aligned to is used for every
+    /// part of the range, and the TLB is flushed once at the end. The pages
+    /// mapped before an error are unmapped again.
+    pub fn map_region(
+        &self,
+        vrange: Range<VirtAddr>,
+        paddr: PhysAddr,
+        flags: MappingFlags,
+    ) -> PagingResult {
+        let (start, end) = (vrange.start.raw(), vrange.end.raw());
+        Self::check_aligned(start | end | paddr.raw(), MappingSize::Page4KB)?;
+        let mut batch = TlbBatch::new(*self);
+        let mut offset = 0;
+        while start + offset < end {
+            let vaddr = VirtAddr::new(start + offset);
+            let size = Self::fit_size(
+                Self::max_page_size(),
+                vaddr.raw() | (paddr.raw() + offset),
+                end - vaddr.raw(),
+            );
+            let ret = self.map_entry(vaddr, PhysAddr::new(paddr.raw() + offset), flags, size);
+            if let Err(err) = ret {
+                let _ = self.unmap_region(vrange.start..vaddr);
+                return Err(err);
             }
-            pte_list = Self::get_pte_list(pte.paddr());
+            batch.add(vaddr);
+            offset += size.size();
         }
-        // level 2
-        {
-            let pte = &mut pte_list[pg_index(vaddr, 1)];
-            if !pte.is_valid() && size != MappingSize::Page2MB {
-                *pte = PTE::new_table(alloc_page());
-            } else if size == MappingSize::Page2MB {
-                // TODO: Mapping huge page 2MB
-                *pte = pte_value;
-                return;
+        // The new entries were invalid, the other cpus don't cache them.
+        batch.flush_local();
+        batch.clear();
+        Ok(())
+    }
+
+    /// Change the flags of the mapped page.
+    ///
+    /// The entry is flushed on all cpus using the page table.
+    pub fn protect(&self, vaddr: VirtAddr, flags: MappingFlags, size: MappingSize) -> PagingResult {
+        self.protect_batched(vaddr, flags, size, false, &mut TlbBatch::new(*self))
+    }
+
+    /// Rewrite the flags of the leaf entry of the page and add it to the `batch`.
+    ///
+    /// The larger huge page containing the page is split if `split` is true.
+    fn protect_batched(
+        &self,
+        vaddr: VirtAddr,
+        flags: MappingFlags,
+        size: MappingSize,
+        split: bool,
+        batch: &mut TlbBatch,
+    ) -> PagingResult {
+        Self::check_aligned(vaddr.raw(), size)?;
+        let pte = self.entry_mut(vaddr, size.level(), |pte, upper| {
+            match (pte.is_valid(), split) {
+                (true, true) => self.split_block(pte, upper, vaddr),
+                (true, false) => Err(PagingError::MappedToHugePage),
+                (false, _) => Err(PagingError::NotMapped),
             }
-            pte_list = Self::get_pte_list(pte.paddr());
+        })?;
+        if !pte.is_valid() || (size.level() > 0 && pte.is_table()) {
+            return Err(PagingError::NotMapped);
         }
-        // level 1, map page
-        pte_list[pg_index(vaddr, 0)] = pte_value;
-        TLB::flush_vaddr(vaddr);
+        *pte = PTE::new_page(pte.paddr().floor(size.size()), flags.into(), size);
+        batch.add(vaddr);
+        Ok(())
+    }
+
+    /// Change the flags of all the pages in the virtual range in place.
+    ///
+    /// The huge pages partly in the range are split. Return NotMapped if
+    /// there is a hole in the range, the pages before it are changed.
+    pub fn protect_region(&self, vrange: Range<VirtAddr>, flags: MappingFlags) -> PagingResult {
+        let (start, end) = (vrange.start.raw(), vrange.end.raw());
+        Self::check_aligned(start | end, MappingSize::Page4KB)?;
+        let mut batch = TlbBatch::new(*self);
+        let mut vaddr = vrange.start;
+        while vaddr < vrange.end {
+            let (_, leaf) = self.find_leaf(vaddr).map_err(|_| PagingError::NotMapped)?;
+            let size = Self::fit_size(leaf, vaddr.raw(), end - vaddr.raw());
+            self.protect_batched(vaddr, flags, size, size != leaf, &mut batch)?;
+            vaddr = VirtAddr::new(vaddr.raw() + size.size());
+        }
+        Ok(())
     }
 
     /// Unmap a page from specific virtual page (user space address).
     ///
     /// Ensure the virtual page is exists.
     /// vpn: Virtual address.
+    /// The entry is flushed on all cpus using the page table, use
+    /// [VSpace::unmap_page_batched] to flush many pages at once.
+    pub fn unmap_page(&self, vaddr: VirtAddr, size: MappingSize) -> PagingResult {
+        self.unmap_page_batched(vaddr, size, &mut TlbBatch::new(*self))
+    }
+
+    /// Unmap a page and add it to the `batch`, the TLB is flushed with the batch.
     ///
-    /// TODO: Unmap huge page
-    pub fn unmap_page(&self, vaddr: VirtAddr, _size: MappingSize) {
-        let mut pte_list = Self::get_pte_list(self.0);
-        if Self::PAGE_LEVEL == 4 {
-            let pte = &mut pte_list[pg_index(vaddr, 3)];
-            if !pte.is_table() {
-                return;
-            };
-            pte_list = Self::get_pte_list(pte.paddr());
+    /// The larger huge page containing the page is split into smaller pages
+    /// first. The smaller pages in the range of the page are all unmapped.
+    pub fn unmap_page_batched(
+        &self,
+        vaddr: VirtAddr,
+        size: MappingSize,
+        batch: &mut TlbBatch,
+    ) -> PagingResult {
+        Self::check_aligned(vaddr.raw(), size)?;
+        let level = size.level();
+        let pte = self.entry_mut(vaddr, level, |pte, upper| match pte.is_valid() {
+            true => self.split_block(pte, upper, vaddr),
+            false => Err(PagingError::NotMapped),
+        })?;
+        if level > 0 && pte.is_table() {
+            match Self::clear_table(pte.paddr(), level - 1, vaddr, batch) {
+                0 => Err(PagingError::NotMapped),
+                _ => Ok(()),
+            }
+        } else if pte.is_valid() {
+            *pte = PTE(0);
+            batch.add(vaddr);
+            Ok(())
+        } else {
+            Err(PagingError::NotMapped)
         }
-        // level 3
-        {
-            let pte = &mut pte_list[pg_index(vaddr, 2)];
-            if !pte.is_table() {
-                return;
+    }
+
+    /// Unmap all the pages in the virtual range, the holes are skipped.
+    ///
+    /// The huge pages partly in the range are split, and the TLB is
+    /// flushed once at the end.
+    pub fn unmap_region(&self, vrange: Range<VirtAddr>) -> PagingResult {
+        let (start, end) = (vrange.start.raw(), vrange.end.raw());
+        Self::check_aligned(start | end, MappingSize::Page4KB)?;
+        let mut batch = TlbBatch::new(*self);
+        let mut vaddr = vrange.start;
+        while vaddr < vrange.end {
+            let next = match self.find_leaf(vaddr) {
+                Ok((_, leaf)) => {
+                    let size = Self::fit_size(leaf, vaddr.raw(), end - vaddr.raw());
+                    self.unmap_page_batched(vaddr, size, &mut batch)?;
+                    vaddr.raw() + size.size()
+                }
+                // Skip the whole range of the invalid entry.
+                Err(level) => {
+                    let size = Self::level_size(level);
+                    vaddr.floor(size).raw().saturating_add(size)
+                }
             };
-            pte_list = Self::get_pte_list(pte.paddr());
+            vaddr = VirtAddr::new(next.min(end));
         }
-        // level 2
-        {
-            let pte = &mut pte_list[pg_index(vaddr, 1)];
-            if !pte.is_table() {
-                return;
-            };
-            pte_list = Self::get_pte_list(pte.paddr());
+        Ok(())
+    }
+
+    /// Get the largest page not larger than `max` which `addr` is aligned
+    /// to and fits in `len` bytes.
+    fn fit_size(max: MappingSize, addr: usize, len: usize) -> MappingSize {
+        [MappingSize::Page1GB, MappingSize::Page2MB]
+            .into_iter()
+            .filter(|size| size.size() <= max.size())
+            .find(|size| addr % size.size() == 0 && size.size() <= len)
+            .unwrap_or(MappingSize::Page4KB)
+    }
+
+    /// Get the size of the memory covered by an entry at `level`.
+    const fn level_size(level: usize) -> usize {
+        Self::PAGE_SIZE << (Self::INDEX_BITS * level)
+    }
+
+    /// Replace the huge page at `level` by a table of the smaller pages with the same mapping.
+    ///
+    /// `vaddr` is an address in the huge page.
+    fn split_block(&self, pte: &mut PTE, level: usize, vaddr: VirtAddr) -> PagingResult {
+        let (Some(size), Some(child)) = (
+            MappingSize::from_level(level),
+            MappingSize::from_level(level - 1),
+        ) else {
+            panic!("There is no huge page at level {}", level);
+        };
+        let block = *pte;
+        let base = block.paddr().floor(size.size());
+        let table = self.allocator().alloc_page().ok_or(PagingError::NoMemory)?;
+        for (i, entry) in Self::get_pte_list(table).iter_mut().enumerate() {
+            let paddr = PhysAddr::new(base.raw() + i * child.size());
+            *entry = PTE::new_page(paddr, block.leaf_flags(), child);
         }
-        // level 1, map page
-        pte_list[pg_index(vaddr, 0)] = PTE(0);
-        TLB::flush_vaddr(vaddr);
+        if Self::BREAK_BEFORE_MAKE {
+            *pte = PTE(0);
+            TlbBatch::new(*self).add(vaddr.floor(size.size()));
+        }
+        *pte = PTE::new_table(table);
+        Ok(())
     }
 
-    /// Translate a virtual adress to a physical address and mapping flags.
+    /// Clear all the pages in the table at `level`, `vaddr` is the start of the table.
     ///
-    /// Return None if the vaddr isn't mapped.
-    /// vpn: The virtual address will be translated.
-    pub fn translate(&self, vaddr: VirtAddr) -> Option<(PhysAddr, MappingFlags)> {
-        let mut pte_list = Self::get_pte_list(self.0);
-        if Self::PAGE_LEVEL == 4 {
-            let pte = &mut pte_list[pg_index(vaddr, 3)];
-            if !pte.is_table() {
-                return None;
+    /// Return the number of the cleared pages. The table pages are kept,
+    /// they are freed in [VSpace::release] or when a huge page is mapped
+    /// over them.
+    fn clear_table(table: PhysAddr, level: usize, vaddr: VirtAddr, batch: &mut TlbBatch) -> usize {
+        let Some(size) = MappingSize::from_level(level) else {
+            return 0;
+        };
+        let mut cleared = 0;
+        for (i, pte) in Self::get_pte_list(table).iter_mut().enumerate() {
+            let vaddr = VirtAddr::new(vaddr.raw() + i * size.size());
+            if level > 0 && pte.is_table() {
+                cleared += Self::clear_table(pte.paddr(), level - 1, vaddr, batch);
+            } else if pte.is_valid() {
+                *pte = PTE(0);
+                batch.add(vaddr);
+                cleared += 1;
             }
-            pte_list = Self::get_pte_list(pte.paddr());
         }
-        // level 3
-        {
-            let pte = &mut pte_list[pg_index(vaddr, 2)];
-            if !pte.is_table() {
-                return None;
+        cleared
+    }
+
+    /// Find the leaf entry of `vaddr` at any level and the size it maps.
+    ///
+    /// Return the level of the invalid entry if `vaddr` isn't mapped.
+    fn find_leaf(&self, vaddr: VirtAddr) -> Result<(PTE, MappingSize), usize> {
+        let mut table = self.0;
+        for level in (0..Self::PAGE_LEVEL).rev() {
+            let pte = Self::get_pte_list(table)[pg_index(vaddr, level)];
+            if !pte.is_valid() {
+                return Err(level);
             }
-            pte_list = Self::get_pte_list(pte.paddr());
-        }
-        // level 2
-        {
-            let pte = &mut pte_list[pg_index(vaddr, 1)];
-            if !pte.is_table() {
-                return None;
+            // The bit of the huge page in the last level has other meanings.
+            if level == 0 || !pte.is_table() {
+                return MappingSize::from_level(level)
+                    .map(|size| (pte, size))
+                    .ok_or(level);
             }
-            pte_list = Self::get_pte_list(pte.paddr());
+            table = pte.paddr();
+        }
+        Err(0)
+    }
+
+    /// Translate a virtual adress to a physical address, mapping flags and
+    /// the size of the page containing it.
+    ///
+    /// Return NotMapped if the vaddr isn't mapped.
+    /// vpn: The virtual address will be translated.
+    pub fn translate(
+        &self,
+        vaddr: VirtAddr,
+    ) -> PagingResult<(PhysAddr, MappingFlags, MappingSize)> {
+        let (pte, size) = self.find_leaf(vaddr).map_err(|_| PagingError::NotMapped)?;
+        let paddr = pte.paddr().floor(size.size()).raw() + pg_offest(vaddr, size.level());
+        // The flags of the huge page are read as the flags of its smaller pages.
+        let flags = match size {
+            MappingSize::Page4KB => pte.flags(),
+            _ => pte.leaf_flags(),
+        };
+        Ok((PhysAddr::new(paddr), flags.into(), size))
+    }
+
+    /// Get the ASID given to the page table by [VSpace::switch].
+    ///
+    /// Return None if the cpu has no ASIDs or it isn't switched to since
+    /// the ASIDs were reclaimed.
+    pub fn asid(&self) -> Option<usize> {
+        asid::asid_of(self)
+    }
+
+    /// Link the zeroed page `table` as the root entry covering `vaddr`.
+    ///
+    /// The page tables under it are shared by all the root page tables
+    /// linking it, such as the user spaces created after it.
+    pub fn link_root_table(&self, vaddr: VirtAddr, table: PhysAddr) -> PagingResult {
+        let pte = &mut Self::get_pte_list(self.0)[pg_index(vaddr, Self::PAGE_LEVEL - 1)];
+        if pte.is_valid() {
+            return Err(PagingError::AlreadyMapped);
+        }
+        *pte = PTE::new_table(table);
+        Ok(())
+    }
+
+    /// Create a user address space sharing the kernel mappings.
+    ///
+    /// The kernel half of the root is linked to the current kernel space,
+    /// the pages are allocated from the global allocator. Use
+    /// [VSpace::destroy] to free it.
+    ///
+    /// Only the higher half kernel is supported. Return
+    /// [PagingError::Unsupported] if `KERNEL_OFFSET` is 0, the kernel
+    /// and its linear mapping are in the user half then.
+    pub fn new_user() -> PagingResult<VSpace> {
+        if KERNEL_OFFSET == 0 {
+            return Err(PagingError::Unsupported);
         }
-        // level 1, map page
-        let pte = pte_list[pg_index(vaddr, 0)];
-        Some((
-            PhysAddr::new(pte.paddr().raw() + pg_offest(vaddr, 0)),
-            pte.flags().into(),
-        ))
+        let root = page_allocator().alloc_page().ok_or(PagingError::NoMemory)?;
+        let pte_list = Self::get_pte_list(root);
+        pte_list.fill(PTE(0));
+        if let Some(kernel) = Self::kernel_root() {
+            let range = Self::GLOBAL_ROOT_PTE_RANGE..;
+            pte_list[range.clone()].copy_from_slice(&Self::get_pte_list(kernel)[range]);
+        }
+        Ok(Self::from_paddr(root))
+    }
+
+    /// Release the user space created by [VSpace::new_user] and free the root page.
+    ///
+    /// It mustn't be used by any cpu.
+    pub fn destroy(self) {
+        self.release();
+        self.allocator().free_page(self.0);
     }
 
     /// Release the page table entry.
@@ -246,40 +665,39 @@ impl VSpace {
     /// [Page Table Wikipedia](https://en.wikipedia.org/wiki/Page_table).
     /// You don't need to care about this if you just want to use.
     pub fn release(&self) {
-        let drop_l2 = |pte_list: &[PTE]| {
-            pte_list.iter().for_each(|x| {
-                if x.is_table() {
-                    free_page(x.paddr());
-                }
-            });
-        };
-        let drop_l3 = |pte_list: &[PTE]| {
-            pte_list.iter().for_each(|x| {
-                if x.is_table() {
-                    drop_l2(Self::get_pte_list(x.paddr()));
-                    free_page(x.paddr());
-                }
-            });
-        };
-        let drop_l4 = |pte_list: &[PTE]| {
-            pte_list.iter().for_each(|x| {
-                if x.is_table() {
-                    drop_l3(Self::get_pte_list(x.paddr()));
-                    free_page(x.paddr());
-                }
-            });
-        };
-
         // Drop all sub page table entry and clear root page.
         let pte_list = &mut Self::get_pte_list(self.0)[..Self::GLOBAL_ROOT_PTE_RANGE];
-        match Self::PAGE_LEVEL {
-            4 => drop_l4(pte_list),
-            _ => drop_l3(pte_list),
-        }
+        self.free_tables(pte_list, Self::PAGE_LEVEL - 1);
         pte_list.fill(PTE(0));
+        asid::reset(self);
+    }
+
+    /// Free the tables under the entries of `pte_list` at `level`.
+    ///
+    /// The entries in the last level are always pages.
+    fn free_tables(&self, pte_list: &[PTE], level: usize) {
+        for pte in pte_list.iter().filter(|pte| level > 0 && pte.is_table()) {
+            self.free_tables(Self::get_pte_list(pte.paddr()), level - 1);
+            self.allocator().free_page(pte.paddr());
+        }
     }
 }
 
+/// Get n level page table index of the given virtual address
+///
+/// The sign-extended bits above [VSpace::VA_BITS] aren't in the index.
+#[inline]
+const fn pg_index(vaddr: VirtAddr, n: usize) -> usize {
+    let vaddr = vaddr.raw() & ((1 << VSpace::VA_BITS) - 1);
+    (vaddr >> (PAGE_SHIFT + VSpace::INDEX_BITS * n)) & (VSpace::PTE_NUM_IN_PAGE - 1)
+}
+
+/// Get n level page table offset of the given virtual address
+#[inline]
+const fn pg_offest(vaddr: VirtAddr, n: usize) -> usize {
+    vaddr.raw() % VSpace::level_size(n)
+}
+
 /// TLB Operation set.
 /// Such as flush_vaddr, flush_all.
 /// Just use it in the fn.
@@ -288,19 +706,22 @@ impl VSpace {
 ///
 /// ### Flush the tlb entry through the specific virtual address
 ///
-/// ```rust
+/// ```rust,ignore
 /// TLB::flush_vaddr(arg0);  arg0 should be VirtAddr
 /// ```
 /// ### Flush all tlb entries
-/// ```rust
+/// ```rust,ignore
 /// TLB::flush_all();
 /// ```
 pub struct TLB;
 
 /// This structure indicates size of the page that will be mapped.
 ///
-/// TODO: Support More Page Size, 16KB or 32KB
-/// Just support 4KB right now.
+/// The names are the sizes with 4K pages, they are the pages of the same
+/// levels with the larger granules, 16K, 32M and 64G with 16K pages or 64K,
+/// 512M and 4T with 64K pages.
+/// 2MB and 1GB huge pages are supported on all architectures, aarch64 only
+/// supports the 1GB level with 4K pages.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum MappingSize {
     /// 4KB per page
@@ -310,3 +731,29 @@ pub enum MappingSize {
     /// 1GB per page
     Page1GB,
 }
+
+impl MappingSize {
+    /// Get the size of the page in bytes.
+    pub const fn size(self) -> usize {
+        VSpace::level_size(self.level())
+    }
+
+    /// Get the level of the page table where the page is a leaf, level 0 is the last level.
+    pub(crate) const fn level(self) -> usize {
+        match self {
+            MappingSize::Page4KB => 0,
+            MappingSize::Page2MB => 1,
+            MappingSize::Page1GB => 2,
+        }
+    }
+
+    /// Get the size of the leaf entry at `level`.
+    pub(crate) const fn from_level(level: usize) -> Option<Self> {
+        match level {
+            0 => Some(MappingSize::Page4KB),
+            1 => Some(MappingSize::Page2MB),
+            2 => Some(MappingSize::Page1GB),
+            _ => None,
+        }
+    }
+}
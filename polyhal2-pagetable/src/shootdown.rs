@@ -0,0 +1,196 @@
+//! Cross-cpu TLB shootdown.
+//!
+//! Every cpu records the root of the page table it uses in [VSpace::switch].
+//! The changed entries of a [VSpace] are collected in a [TlbBatch], then the
+//! local TLB is flushed and the other cpus using the [VSpace] are asked to
+//! flush through `__polyhal_flush_remote`. The entries above the user space
+//! are shared by all page tables, so all cpus are asked to flush them.
+//! The cpus which used the [VSpace] before flush its ASID when they switch
+//! back to it, see [crate::asid].
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use polyhal2_core::addr::VirtAddr;
+
+use crate::{TLB, VSpace};
+
+/// The maximum number of the cpus tracked, the same as the bits of the cpu mask.
+pub(crate) const MAX_CPU_ID: usize = u64::BITS as usize;
+
+/// The number of the addresses in a batch, the whole TLB is flushed if more.
+pub const TLB_BATCH_SIZE: usize = 32;
+
+/// The root of the page table which every cpu uses, indexed by the cpu id.
+static ACTIVE_ROOT: [AtomicUsize; MAX_CPU_ID] = [const { AtomicUsize::new(0) }; MAX_CPU_ID];
+
+unsafe extern "Rust" {
+    /// Get the cpu id of the current cpu.
+    fn __polyhal_current_cpu_id() -> usize;
+    /// Flush the TLB of the cpus in `targets` except the current cpu.
+    fn __polyhal_flush_remote(targets: u64, batch: &TlbBatch);
+}
+
+/// Weak function
+/// There is only one cpu if the platform doesn't provide the cpu id.
+#[linkage = "weak"]
+#[unsafe(export_name = "__polyhal_current_cpu_id")]
+fn current_cpu_id() -> usize {
+    0
+}
+
+/// Weak function
+/// There is no other cpu to flush if the platform doesn't provide it.
+#[linkage = "weak"]
+#[unsafe(export_name = "__polyhal_flush_remote")]
+fn flush_remote(_targets: u64, _batch: &TlbBatch) {}
+
+/// Get the cpu id of the current cpu.
+pub(crate) fn cpu_id() -> usize {
+    unsafe { __polyhal_current_cpu_id() }
+}
+
+/// Record that the current cpu uses the page table `vspace`.
+pub(crate) fn set_active(vspace: &VSpace) {
+    if let Some(root) = ACTIVE_ROOT.get(cpu_id()) {
+        root.store(vspace.0.raw(), Ordering::Release);
+    }
+}
+
+/// Get the root of the page table which the current cpu uses.
+pub(crate) fn current_root() -> usize {
+    ACTIVE_ROOT
+        .get(cpu_id())
+        .map_or(0, |root| root.load(Ordering::Acquire))
+}
+
+/// Get the mask of the cpus which use the page table `vspace`.
+fn active_cpus(vspace: &VSpace) -> u64 {
+    ACTIVE_ROOT
+        .iter()
+        .enumerate()
+        .filter(|(_, root)| root.load(Ordering::Acquire) == vspace.0.raw())
+        .fold(0, |mask, (cpu_id, _)| mask | (1 << cpu_id))
+}
+
+/// A batch of the TLB entries to flush, it is flushed when dropped.
+///
+/// ## Demo
+///
+/// ```rust,ignore
+/// let mut batch = TlbBatch::new(vspace);
+/// for vaddr in (start..end).step_by(VSpace::PAGE_SIZE) {
+///     vspace.unmap_page_batched(VirtAddr::new(vaddr), MappingSize::Page4KB, &mut batch)?;
+/// }
+/// // Flush all cpus once.
+/// batch.flush();
+/// ```
+pub struct TlbBatch {
+    vspace: VSpace,
+    vaddrs: [VirtAddr; TLB_BATCH_SIZE],
+    len: usize,
+    /// Too many addresses, flush the whole TLB.
+    full: bool,
+    /// Some addresses are shared by all page tables.
+    global: bool,
+}
+
+impl TlbBatch {
+    /// Create an empty batch of the page table `vspace`.
+    pub const fn new(vspace: VSpace) -> Self {
+        Self {
+            vspace,
+            vaddrs: [VirtAddr::new(0); TLB_BATCH_SIZE],
+            len: 0,
+            full: false,
+            global: false,
+        }
+    }
+
+    /// Add the virtual address whose entry is changed.
+    pub fn add(&mut self, vaddr: VirtAddr) {
+        // The higher half is the kernel space.
+        self.global |= (vaddr.raw() as isize) < 0;
+        match self.vaddrs.get_mut(self.len) {
+            Some(slot) => {
+                *slot = vaddr;
+                self.len += 1;
+            }
+            None => self.full = true,
+        }
+    }
+
+    /// Add the virtual address in the range of the table whose entry is
+    /// changed, the whole TLB is flushed since the cpus may cache the
+    /// entries of the table.
+    pub(crate) fn add_table(&mut self, vaddr: VirtAddr) {
+        self.add(vaddr);
+        self.full = true;
+    }
+
+    /// Get the virtual addresses in the batch.
+    ///
+    /// They are meaningless if [TlbBatch::is_full] returns true.
+    pub fn vaddrs(&self) -> &[VirtAddr] {
+        &self.vaddrs[..self.len]
+    }
+
+    /// Return true if there are too many addresses and the whole TLB should be flushed.
+    pub const fn is_full(&self) -> bool {
+        self.full
+    }
+
+    /// Return true if some addresses are shared by all page tables, their
+    /// global entries should be flushed too.
+    pub const fn is_global(&self) -> bool {
+        self.global
+    }
+
+    /// Return true if there is no address in the batch.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Flush the entries in the batch on the current cpu.
+    pub fn flush_local(&self) {
+        // The tests build the page tables on the host, which can't flush the TLB.
+        if cfg!(test) {
+            return;
+        }
+        match (self.full, self.global) {
+            (true, true) => TLB::flush_all_global(),
+            (true, false) => TLB::flush_all(),
+            (false, _) => self
+                .vaddrs()
+                .iter()
+                .for_each(|vaddr| TLB::flush_vaddr(*vaddr)),
+        }
+    }
+
+    /// Flush the entries on all the cpus using the page table and clear the batch.
+    pub fn flush(&mut self) {
+        if self.is_empty() {
+            return;
+        }
+        self.flush_local();
+        crate::asid::invalidate(&self.vspace, self.global);
+        let targets = match self.global {
+            true => u64::MAX,
+            false => active_cpus(&self.vspace),
+        };
+        unsafe { __polyhal_flush_remote(targets, self) };
+        self.clear();
+    }
+
+    /// Remove all the addresses without flushing them.
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.full = false;
+        self.global = false;
+    }
+}
+
+impl Drop for TlbBatch {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
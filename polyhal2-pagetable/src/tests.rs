@@ -0,0 +1,195 @@
+//! Check that the [MappingFlags] round-trip through the entry flags of all
+//! the architectures, run them on the host with `cargo test`.
+//!
+//! The page table of the host architecture is also built in the heap, the
+//! TLB isn't flushed on the host.
+
+extern crate std;
+
+#[path = "flags/aarch64.rs"]
+mod aarch64;
+#[path = "flags/loongarch64.rs"]
+mod loongarch64;
+#[path = "flags/riscv64.rs"]
+mod riscv64;
+#[path = "flags/x86_64.rs"]
+mod x86_64;
+
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use polyhal2_core::addr::{PhysAddr, VirtAddr};
+
+use crate::{MappingFlags, MappingSize, PagingError, VSpace, VSpaceAO};
+
+/// The flags reporting the state of the entry, they aren't kept as requested.
+const STATUS: MappingFlags = MappingFlags::P
+    .union(MappingFlags::A)
+    .union(MappingFlags::D);
+
+/// The flags combined with the required flags in the tests.
+const OPTIONAL: [MappingFlags; 4] = [
+    MappingFlags::W,
+    MappingFlags::X,
+    MappingFlags::U,
+    MappingFlags::G,
+];
+
+/// All the combinations of `required` with any of `optional` in every memory type.
+fn matrix(
+    required: MappingFlags,
+    optional: &'static [MappingFlags],
+) -> impl Iterator<Item = MappingFlags> {
+    let types = [
+        MappingFlags::Cache,
+        MappingFlags::Device,
+        MappingFlags::Device | MappingFlags::Cache,
+    ];
+    (0..1 << optional.len()).flat_map(move |mask: usize| {
+        let flags = optional
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| mask & (1 << i) != 0)
+            .fold(required, |flags, (_, flag)| flags | *flag);
+        types.into_iter().map(move |kind| flags | kind)
+    })
+}
+
+/// The readable flags, all the architectures can map them.
+fn readable() -> impl Iterator<Item = MappingFlags> {
+    matrix(MappingFlags::R, &OPTIONAL)
+}
+
+/// Convert every flags to `T` and back, they should be the same.
+fn check_round_trip<T>(flags: impl Iterator<Item = MappingFlags>)
+where
+    T: From<MappingFlags>,
+    MappingFlags: From<T>,
+{
+    for flags in flags {
+        let converted = MappingFlags::from(T::from(flags));
+        assert_eq!(converted.difference(STATUS), flags);
+    }
+}
+
+#[test]
+fn aarch64_round_trip() {
+    check_round_trip::<aarch64::PTEFlags>(readable());
+}
+
+#[test]
+fn x86_64_round_trip() {
+    check_round_trip::<x86_64::PTEFlags>(readable());
+}
+
+#[test]
+fn riscv64_round_trip() {
+    riscv64::enable_svpbmt();
+    check_round_trip::<riscv64::PTEFlags>(readable());
+    // The execute-only pages.
+    let optional = &[MappingFlags::U, MappingFlags::G];
+    check_round_trip::<riscv64::PTEFlags>(matrix(MappingFlags::X, optional));
+}
+
+#[test]
+fn loongarch64_round_trip() {
+    check_round_trip::<loongarch64::PTEFlags>(readable());
+    // The pages without R are mapped with NR.
+    check_round_trip::<loongarch64::PTEFlags>(matrix(MappingFlags::empty(), &OPTIONAL));
+}
+
+#[test]
+fn no_execute() {
+    use aarch64::PTEFlags as Arm;
+    use loongarch64::PTEFlags as La;
+    use riscv64::PTEFlags as Rv;
+    use x86_64::PTEFlags as X86;
+
+    let flags = MappingFlags::R | MappingFlags::W;
+    assert!(Arm::from(flags).contains(Arm::UXN | Arm::PXN));
+    assert!(X86::from(flags).contains(X86::XD));
+    assert!(La::from(flags).contains(La::NX));
+    assert!(!Rv::from(flags).contains(Rv::X));
+
+    let flags = flags | MappingFlags::X;
+    assert!(!Arm::from(flags).intersects(Arm::UXN | Arm::PXN));
+    assert!(!X86::from(flags).contains(X86::XD));
+    assert!(!La::from(flags).contains(La::NX));
+    assert!(Rv::from(flags).contains(Rv::X));
+}
+
+#[test]
+fn no_read() {
+    use loongarch64::PTEFlags as La;
+    use riscv64::PTEFlags as Rv;
+
+    assert!(La::from(MappingFlags::X).contains(La::NR));
+    assert!(!La::from(MappingFlags::R).contains(La::NR));
+    assert!(!Rv::from(MappingFlags::X).contains(Rv::R));
+}
+
+/// Allocate the page table pages from the heap of the host.
+struct HostAlloc;
+
+/// The number of the pages allocated by [HostAlloc] and not freed.
+static HOST_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+impl HostAlloc {
+    const LAYOUT: Layout = match Layout::from_size_align(VSpace::PAGE_SIZE, VSpace::PAGE_SIZE) {
+        Ok(layout) => layout,
+        Err(_) => panic!("Invalid page layout"),
+    };
+}
+
+impl VSpaceAO for HostAlloc {
+    fn alloc_page(&self) -> Option<PhysAddr> {
+        let ptr = unsafe { std::alloc::alloc_zeroed(Self::LAYOUT) };
+        HOST_PAGES.fetch_add(1, Ordering::Relaxed);
+        (!ptr.is_null()).then(|| PhysAddr::new(ptr as usize))
+    }
+
+    fn free_page(&self, paddr: PhysAddr) {
+        HOST_PAGES.fetch_sub(1, Ordering::Relaxed);
+        unsafe { std::alloc::dealloc(paddr.raw() as *mut u8, Self::LAYOUT) };
+    }
+}
+
+#[test]
+fn map_huge_after_unmap() {
+    let size = MappingSize::Page2MB.size();
+    let vaddr = VirtAddr::new(0x4000_0000);
+    let paddr = PhysAddr::new(0x8000_0000);
+    let root = HostAlloc.alloc_page().unwrap();
+    let vspace = VSpace::from_paddr(root).with_allocator(&HostAlloc);
+
+    // The small page leaves the tables after it is unmapped.
+    let end = VirtAddr::new(vaddr.raw() + VSpace::PAGE_SIZE);
+    vspace
+        .map_region(vaddr..end, paddr, MappingFlags::RWX)
+        .unwrap();
+    vspace.unmap_region(vaddr..end).unwrap();
+    let pages = HOST_PAGES.load(Ordering::Relaxed);
+
+    // The empty table of the small pages is freed for the huge page.
+    let end = VirtAddr::new(vaddr.raw() + size);
+    vspace
+        .map_region(vaddr..end, paddr, MappingFlags::RWX)
+        .unwrap();
+    assert_eq!(HOST_PAGES.load(Ordering::Relaxed), pages - 1);
+    let (target, _, mapped) = vspace.translate(vaddr).unwrap();
+    assert_eq!((target.raw(), mapped), (paddr.raw(), MappingSize::Page2MB));
+
+    // The table with the mapped pages isn't replaced.
+    let (vaddr, paddr) = (
+        VirtAddr::new(vaddr.raw() + size),
+        PhysAddr::new(paddr.raw() + size),
+    );
+    let small = VirtAddr::new(vaddr.raw() + VSpace::PAGE_SIZE);
+    vspace
+        .map_region(vaddr..small, paddr, MappingFlags::RWX)
+        .unwrap();
+    let end = VirtAddr::new(vaddr.raw() + size);
+    let ret = vspace.map_region(vaddr..end, paddr, MappingFlags::RWX);
+    assert!(matches!(ret, Err(PagingError::AlreadyMapped)));
+    vspace.destroy();
+}